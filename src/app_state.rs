@@ -1,17 +1,43 @@
-use crate::database::DatabaseOps;
+use crate::{commit_cache::CommitCache, database::DatabaseOps, sync_status::SyncStatus};
 use anyhow::Result;
 
 #[derive(Clone)]
 pub struct AppState {
     pub db: DatabaseOps,
     pub github_token: Option<String>,
+    /// Shared by the server and a sync running in the same process, which updates it
+    /// only after its changes are committed.
+    pub commit_cache: CommitCache,
+    /// Set by a sync running in this process, reported by the health endpoint.
+    pub sync_status: SyncStatus,
 }
 
 impl AppState {
-    pub async fn new(db_path: &str, github_token: Option<String>) -> Result<Self> {
-        Ok(Self {
-            db: DatabaseOps::new(db_path).await?,
+    pub async fn new(db_path: &str, pool_size: u32, github_token: Option<String>) -> Result<Self> {
+        Ok(Self::with_db(
+            DatabaseOps::new(db_path, pool_size).await?,
             github_token,
-        })
+        ))
+    }
+
+    /// State for `serve` on a read-only copy of the database.
+    pub async fn read_only(
+        db_path: &str,
+        pool_size: u32,
+        github_token: Option<String>,
+    ) -> Result<Self> {
+        Ok(Self::with_db(
+            DatabaseOps::open_read_only(db_path, pool_size).await?,
+            github_token,
+        ))
+    }
+
+    fn with_db(db: DatabaseOps, github_token: Option<String>) -> Self {
+        Self {
+            db,
+            github_token,
+            commit_cache: CommitCache::default(),
+            sync_status: SyncStatus::default(),
+        }
     }
 }
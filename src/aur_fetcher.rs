@@ -1,63 +1,407 @@
-use crate::types::GqlFetchSrcInfoResponse;
-use anyhow::{anyhow, Result};
+use crate::{
+    pkt_line,
+    types::{AurDumpPackage, GqlBranchRefsData, GqlFetchSrcInfoData, GqlResponse},
+};
+use anyhow::{anyhow, Context, Result};
 use chrono::{DateTime, Utc};
-use reqwest::{header, Client};
+use flate2::read::GzDecoder;
+use reqwest::{header, Client, ClientBuilder, RequestBuilder, Response, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt::Write;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::time::sleep;
-use tracing::info;
+use tracing::{info, warn};
 
-const AUR_GIT_UPLOAD_PACK_GET_URL: &str =
-    "https://github.com/archlinux/aur.git/info/refs?service=git-upload-pack";
+pub const DEFAULT_AUR_OWNER: &str = "archlinux";
+pub const DEFAULT_AUR_REPO: &str = "aur";
 const GITHUB_GRAPHQL_URL: &str = "https://api.github.com/graphql";
 const RETRY_AFTER_FINETUNING: i64 = 15;
+/// The maximum page size GitHub allows for connections.
+const GRAPHQL_PAGE_SIZE: usize = 100;
+/// Retries of a request whose response had neither `data` nor `errors`, which
+/// GitHub occasionally returns during upstream glitches.
+const NULL_DATA_RETRIES: u32 = 3;
+const NULL_DATA_RETRY_DELAY: Duration = Duration::from_secs(2);
+/// Delay before the first retry of a failed request, doubled for every further one.
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
+/// Enough for a 100-commit GraphQL batch, which GitHub takes up to half a minute for.
+const DEFAULT_FETCH_TIMEOUT: Duration = Duration::from_secs(120);
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Same as reqwest's default, stated since idle connections to GitHub are reused
+/// between batches.
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+/// Bytes of a malformed response body that are logged.
+const BODY_SNIPPET_LENGTH: usize = 512;
+
+/// Where the branch list is read from.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BranchListSource {
+    /// A single git smart-HTTP `info/refs` request to github.com.
+    #[default]
+    Git,
+    /// Paginated GraphQL `refs` queries, for networks where only api.github.com is
+    /// reachable. Costs one request per 100 branches, i.e. well over a thousand for
+    /// the whole AUR, which counts against the rate limit.
+    Graphql,
+}
+
+/// The GitHub repository holding one branch per package base.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamRepo {
+    owner: String,
+    name: String,
+}
+
+impl Default for UpstreamRepo {
+    fn default() -> Self {
+        Self {
+            owner: DEFAULT_AUR_OWNER.to_string(),
+            name: DEFAULT_AUR_REPO.to_string(),
+        }
+    }
+}
+
+impl UpstreamRepo {
+    /// Fails unless both parts are plain GitHub names, which can be put into URLs
+    /// and GraphQL queries as they are.
+    pub fn new(owner: &str, name: &str) -> Result<Self> {
+        for (what, value) in [("owner", owner), ("name", name)] {
+            let valid = !value.is_empty()
+                && value != "."
+                && value != ".."
+                && value
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'));
+            if !valid {
+                return Err(anyhow!("Invalid upstream repository {}: {:?}", what, value));
+            }
+        }
+        Ok(Self {
+            owner: owner.to_string(),
+            name: name.to_string(),
+        })
+    }
+
+    /// The smart-HTTP URL, e.g. `https://github.com/archlinux/aur.git`.
+    pub fn git_url(&self) -> String {
+        format!("https://github.com/{}/{}.git", self.owner, self.name)
+    }
+
+    /// The URL of a commit's archive, `suffix` being `.tar.gz` or `.zip`.
+    pub fn archive_url(&self, commit_id: &str, suffix: &str) -> String {
+        format!(
+            "https://github.com/{}/{}/archive/{}{}",
+            self.owner, self.name, commit_id, suffix
+        )
+    }
+
+    /// The `repository` selector of GraphQL queries.
+    fn graphql_selector(&self) -> String {
+        format!(r#"repository(owner:"{}",name:"{}")"#, self.owner, self.name)
+    }
+}
+
+/// Timeouts of the HTTP clients talking to GitHub, so a hung connection fails instead
+/// of stalling a sync or a proxied request forever.
+#[derive(Debug, Clone, Copy)]
+pub struct HttpTimeouts {
+    pub connect: Duration,
+    /// Limit of whole requests, or of the time between two reads of a streamed response.
+    pub request: Duration,
+}
+
+impl Default for HttpTimeouts {
+    fn default() -> Self {
+        Self {
+            connect: DEFAULT_CONNECT_TIMEOUT,
+            request: DEFAULT_FETCH_TIMEOUT,
+        }
+    }
+}
+
+impl HttpTimeouts {
+    fn builder(&self) -> ClientBuilder {
+        Client::builder()
+            .user_agent(AurFetcher::user_agent())
+            .connect_timeout(self.connect)
+            .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+    }
+
+    /// A client whose requests, including reading the response, must finish within
+    /// the request timeout.
+    ///
+    /// Panics like `Client::new` if the TLS backend cannot be initialized.
+    pub fn client(&self) -> Client {
+        self.builder()
+            .timeout(self.request)
+            .build()
+            .expect("Failed to build HTTP client")
+    }
+
+    /// A client for long streamed responses such as git packs, which only time out
+    /// when no data arrives for the request timeout.
+    ///
+    /// Panics like `Client::new` if the TLS backend cannot be initialized.
+    pub fn streaming_client(&self) -> Client {
+        self.builder()
+            .read_timeout(self.request)
+            .build()
+            .expect("Failed to build HTTP client")
+    }
+}
+
+pub enum BranchList {
+    /// The advertisement did not change since the given ETag.
+    NotModified,
+    Fetched {
+        branches: HashMap<String, String>,
+        etag: Option<String>,
+    },
+}
 
 #[derive(Clone)]
 pub struct AurFetcher {
     client: Client,
     github_token: Option<String>,
+    fetch_commit_dates: bool,
+    branch_list_source: BranchListSource,
+    upstream_repo: UpstreamRepo,
+    dump_queries: bool,
+    /// Attempts of a request failing with a 5xx status or a network error.
+    max_attempts: u32,
+    /// When the GraphQL budget is replenished, shared by all clones.
+    rate_limit_reset: Arc<Mutex<Option<DateTime<Utc>>>>,
+}
+
+/// Seconds to wait before the next request according to `Retry-After` or GitHub's
+/// `x-ratelimit-*` headers, or `None` if the response was not rate limited.
+fn rate_limit_wait(headers: &header::HeaderMap, now: DateTime<Utc>) -> Option<i64> {
+    let header_str = |name| headers.get(name).and_then(|v| v.to_str().ok());
+
+    if let Some(retry_after) = header_str(header::RETRY_AFTER.as_str()) {
+        if let Ok(retry_after_secs) = retry_after.parse::<i64>() {
+            return Some(retry_after_secs + RETRY_AFTER_FINETUNING);
+        } else if let Ok(date) = DateTime::parse_from_rfc2822(retry_after) {
+            return Some(date.timestamp() - now.timestamp() + RETRY_AFTER_FINETUNING);
+        }
+    }
+
+    if header_str("x-ratelimit-remaining").and_then(|v| v.parse::<u32>().ok()) == Some(0) {
+        // The whole budget is restored at once, so waiting until the reset is enough
+        let rate_limit_reset = header_str("x-ratelimit-reset")
+            .and_then(|v| v.parse::<i64>().ok())
+            .unwrap_or(now.timestamp());
+        return Some(rate_limit_reset - now.timestamp() + RETRY_AFTER_FINETUNING);
+    }
+
+    None
+}
+
+/// Parses the AUR metadata dump, which is served as a gzip file, unless something on
+/// the way already decoded it.
+fn parse_package_meta(body: &[u8]) -> Result<Vec<AurDumpPackage>> {
+    let packages = if body.starts_with(&[0x1f, 0x8b]) {
+        serde_json::from_reader(std::io::BufReader::new(GzDecoder::new(body)))
+    } else {
+        serde_json::from_slice(body)
+    };
+    packages.context("Failed to parse the AUR metadata dump")
 }
 
 impl AurFetcher {
     pub fn new(github_token: Option<String>) -> Self {
-        let client = Client::new();
         Self {
-            client,
+            client: HttpTimeouts::default().client(),
             github_token,
+            fetch_commit_dates: false,
+            branch_list_source: BranchListSource::default(),
+            upstream_repo: UpstreamRepo::default(),
+            dump_queries: false,
+            max_attempts: 1,
+            rate_limit_reset: Default::default(),
         }
     }
 
+    pub fn with_branch_list_source(mut self, source: BranchListSource) -> Self {
+        self.branch_list_source = source;
+        self
+    }
+
+    pub fn with_upstream_repo(mut self, repo: UpstreamRepo) -> Self {
+        self.upstream_repo = repo;
+        self
+    }
+
+    /// Also request each commit's `committedDate` in `fetch_srcinfo_batch`.
+    pub fn with_commit_dates(mut self, enabled: bool) -> Self {
+        self.fetch_commit_dates = enabled;
+        self
+    }
+
+    /// Log every GraphQL query before it is sent, so a failing one can be replayed.
+    pub fn with_dump_queries(mut self, enabled: bool) -> Self {
+        self.dump_queries = enabled;
+        self
+    }
+
+    pub fn with_timeouts(mut self, timeouts: HttpTimeouts) -> Self {
+        self.client = timeouts.client();
+        self
+    }
+
+    /// Retry requests failing with a 5xx status or a network error with exponential
+    /// backoff, making at most `max_attempts` attempts.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
     pub fn github_token(&self) -> Option<&str> {
         self.github_token.as_deref()
     }
 
+    /// Sent with every request, GitHub rejects API requests without a `User-Agent`.
     pub fn user_agent() -> String {
         format!("AUR-Mirror-Meta/{}", env!("CARGO_PKG_VERSION"))
     }
 
-    pub async fn fetch_branch_list(&self) -> Result<HashMap<String, String>> {
-        let mut request_builder = self.client.get(AUR_GIT_UPLOAD_PACK_GET_URL);
+    /// Delays further GraphQL requests by `wait_time` seconds from now.
+    fn set_rate_limit_reset(&self, wait_time: i64) {
+        let reset_at = Utc::now() + chrono::Duration::seconds(wait_time);
+        let mut rate_limit_reset = self.rate_limit_reset.lock().unwrap();
+        // Keep the later reset if another response reported one
+        if rate_limit_reset.is_none_or(|current| current < reset_at) {
+            *rate_limit_reset = Some(reset_at);
+        }
+    }
+
+    /// Sleeps until the last reported rate limit reset, if it is still ahead.
+    async fn wait_for_rate_limit_reset(&self) {
+        let reset_at = *self.rate_limit_reset.lock().unwrap();
+        // Re-read the clock, the reset may have passed while the response was processed
+        if let Some(wait) = reset_at.and_then(|reset_at| (reset_at - Utc::now()).to_std().ok()) {
+            info!("Rate limited. Waiting {} seconds...", wait.as_secs());
+            sleep(wait).await;
+        }
+    }
+
+    /// Fetches the branch list, or returns `BranchList::NotModified` if `etag` still matches.
+    pub async fn fetch_branch_list(&self, etag: Option<&str>) -> Result<BranchList> {
+        match self.branch_list_source {
+            BranchListSource::Git => self.fetch_branch_list_git(etag).await,
+            BranchListSource::Graphql => Ok(BranchList::Fetched {
+                branches: self.fetch_branch_list_graphql().await?,
+                etag: None,
+            }),
+        }
+    }
+
+    async fn fetch_branch_list_git(&self, etag: Option<&str>) -> Result<BranchList> {
+        let url = format!(
+            "{}/info/refs?service=git-upload-pack",
+            self.upstream_repo.git_url()
+        );
+        let mut request_builder = self.client.get(url);
         if let Some(token) = &self.github_token {
             request_builder = request_builder.basic_auth(token, None::<&str>);
         }
-        let response = request_builder.send().await?;
+        if let Some(etag) = etag {
+            request_builder = request_builder.header(header::IF_NONE_MATCH, etag);
+        }
+        let response = self.send_with_retry(request_builder).await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(BranchList::NotModified);
+        }
         if !response.status().is_success() {
             return Err(anyhow!("Failed to fetch refs: {}", response.status()));
         }
+        let etag = response
+            .headers()
+            .get(header::ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
 
-        let text = response.text().await?;
+        // A connection dropped mid-body surfaces here as an error for the caller to retry
+        let body = response
+            .bytes()
+            .await
+            .context("Failed to read the refs advertisement")?;
         let mut branches = HashMap::new();
 
-        for line in text.lines() {
-            if let Some((commit, branch_name)) = line.split_once(" refs/heads/") {
-                if commit.len() >= 4 {
-                    let commit_id = &commit[4..]; // Remove the length prefix
-                    if branch_name != "main" {
-                        branches.insert(branch_name.to_string(), commit_id.to_string());
-                    }
+        for packet in pkt_line::decode(&body)? {
+            if packet.starts_with(b"# service=") {
+                continue;
+            }
+            // The first ref carries the capability list after a NUL byte
+            let packet = packet.split(|&b| b == 0).next().unwrap_or_default();
+            // Decode each ref instead of the whole body lossily, so a bad branch
+            // name is skipped rather than stored with replacement characters.
+            let Ok(line) = std::str::from_utf8(packet) else {
+                warn!(
+                    "⚠ Skipping ref with invalid UTF-8: {}",
+                    packet.escape_ascii()
+                );
+                continue;
+            };
+            let Some((commit_id, branch_name)) = line.split_once(" refs/heads/") else {
+                continue;
+            };
+            if !branch_name.is_ascii() {
+                warn!(
+                    "⚠ Branch name contains non-ASCII characters: {}",
+                    branch_name.escape_debug()
+                );
+            }
+            if branch_name != "main" {
+                branches.insert(branch_name.to_string(), commit_id.to_string());
+            }
+        }
+        Ok(BranchList::Fetched { branches, etag })
+    }
+
+    /// Downloads the AUR metadata dump at `url`, a gzipped JSON array of packages.
+    pub async fn fetch_package_meta(&self, url: &str) -> Result<Vec<AurDumpPackage>> {
+        let response = self.send_with_retry(self.client.get(url)).await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("Failed to fetch {}: {}", url, response.status()));
+        }
+        let body = response
+            .bytes()
+            .await
+            .context("Failed to read the AUR metadata dump")?;
+        // Tens of megabytes of JSON, too much work for an async task
+        tokio::task::spawn_blocking(move || parse_package_meta(&body)).await?
+    }
+
+    async fn fetch_branch_list_graphql(&self) -> Result<HashMap<String, String>> {
+        let query = format!(
+            r#"query($cursor:String){{{}{{refs(refPrefix:"refs/heads/",first:{},after:$cursor){{pageInfo{{hasNextPage endCursor}}nodes{{name target{{oid}}}}}}}}}}"#,
+            self.upstream_repo.graphql_selector(),
+            GRAPHQL_PAGE_SIZE
+        );
+        let mut branches = HashMap::new();
+        let mut cursor: Option<String> = None;
+        for page in 1.. {
+            let data: GqlBranchRefsData = self
+                .post_graphql(&query, serde_json::json!({ "cursor": cursor }))
+                .await?;
+            let refs = data.repository.refs;
+            for node in refs.nodes {
+                if node.name != "main" {
+                    branches.insert(node.name, node.target.oid);
                 }
             }
+            if page % 100 == 0 {
+                info!("Listed {} branches...", branches.len());
+            }
+            match refs.page_info.end_cursor {
+                Some(end_cursor) if refs.page_info.has_next_page => cursor = Some(end_cursor),
+                _ => break,
+            }
         }
         Ok(branches)
     }
@@ -65,10 +409,10 @@ impl AurFetcher {
     pub async fn fetch_srcinfo_batch(
         &self,
         commits: impl Iterator<Item = impl AsRef<str>>,
-    ) -> Result<impl Iterator<Item = String>> {
+    ) -> Result<impl Iterator<Item = (String, Option<i64>)>> {
         let mut n_commits: usize = 0;
         let mut query = String::new();
-        query.push_str(r#"query{repository(owner:"archlinux",name:"aur"){"#);
+        write!(query, "query{{{}{{", self.upstream_repo.graphql_selector())?;
         for (i, commit) in commits.enumerate() {
             write!(
                 query,
@@ -76,95 +420,284 @@ impl AurFetcher {
                 i,
                 commit.as_ref()
             )?;
+            if self.fetch_commit_dates {
+                write!(
+                    query,
+                    r#"d{}:object(expression:"{}"){{... on Commit{{committedDate}}}}"#,
+                    i,
+                    commit.as_ref()
+                )?;
+            }
             n_commits += 1;
         }
         query.push_str(r#"}}"#);
 
+        let mut data: GqlFetchSrcInfoData =
+            self.post_graphql(&query, serde_json::Value::Null).await?;
+
+        let result = (0..n_commits).map(move |i| {
+            let text = data
+                .repository
+                .remove(&format!("x{}", i))
+                .flatten()
+                .and_then(|obj| obj.text)
+                .unwrap_or_default();
+            let committed_date = data
+                .repository
+                .remove(&format!("d{}", i))
+                .flatten()
+                .and_then(|obj| obj.committed_date)
+                .and_then(|date| DateTime::parse_from_rfc3339(&date).ok())
+                .map(|date| date.timestamp());
+            (text, committed_date)
+        });
+
+        Ok(result)
+    }
+
+    /// Posts a GraphQL query, waiting out rate limits and retrying responses without
+    /// `data` or `errors`, and returns its `data`.
+    async fn post_graphql<T: DeserializeOwned>(
+        &self,
+        query: &str,
+        variables: serde_json::Value,
+    ) -> Result<T> {
         let request_body = serde_json::json!({
-            "query": query
+            "query": query,
+            "variables": variables,
         });
 
-        let graphql_response = loop {
+        if self.dump_queries {
+            info!("GraphQL query: {}", request_body);
+        }
+
+        let mut null_data_retries = 0;
+        loop {
+            self.wait_for_rate_limit_reset().await;
+
             let mut request_builder = self
                 .client
                 .post(GITHUB_GRAPHQL_URL)
-                .header(header::CONTENT_TYPE, "application/json")
-                .header(header::USER_AGENT, &Self::user_agent());
+                .header(header::CONTENT_TYPE, "application/json");
             if let Some(token) = self.github_token() {
                 request_builder = request_builder.bearer_auth(token);
             }
-            let response = request_builder.json(&request_body).send().await?;
-
-            // Handle standard Retry-After headers
-            if let Some(retry_after) = response
-                .headers()
-                .get(header::RETRY_AFTER)
-                .and_then(|v| v.to_str().ok())
-            {
-                if let Ok(retry_after_secs) = retry_after.parse::<i64>() {
-                    let wait_time = retry_after_secs + RETRY_AFTER_FINETUNING;
-                    if wait_time > 0 {
-                        info!("Rate limited. Waiting {} seconds...", wait_time);
-                        sleep(Duration::from_secs(wait_time as u64)).await;
-                    }
-                    continue;
-                } else if let Ok(date) = DateTime::parse_from_rfc2822(retry_after) {
-                    let wait_time =
-                        date.timestamp() - Utc::now().timestamp() + RETRY_AFTER_FINETUNING;
-                    if wait_time > 0 {
-                        info!("Rate limited. Waiting {} seconds...", wait_time);
-                        sleep(Duration::from_secs(wait_time as u64)).await;
-                    }
-                    continue;
+            let response = self
+                .send_with_retry(request_builder.json(&request_body))
+                .await?;
+            let wait_time = rate_limit_wait(response.headers(), Utc::now());
+
+            if response.status().is_success() {
+                // The response is complete even if it used up the budget, only the
+                // next request has to wait for the reset
+                if let Some(wait_time) = wait_time {
+                    self.set_rate_limit_reset(wait_time);
+                }
+                let status = response.status();
+                let body = response.text().await?;
+                let graphql_response: GqlResponse<T> = serde_json::from_str(&body)?;
+                if let Some(errors) = graphql_response.errors {
+                    return Err(anyhow!("GraphQL errors: {:?}", errors));
+                }
+                if let Some(data) = graphql_response.data {
+                    return Ok(data);
                 }
-            }
 
-            // Handle GitHub-specific rate limit headers
-            if response
-                .headers()
-                .get("x-ratelimit-remaining")
-                .and_then(|v| v.to_str().ok())
-                .and_then(|v| v.parse::<u32>().ok())
-                == Some(0)
-            {
-                let now = Utc::now().timestamp();
-                let rate_limit_reset = response
-                    .headers()
-                    .get("x-ratelimit-reset")
-                    .and_then(|v| v.to_str().ok())
-                    .and_then(|v| v.parse::<i64>().ok())
-                    .unwrap_or(now);
-                let wait_time = rate_limit_reset - now + RETRY_AFTER_FINETUNING;
-                if wait_time > 0 {
-                    info!("Rate limited. Waiting {} seconds...", wait_time);
-                    sleep(Duration::from_secs(wait_time as u64)).await;
+                warn!(
+                    "No data in GraphQL response (status {}): {}",
+                    status,
+                    body_snippet(&body)
+                );
+                if null_data_retries == NULL_DATA_RETRIES {
+                    return Err(anyhow!("No data in GraphQL response"));
                 }
+                null_data_retries += 1;
+                sleep(NULL_DATA_RETRY_DELAY * null_data_retries).await;
                 continue;
             }
-
-            if response.status().is_success() {
-                break response.json::<GqlFetchSrcInfoResponse>().await?;
-            } else {
-                return Err(anyhow!("GitHub API error: {}", response.status()));
+            match wait_time {
+                Some(wait_time) => self.set_rate_limit_reset(wait_time),
+                None => return Err(anyhow!("GitHub API error: {}", response.status())),
             }
-        };
+        }
+    }
 
-        if let Some(errors) = graphql_response.errors {
-            return Err(anyhow!("GraphQL errors: {:?}", errors));
+    /// Sends a request, retrying connection errors, timeouts and 5xx responses with
+    /// exponential backoff. The last 5xx response is returned like any other, for the
+    /// caller to report.
+    async fn send_with_retry(&self, request: RequestBuilder) -> Result<Response> {
+        let mut attempt = 1;
+        loop {
+            let Some(this_request) = request.try_clone() else {
+                // Streaming bodies cannot be sent twice
+                return Ok(request.send().await?);
+            };
+            let error = match this_request.send().await {
+                Ok(response)
+                    if !response.status().is_server_error() || attempt >= self.max_attempts =>
+                {
+                    return Ok(response);
+                }
+                Ok(response) => anyhow!("GitHub returned {}", response.status()),
+                Err(e) if is_transient(&e) && attempt < self.max_attempts => e.into(),
+                Err(e) => return Err(e.into()),
+            };
+            let delay = RETRY_BASE_DELAY
+                .saturating_mul(2u32.saturating_pow(attempt - 1))
+                .min(RETRY_MAX_DELAY);
+            warn!(
+                "{}, retrying in {:?} ({}/{})",
+                error,
+                delay,
+                attempt,
+                self.max_attempts - 1
+            );
+            sleep(delay).await;
+            attempt += 1;
         }
+    }
+}
 
-        let mut data = graphql_response
-            .data
-            .ok_or_else(|| anyhow!("No data in GraphQL response"))?;
+/// Network errors worth retrying, as opposed to e.g. an invalid URL.
+fn is_transient(e: &reqwest::Error) -> bool {
+    e.is_connect() || e.is_timeout() || e.is_request()
+}
 
-        let result = (0..n_commits).map(move |i| {
-            let key = format!("x{}", i);
-            data.repository
-                .remove(&key)
-                .map(|obj| obj.text)
-                .unwrap_or_default()
-        });
+/// The start of `body`, cut at a character boundary.
+fn body_snippet(body: &str) -> &str {
+    let mut end = body.len().min(BODY_SNIPPET_LENGTH);
+    while !body.is_char_boundary(end) {
+        end -= 1;
+    }
+    &body[..end]
+}
 
-        Ok(result)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+    use header::{HeaderMap, HeaderValue};
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|&(name, value)| {
+                (
+                    header::HeaderName::from_static(name),
+                    HeaderValue::from_str(value).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    fn now() -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000, 0).unwrap()
+    }
+
+    #[test]
+    fn no_wait_without_rate_limit_headers() {
+        assert_eq!(rate_limit_wait(&HeaderMap::new(), now()), None);
+        let remaining = headers(&[
+            ("x-ratelimit-remaining", "12"),
+            ("x-ratelimit-reset", "1700000600"),
+        ]);
+        assert_eq!(rate_limit_wait(&remaining, now()), None);
+    }
+
+    #[test]
+    fn retry_after_seconds_and_date() {
+        let seconds = headers(&[("retry-after", "60")]);
+        assert_eq!(
+            rate_limit_wait(&seconds, now()),
+            Some(60 + RETRY_AFTER_FINETUNING)
+        );
+        let date = (now() + TimeDelta::seconds(90)).to_rfc2822();
+        let date = headers(&[("retry-after", &date)]);
+        assert_eq!(
+            rate_limit_wait(&date, now()),
+            Some(90 + RETRY_AFTER_FINETUNING)
+        );
+    }
+
+    #[test]
+    fn exhausted_budget_waits_until_the_reset() {
+        let exhausted = headers(&[
+            ("x-ratelimit-remaining", "0"),
+            ("x-ratelimit-reset", "1700000600"),
+        ]);
+        assert_eq!(
+            rate_limit_wait(&exhausted, now()),
+            Some(600 + RETRY_AFTER_FINETUNING)
+        );
+        // Fresh headers read later shorten the wait accordingly
+        assert_eq!(
+            rate_limit_wait(&exhausted, now() + TimeDelta::seconds(500)),
+            Some(100 + RETRY_AFTER_FINETUNING)
+        );
+        let no_reset = headers(&[("x-ratelimit-remaining", "0")]);
+        assert_eq!(
+            rate_limit_wait(&no_reset, now()),
+            Some(RETRY_AFTER_FINETUNING)
+        );
+    }
+
+    #[test]
+    fn retry_after_takes_precedence() {
+        let both = headers(&[
+            ("retry-after", "5"),
+            ("x-ratelimit-remaining", "0"),
+            ("x-ratelimit-reset", "1700000600"),
+        ]);
+        assert_eq!(
+            rate_limit_wait(&both, now()),
+            Some(5 + RETRY_AFTER_FINETUNING)
+        );
+    }
+
+    const META_DUMP: &str = r#"[
+        {"ID": 1, "Name": "foo", "Maintainer": "alice", "NumVotes": 3,
+         "Popularity": 0.5, "FirstSubmitted": 1600000000, "LastModified": 1700000000},
+        {"ID": 2, "Name": "bar", "Maintainer": null, "NumVotes": 0,
+         "Popularity": 0, "FirstSubmitted": 1600000000, "LastModified": 1600000000}
+    ]"#;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn assert_meta_dump(packages: &[AurDumpPackage]) {
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages[0].name, "foo");
+        assert_eq!(packages[0].meta.maintainer.as_deref(), Some("alice"));
+        assert_eq!(packages[0].meta.num_votes, 3);
+        assert_eq!(packages[1].meta.maintainer, None);
+    }
+
+    #[test]
+    fn package_meta_from_gzip() {
+        let packages = parse_package_meta(&gzip(META_DUMP.as_bytes())).unwrap();
+        assert_meta_dump(&packages);
+    }
+
+    #[test]
+    fn package_meta_already_decoded() {
+        let packages = parse_package_meta(META_DUMP.as_bytes()).unwrap();
+        assert_meta_dump(&packages);
+    }
+
+    #[test]
+    fn package_meta_rejects_corrupt_data() {
+        let mut corrupt = gzip(META_DUMP.as_bytes());
+        let middle = corrupt.len() / 2;
+        corrupt[middle] ^= 0xff;
+        assert!(parse_package_meta(&corrupt).is_err());
+        let truncated = gzip(META_DUMP.as_bytes());
+        assert!(parse_package_meta(&truncated[..truncated.len() / 2]).is_err());
+        assert!(parse_package_meta(b"not json").is_err());
     }
 }
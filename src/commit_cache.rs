@@ -0,0 +1,69 @@
+//! In-memory branch → commit lookups for the git and snapshot routes.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Entries are re-read from the database after this long, so commits written by a
+/// separate sync process are picked up.
+pub const ENTRY_TTL: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Default)]
+pub struct CommitCache {
+    entries: Arc<RwLock<HashMap<String, (String, Instant)>>>,
+}
+
+impl CommitCache {
+    /// The cached commit of `branch`, unless it is missing or expired.
+    pub fn get(&self, branch: &str) -> Option<String> {
+        let entries = self.entries.read().unwrap();
+        entries
+            .get(branch)
+            .filter(|(_, cached_at)| cached_at.elapsed() < ENTRY_TTL)
+            .map(|(commit, _)| commit.clone())
+    }
+
+    pub fn insert(&self, branch: String, commit: String) {
+        let mut entries = self.entries.write().unwrap();
+        entries.insert(branch, (commit, Instant::now()));
+    }
+
+    pub fn extend(&self, commits: impl IntoIterator<Item = (String, String)>) {
+        let now = Instant::now();
+        let mut entries = self.entries.write().unwrap();
+        entries.extend(
+            commits
+                .into_iter()
+                .map(|(branch, commit)| (branch, (commit, now))),
+        );
+    }
+
+    pub fn remove(&self, branch: &str) {
+        self.entries.write().unwrap().remove(branch);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.read().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_extend_and_remove() {
+        let cache = CommitCache::default();
+        assert_eq!(cache.get("foo"), None);
+        cache.insert("foo".to_string(), "a".to_string());
+        cache.extend([
+            ("foo".to_string(), "b".to_string()),
+            ("bar".to_string(), "c".to_string()),
+        ]);
+        assert_eq!(cache.get("foo").as_deref(), Some("b"));
+        assert_eq!(cache.len(), 2);
+        cache.remove("foo");
+        assert_eq!(cache.get("foo"), None);
+        assert_eq!(cache.get("bar").as_deref(), Some("c"));
+    }
+}
@@ -1,22 +1,203 @@
-use anyhow::{anyhow, Result};
+use crate::{
+    aur_fetcher::{
+        BranchListSource, HttpTimeouts, UpstreamRepo, DEFAULT_AUR_OWNER, DEFAULT_AUR_REPO,
+    },
+    rate_limit::IpRange,
+    rpc_server::SnapshotMode,
+    syncer::EmptySrcinfoPolicy,
+};
+use anyhow::{anyhow, bail, Context, Result};
 use serde::{Deserialize, Serialize};
 use std::env;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::warn;
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct ConfigFileModel {
     pub db_path: Option<String>,
+    pub query_db_path: Option<String>,
     pub github_token: Option<String>,
+    pub allowed_upstream_hosts: Option<Vec<String>>,
+    pub debug_endpoints: Option<bool>,
+    pub version_placeholders: Option<Vec<String>>,
+    pub admin_token: Option<String>,
+    pub suggest_limit: Option<u32>,
+    pub branch_list_source: Option<BranchListSource>,
+    pub trust_forwarded_headers: Option<bool>,
+    pub commit_history: Option<bool>,
+    pub max_value_length: Option<usize>,
+    pub sync_concurrency: Option<usize>,
+    pub empty_srcinfo: Option<EmptySrcinfoPolicy>,
+    pub warm_commit_cache: Option<bool>,
+    pub aur_web_url: Option<String>,
+    pub snapshot_mode: Option<SnapshotMode>,
+    pub snapshot_redirect_permanent: Option<bool>,
+    pub rate_limit_per_minute: Option<u32>,
+    pub rate_limit_exempt: Option<Vec<String>>,
+    pub info_case_insensitive: Option<bool>,
+    pub metrics_endpoint: Option<bool>,
+    pub rpc_post_body_limit: Option<usize>,
+    pub rpc_post_timeout_secs: Option<u64>,
+    pub shutdown_timeout_secs: Option<u64>,
+    pub db_pool_size: Option<u32>,
+    pub request_max_attempts: Option<u32>,
+    pub fetch_timeout_secs: Option<u64>,
+    pub connect_timeout_secs: Option<u64>,
+    pub response_cache_size: Option<usize>,
+    pub response_cache_ttl_secs: Option<u64>,
+    pub aur_owner: Option<String>,
+    pub aur_repo: Option<String>,
+    pub failed_branch_max_retries: Option<u32>,
+    pub fetch_aur_metadata: Option<bool>,
 }
 
+impl ConfigFileModel {
+    /// Field-by-field merge, values set in `self` win over `fallback`.
+    fn merge(self, fallback: ConfigFileModel) -> Self {
+        Self {
+            db_path: self.db_path.or(fallback.db_path),
+            query_db_path: self.query_db_path.or(fallback.query_db_path),
+            github_token: self.github_token.or(fallback.github_token),
+            allowed_upstream_hosts: self
+                .allowed_upstream_hosts
+                .or(fallback.allowed_upstream_hosts),
+            debug_endpoints: self.debug_endpoints.or(fallback.debug_endpoints),
+            version_placeholders: self.version_placeholders.or(fallback.version_placeholders),
+            admin_token: self.admin_token.or(fallback.admin_token),
+            suggest_limit: self.suggest_limit.or(fallback.suggest_limit),
+            branch_list_source: self.branch_list_source.or(fallback.branch_list_source),
+            trust_forwarded_headers: self
+                .trust_forwarded_headers
+                .or(fallback.trust_forwarded_headers),
+            commit_history: self.commit_history.or(fallback.commit_history),
+            max_value_length: self.max_value_length.or(fallback.max_value_length),
+            sync_concurrency: self.sync_concurrency.or(fallback.sync_concurrency),
+            empty_srcinfo: self.empty_srcinfo.or(fallback.empty_srcinfo),
+            warm_commit_cache: self.warm_commit_cache.or(fallback.warm_commit_cache),
+            aur_web_url: self.aur_web_url.or(fallback.aur_web_url),
+            snapshot_mode: self.snapshot_mode.or(fallback.snapshot_mode),
+            snapshot_redirect_permanent: self
+                .snapshot_redirect_permanent
+                .or(fallback.snapshot_redirect_permanent),
+            rate_limit_per_minute: self
+                .rate_limit_per_minute
+                .or(fallback.rate_limit_per_minute),
+            rate_limit_exempt: self.rate_limit_exempt.or(fallback.rate_limit_exempt),
+            info_case_insensitive: self
+                .info_case_insensitive
+                .or(fallback.info_case_insensitive),
+            metrics_endpoint: self.metrics_endpoint.or(fallback.metrics_endpoint),
+            rpc_post_body_limit: self.rpc_post_body_limit.or(fallback.rpc_post_body_limit),
+            rpc_post_timeout_secs: self
+                .rpc_post_timeout_secs
+                .or(fallback.rpc_post_timeout_secs),
+            shutdown_timeout_secs: self
+                .shutdown_timeout_secs
+                .or(fallback.shutdown_timeout_secs),
+            db_pool_size: self.db_pool_size.or(fallback.db_pool_size),
+            request_max_attempts: self.request_max_attempts.or(fallback.request_max_attempts),
+            fetch_timeout_secs: self.fetch_timeout_secs.or(fallback.fetch_timeout_secs),
+            connect_timeout_secs: self.connect_timeout_secs.or(fallback.connect_timeout_secs),
+            response_cache_size: self.response_cache_size.or(fallback.response_cache_size),
+            response_cache_ttl_secs: self
+                .response_cache_ttl_secs
+                .or(fallback.response_cache_ttl_secs),
+            aur_owner: self.aur_owner.or(fallback.aur_owner),
+            aur_repo: self.aur_repo.or(fallback.aur_repo),
+            failed_branch_max_retries: self
+                .failed_branch_max_retries
+                .or(fallback.failed_branch_max_retries),
+            fetch_aur_metadata: self.fetch_aur_metadata.or(fallback.fetch_aur_metadata),
+        }
+    }
+}
+
+/// Generous enough for long versioned sonames.
+const DEFAULT_MAX_VALUE_LENGTH: usize = 1024;
+
+/// Concurrent queries of the server, each holding one connection.
+const DEFAULT_DB_POOL_SIZE: u32 = 16;
+
+/// Rides out GitHub hiccups of up to about 7 seconds.
+const DEFAULT_REQUEST_MAX_ATTEMPTS: u32 = 4;
+
+/// With hourly syncs, a branch failing for a transient reason gets five hours to recover.
+const DEFAULT_FAILED_BRANCH_MAX_RETRIES: u32 = 5;
+
+/// One request at a time, as GitHub recommends to avoid secondary rate limits.
+const DEFAULT_SYNC_CONCURRENCY: usize = 1;
+
+const DEFAULT_AUR_WEB_URL: &str = "https://aur.archlinux.org";
+
+/// Same as aurweb.
+const DEFAULT_SUGGEST_LIMIT: u32 = 20;
+
+/// Room for several thousand `arg[]` names.
+const DEFAULT_RPC_POST_BODY_LIMIT: usize = 256 * 1024;
+
+const DEFAULT_RPC_POST_TIMEOUT_SECS: u64 = 30;
+
+/// A few MiB of typical `info` responses.
+const DEFAULT_RESPONSE_CACHE_SIZE: usize = 1024;
+/// Bounds how stale responses get when a separate process syncs the database.
+const DEFAULT_RESPONSE_CACHE_TTL_SECS: u64 = 60;
+/// Leaves time for running git clones through the proxy to finish.
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+
+const SYSTEM_CONFIG_PATH: &str = "/etc/aur-mirror-meta/config.toml";
+
+const DEFAULT_ALLOWED_UPSTREAM_HOSTS: &[&str] =
+    &["github.com", "raw.githubusercontent.com", "api.github.com"];
+
+/// `pkgver` values VCS packages commonly ship before `pkgver()` ever ran. Only whole
+/// template values: a real version may contain `r0`, e.g. `1.2.r0.gabc1234` on the first
+/// commit after a tag.
+const DEFAULT_VERSION_PLACEHOLDERS: &[&str] = &[
+    "0",
+    "0.0",
+    "0.0.0",
+    "0.0.1",
+    "r0",
+    "r0.0000000",
+    "*g0000000",
+    "VERSION",
+    "latest",
+];
+
 pub struct Config {
     config_path: Option<PathBuf>,
+    user_model: ConfigFileModel,
+    /// Why the user config file could not be read, in which case it is never written
+    /// back, as that would replace the user's settings with the defaults.
+    user_file_error: Option<String>,
+    system_model: ConfigFileModel,
+    /// The user config layered over the system config.
+    model: ConfigFileModel,
 }
 
 impl Config {
     pub fn new(config_path: Option<PathBuf>) -> Self {
+        let config_path = config_path.or_else(get_default_config_path);
+        let (user_model, user_file_error) =
+            match config_path.as_deref().map(read_config_file).transpose() {
+                Ok(model) => (model.flatten().unwrap_or_default(), None),
+                Err(e) => {
+                    warn!("{:#}", e);
+                    (ConfigFileModel::default(), Some(format!("{:#}", e)))
+                }
+            };
+        let system_model = read_config_file(Path::new(SYSTEM_CONFIG_PATH))
+            .inspect_err(|e| warn!("{:#}", e))
+            .ok()
+            .flatten()
+            .unwrap_or_default();
         Config {
-            config_path: config_path.or_else(get_default_config_path),
+            config_path,
+            model: user_model.clone().merge(system_model.clone()),
+            user_model,
+            user_file_error,
+            system_model,
         }
     }
 
@@ -24,14 +205,9 @@ impl Config {
         self.config_path.as_deref()
     }
 
-    fn read_from_file(&self) -> Option<ConfigFileModel> {
-        self.config_path
-            .as_deref()
-            .and_then(|p| std::fs::read_to_string(p).ok())
-            .and_then(|content| toml::from_str::<ConfigFileModel>(&content).ok())
-    }
-
-    pub fn modify_file<M>(&self, modifier: M) -> Result<()>
+    /// Modifies the user config file only, so system-wide values are not copied into it.
+    /// Refuses to touch a config file that exists but cannot be read.
+    pub fn modify_file<M>(&mut self, modifier: M) -> Result<()>
     where
         M: FnOnce(&mut ConfigFileModel),
     {
@@ -39,19 +215,39 @@ impl Config {
             .config_path
             .as_deref()
             .ok_or(anyhow!("No config path found."))?;
-        let mut model = self.read_from_file().unwrap_or_default();
-        modifier(&mut model);
-        let toml_str = toml::to_string_pretty(&model)?;
+        if let Some(error) = &self.user_file_error {
+            bail!("Not overwriting the config file, fix it first: {}", error);
+        }
+        modifier(&mut self.user_model);
+        let toml_str = toml::to_string_pretty(&self.user_model)?;
         if let Some(parent) = config_path.parent() {
             std::fs::create_dir_all(parent)?;
         }
         std::fs::write(config_path, toml_str)?;
+        self.model = self.user_model.clone().merge(self.system_model.clone());
         Ok(())
     }
 
+    /// A path-valued setting. Relative paths are resolved against the directory of the
+    /// config file that set them rather than the working directory.
+    fn file_path<F>(&self, field: F) -> Option<PathBuf>
+    where
+        F: Fn(&ConfigFileModel) -> Option<&str>,
+    {
+        let sources = [
+            (&self.user_model, self.config_path.as_deref()),
+            (&self.system_model, Some(Path::new(SYSTEM_CONFIG_PATH))),
+        ];
+        sources.into_iter().find_map(|(model, config_file)| {
+            let path = field(model)?;
+            let config_dir = config_file.and_then(Path::parent).unwrap_or(Path::new(""));
+            Some(config_dir.join(path))
+        })
+    }
+
     pub fn db_path(&self) -> Option<String> {
-        self.read_from_file()
-            .and_then(|config| config.db_path)
+        self.file_path(|model| model.db_path.as_deref())
+            .map(|p| p.to_string_lossy().to_string())
             .or_else(|| env::var("AMM_DB_PATH").ok())
             .or_else(|| get_default_db_path().map(|p| p.to_string_lossy().to_string()))
             .filter(|path| {
@@ -62,12 +258,260 @@ impl Config {
             })
     }
 
+    /// Database opened read-only by `serve` instead of `db_path`, e.g. a copy of the
+    /// database kept up to date by another host.
+    pub fn query_db_path(&self) -> Option<String> {
+        self.file_path(|model| model.query_db_path.as_deref())
+            .map(|p| p.to_string_lossy().to_string())
+    }
+
     pub fn github_token(&self) -> Option<String> {
-        self.read_from_file()
-            .and_then(|config| config.github_token)
+        self.model
+            .github_token
+            .clone()
             .or_else(|| env::var("AMM_GITHUB_TOKEN").ok())
             .or_else(|| env::var("GITHUB_TOKEN").ok())
     }
+
+    /// Hosts the server is permitted to redirect or proxy requests to.
+    pub fn allowed_upstream_hosts(&self) -> Vec<String> {
+        self.model
+            .allowed_upstream_hosts
+            .clone()
+            .unwrap_or_else(|| {
+                DEFAULT_ALLOWED_UPSTREAM_HOSTS
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect()
+            })
+    }
+
+    /// `pkgver` patterns marking a VCS package's version as a placeholder.
+    pub fn version_placeholders(&self) -> Vec<String> {
+        self.model.version_placeholders.clone().unwrap_or_else(|| {
+            DEFAULT_VERSION_PLACEHOLDERS
+                .iter()
+                .map(|s| s.to_string())
+                .collect()
+        })
+    }
+
+    /// Bearer token required by admin endpoints. Without it they are unauthenticated.
+    pub fn admin_token(&self) -> Option<String> {
+        self.model
+            .admin_token
+            .clone()
+            .or_else(|| env::var("AMM_ADMIN_TOKEN").ok())
+            .filter(|token| !token.is_empty())
+    }
+
+    pub fn branch_list_source(&self) -> BranchListSource {
+        self.model.branch_list_source.unwrap_or_default()
+    }
+
+    /// Maximum number of names returned by `type=suggest` and `type=suggest-pkgbase`.
+    pub fn suggest_limit(&self) -> u32 {
+        self.model.suggest_limit.unwrap_or(DEFAULT_SUGGEST_LIMIT)
+    }
+
+    /// Maximum size of RPC POST form bodies in bytes.
+    pub fn rpc_post_body_limit(&self) -> usize {
+        self.model
+            .rpc_post_body_limit
+            .unwrap_or(DEFAULT_RPC_POST_BODY_LIMIT)
+    }
+
+    /// Time limit for handling an RPC POST request.
+    pub fn rpc_post_timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.model
+                .rpc_post_timeout_secs
+                .unwrap_or(DEFAULT_RPC_POST_TIMEOUT_SECS),
+        )
+    }
+
+    /// How long the server waits for in-flight requests when shutting down.
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.model
+                .shutdown_timeout_secs
+                .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+        )
+    }
+
+    /// Responses the server caches, 0 disables the cache.
+    pub fn response_cache_size(&self) -> usize {
+        self.model
+            .response_cache_size
+            .unwrap_or(DEFAULT_RESPONSE_CACHE_SIZE)
+    }
+
+    pub fn response_cache_ttl(&self) -> Duration {
+        Duration::from_secs(
+            self.model
+                .response_cache_ttl_secs
+                .unwrap_or(DEFAULT_RESPONSE_CACHE_TTL_SECS),
+        )
+    }
+
+    /// Whether `type=info` ignores the case of package names. Off by default to match
+    /// aurweb exactly.
+    pub fn info_case_insensitive(&self) -> bool {
+        self.model.info_case_insensitive.unwrap_or(false)
+    }
+
+    /// Whether `X-Forwarded-*` headers are trusted. Only enable behind a reverse proxy
+    /// that sets them, otherwise clients can spoof them.
+    pub fn trust_forwarded_headers(&self) -> bool {
+        self.model.trust_forwarded_headers.unwrap_or(false)
+    }
+
+    /// Whether past branch commits are kept so their snapshots can still be served.
+    /// Off by default since it grows the database.
+    pub fn commit_history(&self) -> bool {
+        self.model.commit_history.unwrap_or(false)
+    }
+
+    /// Relation values (dependencies, provides, ...) longer than this are not indexed.
+    pub fn max_value_length(&self) -> usize {
+        self.model
+            .max_value_length
+            .unwrap_or(DEFAULT_MAX_VALUE_LENGTH)
+    }
+
+    pub fn empty_srcinfo(&self) -> EmptySrcinfoPolicy {
+        self.model.empty_srcinfo.unwrap_or_default()
+    }
+
+    /// Connections of the read-only query pool, at least one.
+    pub fn db_pool_size(&self) -> u32 {
+        self.model
+            .db_pool_size
+            .unwrap_or(DEFAULT_DB_POOL_SIZE)
+            .max(1)
+    }
+
+    /// Attempts of a GitHub request failing with a 5xx status or a network error, at
+    /// least one.
+    pub fn request_max_attempts(&self) -> u32 {
+        self.model
+            .request_max_attempts
+            .unwrap_or(DEFAULT_REQUEST_MAX_ATTEMPTS)
+            .max(1)
+    }
+
+    /// The AUR metadata dump syncs download maintainers, votes and popularity from,
+    /// if enabled. Off by default since it is a separate source from GitHub.
+    pub fn package_meta_url(&self) -> Option<String> {
+        self.model
+            .fetch_aur_metadata
+            .unwrap_or(false)
+            .then(|| format!("{}/packages-meta-ext-v1.json.gz", self.aur_web_url()))
+    }
+
+    /// Syncs that may fail to fetch a branch at the same commit before it is skipped.
+    pub fn failed_branch_max_retries(&self) -> u32 {
+        self.model
+            .failed_branch_max_retries
+            .unwrap_or(DEFAULT_FAILED_BRANCH_MAX_RETRIES)
+    }
+
+    /// The GitHub repository mirrored, `archlinux/aur` unless overridden.
+    pub fn upstream_repo(&self) -> Result<UpstreamRepo> {
+        UpstreamRepo::new(
+            self.model.aur_owner.as_deref().unwrap_or(DEFAULT_AUR_OWNER),
+            self.model.aur_repo.as_deref().unwrap_or(DEFAULT_AUR_REPO),
+        )
+    }
+
+    /// Timeouts of requests to GitHub, by the sync and the server's proxy.
+    pub fn http_timeouts(&self) -> HttpTimeouts {
+        let defaults = HttpTimeouts::default();
+        HttpTimeouts {
+            connect: self
+                .model
+                .connect_timeout_secs
+                .map_or(defaults.connect, Duration::from_secs),
+            request: self
+                .model
+                .fetch_timeout_secs
+                .map_or(defaults.request, Duration::from_secs),
+        }
+    }
+
+    /// GraphQL batches a sync fetches at the same time.
+    pub fn sync_concurrency(&self) -> usize {
+        self.model
+            .sync_concurrency
+            .unwrap_or(DEFAULT_SYNC_CONCURRENCY)
+            .max(1)
+    }
+
+    /// Whether the server loads every branch commit into memory before accepting
+    /// requests. Off by default since it costs memory on large databases.
+    pub fn warm_commit_cache(&self) -> bool {
+        self.model.warm_commit_cache.unwrap_or(false)
+    }
+
+    /// Base URL of the AUR web interface that `AURPage` links point to.
+    pub fn aur_web_url(&self) -> String {
+        self.model
+            .aur_web_url
+            .as_deref()
+            .unwrap_or(DEFAULT_AUR_WEB_URL)
+            .trim_end_matches('/')
+            .to_string()
+    }
+
+    pub fn snapshot_mode(&self) -> SnapshotMode {
+        self.model.snapshot_mode.unwrap_or_default()
+    }
+
+    /// Whether snapshot redirects are permanent (308) instead of temporary (307).
+    /// Off by default.
+    pub fn snapshot_redirect_permanent(&self) -> bool {
+        self.model.snapshot_redirect_permanent.unwrap_or(false)
+    }
+
+    /// Requests per minute allowed per client on `/rpc` and snapshot routes.
+    /// Unlimited when unset or zero.
+    pub fn rate_limit_per_minute(&self) -> Option<u32> {
+        self.model.rate_limit_per_minute.filter(|&limit| limit > 0)
+    }
+
+    /// Addresses and CIDR blocks exempt from the rate limit.
+    pub fn rate_limit_exempt(&self) -> Result<Vec<IpRange>> {
+        self.model
+            .rate_limit_exempt
+            .iter()
+            .flatten()
+            .map(|range| range.parse())
+            .collect()
+    }
+
+    /// Whether internal debugging endpoints are exposed. Off by default.
+    pub fn debug_endpoints(&self) -> bool {
+        self.model.debug_endpoints.unwrap_or(false)
+    }
+
+    /// Whether `/metrics` is exposed. Off by default.
+    pub fn metrics_endpoint(&self) -> bool {
+        self.model.metrics_endpoint.unwrap_or(false)
+    }
+}
+
+/// The only place config files are parsed. A missing file is `None`.
+fn read_config_file(path: &Path) -> Result<Option<ConfigFileModel>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read config file {}", path.display()))
+        }
+    };
+    toml::from_str(&content)
+        .map(Some)
+        .with_context(|| format!("Failed to parse config file {}", path.display()))
 }
 
 fn get_default_config_path() -> Option<PathBuf> {
@@ -85,3 +529,126 @@ fn get_default_db_path() -> Option<PathBuf> {
         path
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::srcinfo_parse::ParsedSrcInfo;
+
+    fn is_default_placeholder(pkgver: &str) -> bool {
+        let patterns: Vec<String> = DEFAULT_VERSION_PLACEHOLDERS
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        let srcinfo =
+            format!("pkgbase = foo-git\n\tpkgver = {pkgver}\n\tpkgrel = 1\npkgname = foo-git\n");
+        ParsedSrcInfo::parse(&srcinfo)[0].has_placeholder_version(&patterns)
+    }
+
+    #[test]
+    fn default_placeholders_match_templates() {
+        for pkgver in [
+            "0",
+            "0.0.1",
+            "r0",
+            "r0.0000000",
+            "r0.g0000000",
+            "1.0.g0000000",
+        ] {
+            assert!(is_default_placeholder(pkgver), "{}", pkgver);
+        }
+    }
+
+    #[test]
+    fn default_placeholders_skip_real_versions() {
+        for pkgver in [
+            "1.2.r0.gabc1234",
+            "r10.abc1234",
+            "0.1",
+            "1.0.r3.g1234567",
+            "20240101",
+        ] {
+            assert!(!is_default_placeholder(pkgver), "{}", pkgver);
+        }
+    }
+
+    #[test]
+    fn modify_file_keeps_other_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "debug_endpoints = true\n").unwrap();
+
+        let mut config = Config::new(Some(path.clone()));
+        config
+            .modify_file(|model| model.github_token = Some("token".to_string()))
+            .unwrap();
+
+        let config = Config::new(Some(path));
+        assert!(config.debug_endpoints());
+        assert_eq!(config.model.github_token.as_deref(), Some("token"));
+    }
+
+    #[test]
+    fn relative_paths_resolve_against_the_config_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(
+            &path,
+            "db_path = \"data/md.db\"\nquery_db_path = \"../replica.db\"\n",
+        )
+        .unwrap();
+
+        let config = Config::new(Some(path));
+        let db_path = dir.path().join("data/md.db");
+        assert_eq!(
+            config.db_path(),
+            Some(db_path.to_string_lossy().to_string())
+        );
+        assert!(dir.path().join("data").is_dir());
+        let query_db_path = dir.path().join("../replica.db");
+        assert_eq!(
+            config.query_db_path(),
+            Some(query_db_path.to_string_lossy().to_string())
+        );
+    }
+
+    #[test]
+    fn absolute_paths_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        let db_path = dir.path().join("elsewhere/md.db");
+        std::fs::write(&path, format!("db_path = {:?}\n", db_path)).unwrap();
+
+        let config = Config::new(Some(path));
+        assert_eq!(
+            config.db_path(),
+            Some(db_path.to_string_lossy().to_string())
+        );
+    }
+
+    #[test]
+    fn modify_file_refuses_unparsable_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        let content = "debug_endpoints = true\nsuggest_limit = \"many\"\n";
+        std::fs::write(&path, content).unwrap();
+
+        let mut config = Config::new(Some(path.clone()));
+        assert!(!config.debug_endpoints());
+        let result = config.modify_file(|model| model.github_token = Some("token".to_string()));
+        assert!(result.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), content);
+    }
+
+    #[test]
+    fn modify_file_creates_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested").join("config.toml");
+
+        let mut config = Config::new(Some(path.clone()));
+        config
+            .modify_file(|model| model.github_token = Some("token".to_string()))
+            .unwrap();
+        assert!(std::fs::read_to_string(&path).unwrap().contains("token"));
+    }
+}
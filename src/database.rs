@@ -1,24 +1,186 @@
-use crate::types::{DatabasePackageDetails, DatabasePackageInfo, SearchType};
-use anyhow::Result;
-use futures::stream::TryStreamExt;
-use sqlx::{sqlite::SqliteConnectOptions, Row, SqlitePool};
-use std::collections::HashMap;
+use crate::types::{
+    AurDumpPackage, DatabasePackageDetails, DatabasePackageInfo, MatchMode, PackageChange,
+    PackageMeta, Page, Relation, SearchType, SortOrder,
+};
+use anyhow::{Context, Result};
+use chrono::Utc;
+use futures::stream::{self, Stream, TryStreamExt};
+use sqlx::{
+    sqlite::{
+        SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteRow, SqliteSynchronous,
+    },
+    Column, Row, SqlitePool,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::AddAssign;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Semaphore};
+use tracing::{debug, info};
 
+const SEARCH_STREAM_BUFFER_SIZE: usize = 64;
+/// Streamed searches running at once, each holding a connection of their own pool
+/// for as long as the client takes to read.
+pub const MAX_CONCURRENT_STREAMS: usize = 4;
+/// How long a statement waits for a lock before failing with `SQLITE_BUSY`. In WAL
+/// mode only writers wait for each other, e.g. a `sync` and `reindex` in other
+/// processes, and a sync transaction can take a while.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+/// `sync_meta` key of the last successful sync time.
+const META_LAST_SYNC_AT: &str = "last_sync_at";
+/// `sync_meta` key of how long the last successful sync took, in milliseconds.
+const META_LAST_SYNC_DURATION_MS: &str = "last_sync_duration_ms";
+/// `sync_meta` key of the number of branches in the last fetched branch list.
+const META_BRANCH_LIST_COUNT: &str = "branch_list_count";
+/// Stay below SQLite's historical limit of 999 bound parameters per statement.
+const MAX_BIND_PARAMS: usize = 999;
+
+/// Row counts of an incremental index update.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WriteStats {
+    /// Rows inserted, updated or deleted.
+    pub written: usize,
+    /// Relation rows left untouched because they did not change.
+    pub unchanged: usize,
+}
+
+impl AddAssign for WriteStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.written += rhs.written;
+        self.unchanged += rhs.unchanged;
+    }
+}
+
+/// What a package looked like before a sync, see `get_package_states_with_tx`.
+#[derive(Debug, Clone)]
+pub struct PackageState {
+    pub version: String,
+    /// Values of the dependency relations.
+    pub depends: HashSet<(Relation, String)>,
+}
+
+/// Capabilities of the linked SQLite library.
+#[derive(Debug, Clone)]
+pub struct SqliteFeatures {
+    pub version: String,
+    pub fts5: bool,
+    pub compile_options: Vec<String>,
+}
+
+impl SqliteFeatures {
+    async fn probe(pool: &SqlitePool) -> Result<Self> {
+        // Temporary tables are per connection, so keep the probe on a single one
+        let mut conn = pool.acquire().await?;
+        let version: String = sqlx::query_scalar("SELECT sqlite_version()")
+            .fetch_one(&mut *conn)
+            .await
+            .context("Failed to query the SQLite version")?;
+        let compile_options: Vec<String> = sqlx::query_scalar("PRAGMA compile_options")
+            .fetch_all(&mut *conn)
+            .await?;
+        // FTS5 may also come from a loaded extension, so probe it directly
+        let fts5 = sqlx::query("CREATE VIRTUAL TABLE temp.fts5_probe USING fts5(x)")
+            .execute(&mut *conn)
+            .await
+            .is_ok();
+        if fts5 {
+            sqlx::query("DROP TABLE temp.fts5_probe")
+                .execute(&mut *conn)
+                .await?;
+        }
+        Ok(Self {
+            version,
+            fts5,
+            compile_options,
+        })
+    }
+}
+
+/// SQLite allows a single writer at a time, so all writes go through one dedicated
+/// connection and never wait for a pooled one. Queries use a separate read-only pool,
+/// which in WAL mode keeps answering from the last committed state while a sync
+/// writes.
 #[derive(Clone)]
 pub struct DatabaseOps {
-    pool: SqlitePool,
+    writer: SqlitePool,
+    reader: SqlitePool,
+    /// Connections of streamed searches, kept apart so slow clients cannot starve `reader`.
+    streamer: SqlitePool,
+    /// One permit per `streamer` connection.
+    streams: Arc<Semaphore>,
+    /// Whether the `pkg_fts` full-text index exists, which needs FTS5.
+    full_text: bool,
 }
 
 impl DatabaseOps {
-    pub async fn new(db_path: &str) -> Result<Self> {
-        let pool = SqlitePool::connect_with(
-            SqliteConnectOptions::new()
-                .filename(db_path)
-                .create_if_missing(true),
-        )
-        .await?;
-        let result = Self { pool };
+    pub async fn new(db_path: &str, pool_size: u32) -> Result<Self> {
+        // WAL lets readers and the writer run concurrently and makes commits cheaper,
+        // at the cost of `-wal`/`-shm` files next to the database, which must be on a
+        // local file system. With WAL, `NORMAL` cannot corrupt the database, but a
+        // power loss may roll back the last commits, which the next sync redoes.
+        // `INSERT OR REPLACE` only runs the delete triggers keeping `pkg_fts` in sync
+        // with recursive triggers.
+        let options = SqliteConnectOptions::new()
+            .filename(db_path)
+            .journal_mode(SqliteJournalMode::Wal)
+            .synchronous(SqliteSynchronous::Normal)
+            .busy_timeout(BUSY_TIMEOUT)
+            .foreign_keys(true)
+            .pragma("recursive_triggers", "ON");
+        let writer = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options.clone().create_if_missing(true))
+            .await?;
+        let features = SqliteFeatures::probe(&writer).await?;
+        info!(
+            "SQLite {} (FTS5: {})",
+            features.version,
+            if features.fts5 { "yes" } else { "no" }
+        );
+        debug!(
+            "SQLite compile options: {}",
+            features.compile_options.join(", ")
+        );
+        let mut result = Self {
+            reader: writer.clone(),
+            streamer: writer.clone(),
+            writer,
+            streams: Arc::new(Semaphore::new(MAX_CONCURRENT_STREAMS)),
+            full_text: features.fts5,
+        };
+        // The schema must exist before it can be opened read-only
         result.init_index_tables().await?;
+        let options = options.read_only(true);
+        result.streamer = stream_pool(options.clone());
+        result.reader = SqlitePoolOptions::new()
+            .max_connections(pool_size)
+            .connect_with(options)
+            .await?;
+        Ok(result)
+    }
+
+    /// Opens an existing database for queries only, without creating or migrating its
+    /// schema, so it must have been written by a compatible version. Writes fail.
+    /// A database in WAL mode also needs its `-shm` file to exist or its directory to
+    /// be writable.
+    pub async fn open_read_only(db_path: &str, pool_size: u32) -> Result<Self> {
+        let options = SqliteConnectOptions::new()
+            .filename(db_path)
+            .busy_timeout(BUSY_TIMEOUT)
+            .read_only(true);
+        let streamer = stream_pool(options.clone());
+        let reader = SqlitePoolOptions::new()
+            .max_connections(pool_size)
+            .connect_with(options)
+            .await?;
+        let mut result = Self {
+            writer: reader.clone(),
+            reader,
+            streamer,
+            streams: Arc::new(Semaphore::new(MAX_CONCURRENT_STREAMS)),
+            full_text: false,
+        };
+        result.full_text = result.has_table("pkg_fts").await?;
         Ok(result)
     }
 
@@ -26,76 +188,227 @@ impl DatabaseOps {
         let tables = vec![
             r#"CREATE TABLE IF NOT EXISTS branch_commits (
                 branch TEXT NOT NULL PRIMARY KEY,
-                commit_id TEXT NOT NULL
-            )"#,
-            r#"CREATE TABLE IF NOT EXISTS pkg_info (
-                branch TEXT NOT NULL,
-                pkg_name TEXT NOT NULL,
-                pkg_desc TEXT,
-                version TEXT NOT NULL,
-                url TEXT,
                 commit_id TEXT NOT NULL,
-                PRIMARY KEY (branch, pkg_name)
-            )"#,
-            r#"CREATE TABLE IF NOT EXISTS pkg_depends (
-                branch TEXT NOT NULL,
-                pkg_name TEXT NOT NULL,
-                depend TEXT NOT NULL,
-                PRIMARY KEY (branch, pkg_name, depend)
+                committed_at INTEGER
             )"#,
-            r#"CREATE TABLE IF NOT EXISTS pkg_make_depends (
-                branch TEXT NOT NULL,
-                pkg_name TEXT NOT NULL,
-                make_depend TEXT NOT NULL,
-                PRIMARY KEY (branch, pkg_name, make_depend)
+            r#"CREATE TABLE IF NOT EXISTS sync_meta (
+                key TEXT NOT NULL PRIMARY KEY,
+                value TEXT NOT NULL
             )"#,
-            r#"CREATE TABLE IF NOT EXISTS pkg_opt_depends (
+            r#"CREATE TABLE IF NOT EXISTS branch_commit_history (
                 branch TEXT NOT NULL,
-                pkg_name TEXT NOT NULL,
-                opt_depend TEXT NOT NULL,
-                PRIMARY KEY (branch, pkg_name, opt_depend)
+                commit_id TEXT NOT NULL,
+                seen_at INTEGER NOT NULL,
+                PRIMARY KEY (branch, commit_id)
             )"#,
-            r#"CREATE TABLE IF NOT EXISTS pkg_check_depends (
+            r#"CREATE TABLE IF NOT EXISTS sync_problems (
                 branch TEXT NOT NULL,
-                pkg_name TEXT NOT NULL,
-                check_depend TEXT NOT NULL,
-                PRIMARY KEY (branch, pkg_name, check_depend)
+                commit_id TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                message TEXT NOT NULL,
+                recorded_at INTEGER NOT NULL
             )"#,
-            r#"CREATE TABLE IF NOT EXISTS pkg_provides (
-                branch TEXT NOT NULL,
-                pkg_name TEXT NOT NULL,
-                provide TEXT NOT NULL,
-                PRIMARY KEY (branch, pkg_name, provide)
+            r#"CREATE TABLE IF NOT EXISTS pkg_meta (
+                pkg_name TEXT NOT NULL PRIMARY KEY,
+                maintainer TEXT,
+                num_votes INTEGER NOT NULL,
+                popularity REAL NOT NULL,
+                first_submitted INTEGER NOT NULL,
+                last_modified INTEGER NOT NULL
             )"#,
-            r#"CREATE TABLE IF NOT EXISTS pkg_conflicts (
-                branch TEXT NOT NULL,
-                pkg_name TEXT NOT NULL,
-                conflict TEXT NOT NULL,
-                PRIMARY KEY (branch, pkg_name, conflict)
+            r#"CREATE TABLE IF NOT EXISTS failed_branches (
+                branch TEXT NOT NULL PRIMARY KEY,
+                commit_id TEXT NOT NULL,
+                last_error TEXT NOT NULL,
+                retry_count INTEGER NOT NULL,
+                failed_at INTEGER NOT NULL
             )"#,
-            r#"CREATE TABLE IF NOT EXISTS pkg_replaces (
+            r#"CREATE TABLE IF NOT EXISTS pkg_info (
                 branch TEXT NOT NULL,
                 pkg_name TEXT NOT NULL,
-                replace TEXT NOT NULL,
-                PRIMARY KEY (branch, pkg_name, replace)
+                pkg_desc TEXT,
+                version TEXT NOT NULL,
+                url TEXT,
+                commit_id TEXT NOT NULL,
+                version_is_placeholder INTEGER NOT NULL DEFAULT 0,
+                pkg_name_lc TEXT,
+                pkg_desc_lc TEXT,
+                PRIMARY KEY (branch, pkg_name)
             )"#,
-            r#"CREATE TABLE IF NOT EXISTS pkg_groups (
+            r#"CREATE TABLE IF NOT EXISTS pkg_changes (
                 branch TEXT NOT NULL,
                 pkg_name TEXT NOT NULL,
-                group_name TEXT NOT NULL,
-                PRIMARY KEY (branch, pkg_name, group_name)
+                kind TEXT NOT NULL,
+                old_version TEXT,
+                new_version TEXT,
+                commit_id TEXT NOT NULL,
+                changed_at INTEGER NOT NULL
             )"#,
         ];
 
         for table_sql in tables {
-            sqlx::query(table_sql).execute(&self.pool).await?;
+            sqlx::query(table_sql).execute(&self.writer).await?;
+        }
+        for relation in Relation::ALL {
+            sqlx::query(&relation_table_sql(relation))
+                .execute(&self.writer)
+                .await?;
+            self.migrate_arch_column(relation).await?;
+        }
+        // Added after the initial schema
+        self.ensure_column("branch_commits", "committed_at", "INTEGER")
+            .await?;
+        self.ensure_column(
+            "pkg_info",
+            "version_is_placeholder",
+            "INTEGER NOT NULL DEFAULT 0",
+        )
+        .await?;
+        self.ensure_column("pkg_info", "pkg_name_lc", "TEXT")
+            .await?;
+        self.ensure_column("pkg_info", "pkg_desc_lc", "TEXT")
+            .await?;
+        self.fill_lowercase_columns().await?;
+        if self.full_text {
+            self.init_full_text().await?;
+        }
+
+        self.create_indexes().await
+    }
+
+    /// Creates the full-text index of package names and descriptions, filling it from
+    /// the existing packages the first time. Triggers keep it in sync with every write
+    /// to `pkg_info`, including those of `update_index_with_tx`.
+    async fn init_full_text(&self) -> Result<()> {
+        let exists = self.has_table("pkg_fts").await?;
+        let statements = [
+            "CREATE VIRTUAL TABLE IF NOT EXISTS pkg_fts USING fts5(pkg_name, pkg_desc, content='pkg_info')",
+            r#"CREATE TRIGGER IF NOT EXISTS pkg_fts_insert AFTER INSERT ON pkg_info BEGIN
+                INSERT INTO pkg_fts (rowid, pkg_name, pkg_desc)
+                VALUES (new.rowid, new.pkg_name, new.pkg_desc);
+            END"#,
+            r#"CREATE TRIGGER IF NOT EXISTS pkg_fts_delete AFTER DELETE ON pkg_info BEGIN
+                INSERT INTO pkg_fts (pkg_fts, rowid, pkg_name, pkg_desc)
+                VALUES ('delete', old.rowid, old.pkg_name, old.pkg_desc);
+            END"#,
+            r#"CREATE TRIGGER IF NOT EXISTS pkg_fts_update AFTER UPDATE OF pkg_name, pkg_desc ON pkg_info BEGIN
+                INSERT INTO pkg_fts (pkg_fts, rowid, pkg_name, pkg_desc)
+                VALUES ('delete', old.rowid, old.pkg_name, old.pkg_desc);
+                INSERT INTO pkg_fts (rowid, pkg_name, pkg_desc)
+                VALUES (new.rowid, new.pkg_name, new.pkg_desc);
+            END"#,
+        ];
+        for statement in statements {
+            sqlx::query(statement).execute(&self.writer).await?;
+        }
+        if !exists {
+            self.rebuild_full_text().await?;
+            info!("Built full-text index of existing packages");
+        }
+        Ok(())
+    }
+
+    /// Re-reads the full-text index from `pkg_info`, e.g. after `VACUUM` renumbered rows.
+    async fn rebuild_full_text(&self) -> Result<()> {
+        sqlx::query("INSERT INTO pkg_fts (pkg_fts) VALUES ('rebuild')")
+            .execute(&self.writer)
+            .await?;
+        Ok(())
+    }
+
+    async fn has_table(&self, name: &str) -> Result<bool> {
+        let row = sqlx::query("SELECT 1 FROM sqlite_master WHERE name = ?")
+            .bind(name)
+            .fetch_optional(&self.writer)
+            .await?;
+        Ok(row.is_some())
+    }
+
+    /// Fills `pkg_name_lc`/`pkg_desc_lc` of rows written by older versions. Done here
+    /// rather than with SQL `lower()`, which only folds ASCII letters.
+    async fn fill_lowercase_columns(&self) -> Result<()> {
+        let rows = sqlx::query(
+            "SELECT branch, pkg_name, pkg_desc FROM pkg_info WHERE pkg_name_lc IS NULL",
+        )
+        .fetch_all(&self.writer)
+        .await?;
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let mut tx = self.writer.begin().await?;
+        for row in &rows {
+            let pkg_name: String = row.get("pkg_name");
+            let pkg_desc: Option<String> = row.get("pkg_desc");
+            sqlx::query(
+                "UPDATE pkg_info SET pkg_name_lc = ?, pkg_desc_lc = ? WHERE branch = ? AND pkg_name = ?",
+            )
+            .bind(pkg_name.to_lowercase())
+            .bind(pkg_desc.as_deref().map(str::to_lowercase))
+            .bind(row.get::<String, _>("branch"))
+            .bind(&pkg_name)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        info!("Filled lowercase search columns of {} packages", rows.len());
+        Ok(())
+    }
+
+    /// Rebuilds a relation table created by older versions without the `arch` column,
+    /// which cannot simply be added since it is part of the primary key. The existing
+    /// rows apply to all architectures until their branch is synced again.
+    async fn migrate_arch_column(&self, relation: Relation) -> Result<()> {
+        let table = relation.table();
+        if self.has_column(table, "arch").await? {
+            return Ok(());
+        }
+        let statements = [
+            format!("ALTER TABLE {table} RENAME TO {table}_old"),
+            relation_table_sql(relation),
+            format!(
+                "INSERT INTO {table} (branch, pkg_name, {column}) SELECT branch, pkg_name, {column} FROM {table}_old",
+                column = relation.column()
+            ),
+            format!("DROP TABLE {table}_old"),
+        ];
+        let mut tx = self.writer.begin().await?;
+        for statement in statements {
+            sqlx::query(&statement).execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+        info!("Added arch column to {}", table);
+        Ok(())
+    }
+
+    async fn has_column(&self, table: &str, column: &str) -> Result<bool> {
+        Ok(sqlx::query(&format!("PRAGMA table_info({})", table))
+            .fetch_all(&self.writer)
+            .await?
+            .iter()
+            .any(|row| row.get::<String, _>("name") == column))
+    }
+
+    /// Adds a column to an existing table if databases created by older versions lack it.
+    async fn ensure_column(&self, table: &str, column: &str, decl: &str) -> Result<()> {
+        if !self.has_column(table, column).await? {
+            sqlx::query(&format!(
+                "ALTER TABLE {} ADD COLUMN {} {}",
+                table, column, decl
+            ))
+            .execute(&self.writer)
+            .await?;
         }
+        Ok(())
+    }
 
+    async fn create_indexes(&self) -> Result<()> {
         let indexes = vec![
             // Query based on pkg name
             "CREATE INDEX IF NOT EXISTS idx_pkg_info_name ON pkg_info(pkg_name)",
+            "CREATE INDEX IF NOT EXISTS idx_pkg_info_name_nocase ON pkg_info(pkg_name COLLATE NOCASE)",
             // Query based on branch
             "CREATE INDEX IF NOT EXISTS idx_pkg_info_branch ON pkg_info(branch)",
+            "CREATE INDEX IF NOT EXISTS idx_pkg_meta_maintainer ON pkg_meta(maintainer COLLATE NOCASE)",
             "CREATE INDEX IF NOT EXISTS idx_pkg_depends_branch ON pkg_depends(branch)",
             "CREATE INDEX IF NOT EXISTS idx_pkg_make_depends_branch ON pkg_make_depends(branch)",
             "CREATE INDEX IF NOT EXISTS idx_pkg_opt_depends_branch ON pkg_opt_depends(branch)",
@@ -104,23 +417,44 @@ impl DatabaseOps {
             "CREATE INDEX IF NOT EXISTS idx_pkg_conflicts_branch ON pkg_conflicts(branch)",
             "CREATE INDEX IF NOT EXISTS idx_pkg_replaces_branch ON pkg_replaces(branch)",
             "CREATE INDEX IF NOT EXISTS idx_pkg_groups_branch ON pkg_groups(branch)",
+            "CREATE INDEX IF NOT EXISTS idx_pkg_keywords_branch ON pkg_keywords(branch)",
+            "CREATE INDEX IF NOT EXISTS idx_sync_problems_recorded_at ON sync_problems(recorded_at)",
+            "CREATE INDEX IF NOT EXISTS idx_branch_commit_history_seen_at ON branch_commit_history(seen_at)",
+            "CREATE INDEX IF NOT EXISTS idx_pkg_changes_changed_at ON pkg_changes(changed_at)",
             // For reverse lookups
             "CREATE INDEX IF NOT EXISTS idx_pkg_depends_depend ON pkg_depends(depend)",
             "CREATE INDEX IF NOT EXISTS idx_pkg_make_depends_make_depend ON pkg_make_depends(make_depend)",
             "CREATE INDEX IF NOT EXISTS idx_pkg_opt_depends_opt_depend ON pkg_opt_depends(opt_depend)",
             "CREATE INDEX IF NOT EXISTS idx_pkg_check_depends_check_depend ON pkg_check_depends(check_depend)",
+            "CREATE INDEX IF NOT EXISTS idx_pkg_provides_provide ON pkg_provides(provide)",
+            "CREATE INDEX IF NOT EXISTS idx_pkg_conflicts_conflict ON pkg_conflicts(conflict)",
+            "CREATE INDEX IF NOT EXISTS idx_pkg_replaces_replace ON pkg_replaces(replace)",
+            "CREATE INDEX IF NOT EXISTS idx_pkg_groups_group_name ON pkg_groups(group_name)",
+            "CREATE INDEX IF NOT EXISTS idx_pkg_keywords_keyword ON pkg_keywords(keyword)",
         ];
 
         for index_sql in indexes {
-            sqlx::query(index_sql).execute(&self.pool).await?;
+            sqlx::query(index_sql).execute(&self.writer).await?;
         }
 
         Ok(())
     }
 
+    /// Re-creates missing indexes, rebuilds all of them and refreshes the query planner
+    /// statistics. Useful after bulk changes or schema migrations.
+    pub async fn reindex(&self) -> Result<()> {
+        self.create_indexes().await?;
+        if self.full_text {
+            self.rebuild_full_text().await?;
+        }
+        sqlx::query("REINDEX").execute(&self.writer).await?;
+        sqlx::query("ANALYZE").execute(&self.writer).await?;
+        Ok(())
+    }
+
     pub async fn get_existing_commits(&self) -> Result<HashMap<String, String>> {
         let mut rows =
-            sqlx::query("SELECT branch, commit_id FROM branch_commits").fetch(&self.pool);
+            sqlx::query("SELECT branch, commit_id FROM branch_commits").fetch(&self.reader);
         let mut commits = HashMap::new();
         while let Some(row) = rows.try_next().await? {
             let branch: String = row.get("branch");
@@ -131,7 +465,7 @@ impl DatabaseOps {
     }
 
     pub async fn begin_transaction(&self) -> Result<sqlx::Transaction<'_, sqlx::Sqlite>> {
-        Ok(self.pool.begin().await?)
+        Ok(self.writer.begin().await?)
     }
 
     pub async fn update_branch_commit_with_tx(
@@ -139,17 +473,38 @@ impl DatabaseOps {
         tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
         branch: &str,
         commit_id: &str,
+        committed_at: Option<i64>,
     ) -> Result<()> {
         sqlx::query(
             r#"
-            INSERT OR REPLACE INTO branch_commits (branch, commit_id) 
-            VALUES (?, ?)
+            INSERT OR REPLACE INTO branch_commits (branch, commit_id, committed_at) 
+            VALUES (?, ?, ?)
         "#,
         )
         .bind(branch)
         .bind(commit_id)
+        .bind(committed_at)
         .execute(&mut **tx)
         .await?;
+        sqlx::query("DELETE FROM failed_branches WHERE branch = ?")
+            .bind(branch)
+            .execute(&mut **tx)
+            .await?;
+        Ok(())
+    }
+
+    /// Forgets a branch that no longer exists upstream.
+    pub async fn delete_branch_commit_with_tx(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+        branch: &str,
+    ) -> Result<()> {
+        for table in ["branch_commits", "failed_branches"] {
+            sqlx::query(&format!("DELETE FROM {} WHERE branch = ?", table))
+                .bind(branch)
+                .execute(&mut **tx)
+                .await?;
+        }
         Ok(())
     }
 
@@ -158,17 +513,7 @@ impl DatabaseOps {
         tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
         branch: &str,
     ) -> Result<()> {
-        let tables = vec![
-            "pkg_info",
-            "pkg_depends",
-            "pkg_make_depends",
-            "pkg_opt_depends",
-            "pkg_check_depends",
-            "pkg_provides",
-            "pkg_conflicts",
-            "pkg_replaces",
-            "pkg_groups",
-        ];
+        let tables = std::iter::once("pkg_info").chain(Relation::ALL.map(Relation::table));
         for table in tables {
             let query = format!("DELETE FROM {} WHERE branch = ?", table);
             sqlx::query(&query).bind(branch).execute(&mut **tx).await?;
@@ -189,8 +534,9 @@ impl DatabaseOps {
             sqlx::query(
                 r#"
                 INSERT OR REPLACE INTO pkg_info 
-                (branch, pkg_name, pkg_desc, version, url, commit_id) 
-                VALUES (?, ?, ?, ?, ?, ?)
+                (branch, pkg_name, pkg_desc, version, url, commit_id, version_is_placeholder,
+                 pkg_name_lc, pkg_desc_lc) 
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
             "#,
             )
             .bind(&pkg.info.branch)
@@ -199,81 +545,22 @@ impl DatabaseOps {
             .bind(&pkg.info.version)
             .bind(&pkg.info.url)
             .bind(&pkg.info.commit_id)
+            .bind(pkg.info.version_is_placeholder)
+            .bind(pkg.info.pkg_name.to_lowercase())
+            .bind(pkg.info.pkg_desc.as_deref().map(str::to_lowercase))
             .execute(&mut **tx)
             .await?;
 
-            self.store_array_tx(
-                tx,
-                &pkg.info.branch,
-                &pkg.info.pkg_name,
-                "pkg_depends",
-                "depend",
-                &pkg.depends,
-            )
-            .await?;
-            self.store_array_tx(
-                tx,
-                &pkg.info.branch,
-                &pkg.info.pkg_name,
-                "pkg_make_depends",
-                "make_depend",
-                &pkg.make_depends,
-            )
-            .await?;
-            self.store_array_tx(
-                tx,
-                &pkg.info.branch,
-                &pkg.info.pkg_name,
-                "pkg_opt_depends",
-                "opt_depend",
-                &pkg.opt_depends,
-            )
-            .await?;
-            self.store_array_tx(
-                tx,
-                &pkg.info.branch,
-                &pkg.info.pkg_name,
-                "pkg_check_depends",
-                "check_depend",
-                &pkg.check_depends,
-            )
-            .await?;
-            self.store_array_tx(
-                tx,
-                &pkg.info.branch,
-                &pkg.info.pkg_name,
-                "pkg_provides",
-                "provide",
-                &pkg.provides,
-            )
-            .await?;
-            self.store_array_tx(
-                tx,
-                &pkg.info.branch,
-                &pkg.info.pkg_name,
-                "pkg_conflicts",
-                "conflict",
-                &pkg.conflicts,
-            )
-            .await?;
-            self.store_array_tx(
-                tx,
-                &pkg.info.branch,
-                &pkg.info.pkg_name,
-                "pkg_replaces",
-                "replace",
-                &pkg.replaces,
-            )
-            .await?;
-            self.store_array_tx(
-                tx,
-                &pkg.info.branch,
-                &pkg.info.pkg_name,
-                "pkg_groups",
-                "group_name",
-                &pkg.groups,
-            )
-            .await?;
+            for relation in Relation::ALL {
+                self.store_array_tx(
+                    tx,
+                    &pkg.info.branch,
+                    &pkg.info.pkg_name,
+                    relation,
+                    &pkg.relation_rows(relation),
+                )
+                .await?;
+            }
         }
 
         Ok(())
@@ -284,18 +571,19 @@ impl DatabaseOps {
         tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
         branch: &str,
         pkg_name: &str,
-        table: &str,
-        column: &str,
-        items: &[String],
+        relation: Relation,
+        rows: &[(&str, &str)],
     ) -> Result<()> {
-        for item in items {
-            let query = format!(
-                "INSERT OR IGNORE INTO {} (branch, pkg_name, {}) VALUES (?, ?, ?)",
-                table, column
-            );
+        let query = format!(
+            "INSERT OR IGNORE INTO {} (branch, pkg_name, arch, {}) VALUES (?, ?, ?, ?)",
+            relation.table(),
+            relation.column()
+        );
+        for (arch, item) in rows {
             sqlx::query(&query)
                 .bind(branch)
                 .bind(pkg_name)
+                .bind(arch)
                 .bind(item)
                 .execute(&mut **tx)
                 .await?;
@@ -303,189 +591,1688 @@ impl DatabaseOps {
         Ok(())
     }
 
+    /// Brings the stored index of `branch` in line with `packages`, only issuing
+    /// INSERT/DELETE statements for relation rows that actually changed.
+    pub async fn diff_index_with_tx(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+        branch: &str,
+        packages: &[DatabasePackageDetails],
+    ) -> Result<WriteStats> {
+        let mut stats = WriteStats::default();
+        let new_names: HashSet<&str> = packages.iter().map(|p| p.info.pkg_name.as_str()).collect();
+
+        let stored_names: Vec<String> =
+            sqlx::query("SELECT pkg_name FROM pkg_info WHERE branch = ?")
+                .bind(branch)
+                .fetch(&mut **tx)
+                .map_ok(|row| row.get::<String, _>(0))
+                .try_collect()
+                .await?;
+        for name in stored_names
+            .iter()
+            .filter(|name| !new_names.contains(name.as_str()))
+        {
+            let tables = std::iter::once("pkg_info").chain(Relation::ALL.map(Relation::table));
+            for table in tables {
+                let query = format!("DELETE FROM {} WHERE branch = ? AND pkg_name = ?", table);
+                let result = sqlx::query(&query)
+                    .bind(branch)
+                    .bind(name)
+                    .execute(&mut **tx)
+                    .await?;
+                stats.written += result.rows_affected() as usize;
+            }
+        }
+
+        // The commit id changes on every update, so the pkg_info row is always rewritten.
+        for pkg in packages {
+            sqlx::query(
+                r#"
+                INSERT INTO pkg_info
+                (branch, pkg_name, pkg_desc, version, url, commit_id, version_is_placeholder,
+                 pkg_name_lc, pkg_desc_lc)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+                ON CONFLICT (branch, pkg_name) DO UPDATE SET
+                    pkg_desc = excluded.pkg_desc,
+                    version = excluded.version,
+                    url = excluded.url,
+                    commit_id = excluded.commit_id,
+                    version_is_placeholder = excluded.version_is_placeholder,
+                    pkg_name_lc = excluded.pkg_name_lc,
+                    pkg_desc_lc = excluded.pkg_desc_lc
+            "#,
+            )
+            .bind(&pkg.info.branch)
+            .bind(&pkg.info.pkg_name)
+            .bind(&pkg.info.pkg_desc)
+            .bind(&pkg.info.version)
+            .bind(&pkg.info.url)
+            .bind(&pkg.info.commit_id)
+            .bind(pkg.info.version_is_placeholder)
+            .bind(pkg.info.pkg_name.to_lowercase())
+            .bind(pkg.info.pkg_desc.as_deref().map(str::to_lowercase))
+            .execute(&mut **tx)
+            .await?;
+            stats.written += 1;
+        }
+
+        for relation in Relation::ALL {
+            let query = format!(
+                "SELECT pkg_name, arch, {} FROM {} WHERE branch = ?",
+                relation.column(),
+                relation.table()
+            );
+            let mut stored: HashSet<(String, String, String)> = sqlx::query(&query)
+                .bind(branch)
+                .fetch(&mut **tx)
+                .map_ok(|row| (row.get(0), row.get(1), row.get(2)))
+                .try_collect()
+                .await?;
+
+            for pkg in packages {
+                for (arch, item) in pkg.relation_rows(relation) {
+                    let key = (
+                        pkg.info.pkg_name.clone(),
+                        arch.to_string(),
+                        item.to_string(),
+                    );
+                    if stored.remove(&key) {
+                        stats.unchanged += 1;
+                        continue;
+                    }
+                    let query = format!(
+                        "INSERT OR IGNORE INTO {} (branch, pkg_name, arch, {}) VALUES (?, ?, ?, ?)",
+                        relation.table(),
+                        relation.column()
+                    );
+                    sqlx::query(&query)
+                        .bind(branch)
+                        .bind(&pkg.info.pkg_name)
+                        .bind(arch)
+                        .bind(item)
+                        .execute(&mut **tx)
+                        .await?;
+                    stats.written += 1;
+                }
+            }
+
+            // Whatever is left over no longer exists in the new .SRCINFO
+            let query = format!(
+                "DELETE FROM {} WHERE branch = ? AND pkg_name = ? AND arch = ? AND {} = ?",
+                relation.table(),
+                relation.column()
+            );
+            for (pkg_name, arch, item) in stored {
+                let result = sqlx::query(&query)
+                    .bind(branch)
+                    .bind(&pkg_name)
+                    .bind(&arch)
+                    .bind(&item)
+                    .execute(&mut **tx)
+                    .await?;
+                stats.written += result.rows_affected() as usize;
+            }
+        }
+
+        Ok(stats)
+    }
+
     pub async fn search_packages(
         &self,
         search_type: SearchType,
         keyword: &str,
+        match_mode: MatchMode,
+        order: Option<SortOrder>,
+        page: Option<Page>,
     ) -> Result<Vec<DatabasePackageInfo>> {
-        let (query, param, count) = match search_type {
-            SearchType::Name => (
-                r#"
-                    SELECT DISTINCT p.* FROM pkg_info p 
-                    WHERE p.pkg_name LIKE ?
-                "#,
-                format!("%{}%", keyword),
-                1,
-            ),
-            SearchType::NameDesc => (
-                r#"
-                    SELECT DISTINCT p.* FROM pkg_info p 
-                    WHERE (p.pkg_name LIKE ? OR p.pkg_desc LIKE ?)
-                "#,
-                format!("%{}%", keyword),
-                2,
-            ),
-            SearchType::Depends => (
-                r#"
-                    SELECT DISTINCT p.* FROM pkg_info p
-                    JOIN pkg_depends d ON p.pkg_name = d.pkg_name AND p.branch = d.branch
-                    WHERE d.depend = ?
-                "#,
-                keyword.to_string(),
-                1,
-            ),
-            SearchType::MakeDepends => (
-                r#"
-                    SELECT DISTINCT p.* FROM pkg_info p
-                    JOIN pkg_make_depends md ON p.pkg_name = md.pkg_name AND p.branch = md.branch
-                    WHERE md.make_depend = ?
-                "#,
-                keyword.to_string(),
-                1,
-            ),
-            SearchType::OptDepends => (
-                r#"
-                    SELECT DISTINCT p.* FROM pkg_info p
-                    JOIN pkg_opt_depends od ON p.pkg_name = od.pkg_name AND p.branch = od.branch
-                    WHERE od.opt_depend = ?
-                "#,
-                keyword.to_string(),
-                1,
-            ),
-            SearchType::CheckDepends => (
-                r#"
-                    SELECT DISTINCT p.* FROM pkg_info p
-                    JOIN pkg_check_depends cd ON p.pkg_name = cd.pkg_name AND p.branch = cd.branch
-                    WHERE cd.check_depend = ?
-                "#,
-                keyword.to_string(),
-                1,
-            ),
-        };
+        let (mut query, param, count) = search_query(
+            search_type,
+            keyword,
+            match_mode,
+            order,
+            page.is_some(),
+            self.full_text,
+        );
+        if page.is_some() {
+            query.push_str(" LIMIT ? OFFSET ?");
+        }
 
-        let mut query_builder = sqlx::query(query);
+        let mut query_builder = sqlx::query(&query);
         for _ in 0..count {
             query_builder = query_builder.bind(&param);
         }
+        if let Some(page) = page {
+            query_builder = query_builder
+                .bind(page.limit as i64)
+                .bind(page.offset as i64);
+        }
         query_builder
-            .fetch(&self.pool)
-            .map_ok(|row| DatabasePackageInfo {
-                commit_id: row.get("commit_id"),
-                branch: row.get("branch"),
-                pkg_name: row.get("pkg_name"),
-                pkg_desc: row.get("pkg_desc"),
-                version: row.get("version"),
-                url: row.get("url"),
-            })
+            .fetch(&self.reader)
+            .map_ok(|row| package_info_from_row(&row))
             .try_collect::<Vec<_>>()
             .await
             .map_err(Into::into)
     }
 
-    pub async fn get_package_details(
+    /// Number of results `search_packages` finds without a page.
+    pub async fn count_search_results(
         &self,
-        package_names: &[String],
-    ) -> Result<Vec<DatabasePackageDetails>> {
-        if package_names.is_empty() {
-            return Ok(Vec::new());
+        search_type: SearchType,
+        keyword: &str,
+        match_mode: MatchMode,
+    ) -> Result<u64> {
+        let (query, param, count) = search_filter(search_type, keyword, match_mode, self.full_text);
+        let query = format!("SELECT COUNT(*) FROM ({})", query);
+        let mut query_builder = sqlx::query(&query);
+        for _ in 0..count {
+            query_builder = query_builder.bind(&param);
         }
+        let row = query_builder.fetch_one(&self.reader).await?;
+        Ok(row.get::<i64, _>(0) as u64)
+    }
 
-        let placeholders: Vec<String> = package_names.iter().map(|_| "?".to_string()).collect();
-        let placeholders_str = placeholders.join(",");
-
-        let query = format!(
-            r#"SELECT * FROM pkg_info WHERE pkg_name IN ({})"#,
-            placeholders_str
+    /// Like `search_packages`, but yields rows as they are read from the database cursor
+    /// instead of collecting them, so arbitrarily large result sets use constant memory.
+    /// `None` if `MAX_CONCURRENT_STREAMS` streams are already running.
+    pub fn search_packages_stream(
+        &self,
+        search_type: SearchType,
+        keyword: &str,
+        match_mode: MatchMode,
+        order: Option<SortOrder>,
+    ) -> Option<impl Stream<Item = Result<DatabasePackageInfo>> + Send + 'static> {
+        let permit = self.streams.clone().try_acquire_owned().ok()?;
+        let (query, param, count) = search_query(
+            search_type,
+            keyword,
+            match_mode,
+            order,
+            false,
+            self.full_text,
         );
+        let pool = self.streamer.clone();
+        let (sender, receiver) = mpsc::channel(SEARCH_STREAM_BUFFER_SIZE);
 
-        let mut query_builder = sqlx::query(&query);
-        for name in package_names {
-            query_builder = query_builder.bind(name);
-        }
-
-        query_builder
-            .fetch(&self.pool)
-            .and_then(async |row| -> sqlx::Result<DatabasePackageDetails> {
-                let info = DatabasePackageInfo {
-                    commit_id: row.get("commit_id"),
-                    branch: row.get("branch"),
-                    pkg_name: row.get("pkg_name"),
-                    pkg_desc: row.get("pkg_desc"),
-                    version: row.get("version"),
-                    url: row.get("url"),
+        tokio::spawn(async move {
+            // Released with the connection, once the last row was handed to the channel
+            let _permit = permit;
+            let mut query_builder = sqlx::query(&query);
+            for _ in 0..count {
+                query_builder = query_builder.bind(&param);
+            }
+            let mut rows = query_builder.fetch(&pool);
+            loop {
+                let item = match rows.try_next().await {
+                    Ok(Some(row)) => Ok(package_info_from_row(&row)),
+                    Ok(None) => break,
+                    Err(e) => Err(e.into()),
                 };
-
-                let package_name: String = row.get("pkg_name");
-                let pkg_branch: String = row.get("branch");
-
-                let tables = vec![
-                    ("pkg_depends", "depend"),
-                    ("pkg_make_depends", "make_depend"),
-                    ("pkg_opt_depends", "opt_depend"),
-                    ("pkg_check_depends", "check_depend"),
-                    ("pkg_provides", "provide"),
-                    ("pkg_conflicts", "conflict"),
-                    ("pkg_replaces", "replace"),
-                    ("pkg_groups", "group_name"),
-                ];
-
-                let mut depends = Vec::new();
-                let mut make_depends = Vec::new();
-                let mut opt_depends = Vec::new();
-                let mut check_depends = Vec::new();
-                let mut provides = Vec::new();
-                let mut conflicts = Vec::new();
-                let mut replaces = Vec::new();
-                let mut groups = Vec::new();
-
-                for (table, column) in tables {
-                    let query = format!(
-                        "SELECT {} FROM {} WHERE pkg_name = ? AND branch = ?",
-                        column, table
-                    );
-                    let values = sqlx::query(&query)
-                        .bind(&package_name)
-                        .bind(&pkg_branch)
-                        .fetch(&self.pool)
-                        .map_ok(|row| row.get::<String, _>(column))
-                        .try_collect()
-                        .await?;
-
-                    match column {
-                        "depend" => depends = values,
-                        "make_depend" => make_depends = values,
-                        "opt_depend" => opt_depends = values,
-                        "check_depend" => check_depends = values,
-                        "provide" => provides = values,
-                        "conflict" => conflicts = values,
-                        "replace" => replaces = values,
-                        "group_name" => groups = values,
-                        _ => {}
-                    }
+                let is_err = item.is_err();
+                // Stop reading once the consumer has gone away
+                if sender.send(item).await.is_err() || is_err {
+                    break;
                 }
-                Ok(DatabasePackageDetails {
-                    info,
-                    depends,
-                    make_depends,
-                    opt_depends,
-                    check_depends,
-                    provides,
-                    conflicts,
-                    replaces,
-                    groups,
-                })
-            })
-            .try_collect()
-            .await
-            .map_err(Into::into)
+            }
+        });
+
+        Some(stream::unfold(receiver, async |mut receiver| {
+            receiver.recv().await.map(|item| (item, receiver))
+        }))
     }
 
-    pub async fn get_branch_commit_id(&self, branch: &str) -> Result<Option<String>> {
-        let row = sqlx::query("SELECT commit_id FROM branch_commits WHERE branch = ? LIMIT 1")
-            .bind(branch)
-            .fetch_optional(&self.pool)
+    /// Version of the branch's packages, preferring the one named like the branch.
+    pub async fn get_branch_version(&self, branch: &str) -> Result<Option<String>> {
+        let row = sqlx::query(
+            r#"
+            SELECT version FROM pkg_info WHERE branch = ?
+            ORDER BY pkg_name = branch DESC
+            LIMIT 1
+        "#,
+        )
+        .bind(branch)
+        .fetch_optional(&self.reader)
+        .await?;
+        Ok(row.map(|r| r.get("version")))
+    }
+
+    pub async fn count_packages(&self) -> Result<i64> {
+        let row = sqlx::query("SELECT COUNT(*) FROM pkg_info")
+            .fetch_one(&self.reader)
+            .await?;
+        Ok(row.get(0))
+    }
+
+    pub async fn list_branches(&self) -> Result<Vec<String>> {
+        let rows = sqlx::query("SELECT branch FROM branch_commits")
+            .fetch_all(&self.reader)
+            .await?;
+        Ok(rows.iter().map(|row| row.get("branch")).collect())
+    }
+
+    /// Looks up the commits of many branches at once. Unknown branches are left out.
+    pub async fn get_branch_commit_ids(
+        &self,
+        branches: &[String],
+    ) -> Result<HashMap<String, String>> {
+        let mut commits = HashMap::with_capacity(branches.len());
+        for chunk in branches.chunks(MAX_BIND_PARAMS) {
+            let placeholders = vec!["?"; chunk.len()].join(",");
+            let query = format!(
+                "SELECT branch, commit_id FROM branch_commits WHERE branch IN ({})",
+                placeholders
+            );
+            let mut query_builder = sqlx::query(&query);
+            for branch in chunk {
+                query_builder = query_builder.bind(branch);
+            }
+            let mut rows = query_builder.fetch(&self.reader);
+            while let Some(row) = rows.try_next().await? {
+                commits.insert(row.get("branch"), row.get("commit_id"));
+            }
+        }
+        Ok(commits)
+    }
+
+    /// Replaces all rows of `pkg_meta` with the packages of a new AUR metadata dump.
+    pub async fn replace_package_meta(&self, packages: &[AurDumpPackage]) -> Result<()> {
+        const COLUMNS: usize = 6;
+        let mut tx = self.writer.begin().await?;
+        sqlx::query("DELETE FROM pkg_meta")
+            .execute(&mut *tx)
+            .await?;
+        for chunk in packages.chunks(MAX_BIND_PARAMS / COLUMNS) {
+            let placeholders = vec!["(?, ?, ?, ?, ?, ?)"; chunk.len()].join(",");
+            // The dump lists each name once, but a duplicate must not fail the update
+            let query = format!(
+                r#"
+                INSERT OR REPLACE INTO pkg_meta
+                    (pkg_name, maintainer, num_votes, popularity, first_submitted, last_modified)
+                VALUES {}
+            "#,
+                placeholders
+            );
+            let mut query_builder = sqlx::query(&query);
+            for package in chunk {
+                query_builder = query_builder
+                    .bind(&package.name)
+                    .bind(&package.meta.maintainer)
+                    .bind(package.meta.num_votes)
+                    .bind(package.meta.popularity)
+                    .bind(package.meta.first_submitted)
+                    .bind(package.meta.last_modified);
+            }
+            query_builder.execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Remembers that `branch` pointed at `commit_id`, so its snapshot stays servable.
+    pub async fn record_commit_history_with_tx(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+        branch: &str,
+        commit_id: &str,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT OR IGNORE INTO branch_commit_history (branch, commit_id, seen_at)
+            VALUES (?, ?, ?)
+        "#,
+        )
+        .bind(branch)
+        .bind(commit_id)
+        .bind(Utc::now().timestamp())
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+
+    /// Whether `commit_id` is the current or a recorded past commit of `branch`.
+    pub async fn is_known_commit(&self, branch: &str, commit_id: &str) -> Result<bool> {
+        let row = sqlx::query(
+            r#"
+            SELECT 1 FROM branch_commits WHERE branch = ? AND commit_id = ?
+            UNION ALL
+            SELECT 1 FROM branch_commit_history WHERE branch = ? AND commit_id = ?
+            LIMIT 1
+        "#,
+        )
+        .bind(branch)
+        .bind(commit_id)
+        .bind(branch)
+        .bind(commit_id)
+        .fetch_optional(&self.reader)
+        .await?;
+        Ok(row.is_some())
+    }
+
+    /// Looks up packages by name, ignoring ASCII case if `case_insensitive`. Only the
+    /// given relation tables are queried, the other relation arrays are left empty.
+    /// With `arch`, relations only hold the values for all architectures and that one,
+    /// otherwise those of every architecture.
+    pub async fn get_package_details(
+        &self,
+        package_names: &[String],
+        relations: &[Relation],
+        case_insensitive: bool,
+        arch: Option<&str>,
+        page: Option<Page>,
+    ) -> Result<Vec<DatabasePackageDetails>> {
+        if package_names.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut query = format!(
+            r#"{} {} ORDER BY p.pkg_name, p.branch"#,
+            PKG_INFO_SELECT,
+            package_names_filter(package_names.len(), case_insensitive)
+        );
+        if page.is_some() {
+            query.push_str(" LIMIT ? OFFSET ?");
+        }
+
+        let mut query_builder = sqlx::query(&query);
+        for name in package_names {
+            query_builder = query_builder.bind(name);
+        }
+        if let Some(page) = page {
+            query_builder = query_builder
+                .bind(page.limit as i64)
+                .bind(page.offset as i64);
+        }
+
+        // One connection for everything: querying relations on another one while the
+        // package cursor holds this one would deadlock a pool of one connection
+        let mut conn = self.reader.acquire().await?;
+        let rows = query_builder.fetch_all(&mut *conn).await?;
+        let mut packages = Vec::with_capacity(rows.len());
+        for row in rows {
+            let info = package_info_from_row(&row);
+
+            let package_name: String = row.get("pkg_name");
+            let pkg_branch: String = row.get("branch");
+
+            let mut details = DatabasePackageDetails {
+                info,
+                depends: Vec::new(),
+                make_depends: Vec::new(),
+                opt_depends: Vec::new(),
+                check_depends: Vec::new(),
+                provides: Vec::new(),
+                conflicts: Vec::new(),
+                replaces: Vec::new(),
+                groups: Vec::new(),
+                keywords: Vec::new(),
+                arch_values: HashMap::new(),
+            };
+
+            // Sorted, so identical data always gives identical responses
+            for &relation in relations {
+                let arch_filter = if arch.is_some() {
+                    "AND arch IN ('', ?)"
+                } else {
+                    ""
+                };
+                let query = format!(
+                    "SELECT DISTINCT {column} FROM {} WHERE pkg_name = ? AND branch = ? {} ORDER BY {column}",
+                    relation.table(),
+                    arch_filter,
+                    column = relation.column(),
+                );
+                let mut query_builder = sqlx::query(&query).bind(&package_name).bind(&pkg_branch);
+                if let Some(arch) = arch {
+                    query_builder = query_builder.bind(arch);
+                }
+                *details.relation_mut(relation) = query_builder
+                    .fetch(&mut *conn)
+                    .map_ok(|row| row.get::<String, _>(0))
+                    .try_collect()
+                    .await?;
+            }
+            packages.push(details);
+        }
+        Ok(packages)
+    }
+
+    /// Number of packages `get_package_details` finds without a page.
+    pub async fn count_package_details(
+        &self,
+        package_names: &[String],
+        case_insensitive: bool,
+    ) -> Result<u64> {
+        if package_names.is_empty() {
+            return Ok(0);
+        }
+        let query = format!(
+            "SELECT COUNT(*) FROM pkg_info p {}",
+            package_names_filter(package_names.len(), case_insensitive)
+        );
+        let mut query_builder = sqlx::query(&query);
+        for name in package_names {
+            query_builder = query_builder.bind(name);
+        }
+        let row = query_builder.fetch_one(&self.reader).await?;
+        Ok(row.get::<i64, _>(0) as u64)
+    }
+
+    /// Package bases (branches) that build a package named `pkg_name`.
+    pub async fn get_pkgbases_of(&self, pkg_name: &str) -> Result<Vec<String>> {
+        let rows =
+            sqlx::query("SELECT DISTINCT branch FROM pkg_info WHERE pkg_name = ? ORDER BY branch")
+                .bind(pkg_name)
+                .fetch_all(&self.reader)
+                .await?;
+        Ok(rows.iter().map(|row| row.get("branch")).collect())
+    }
+
+    /// Packages depending on `name`, with or without a version constraint, grouped by
+    /// dependency kind. Runtime dependencies are always included.
+    pub async fn reverse_depends(
+        &self,
+        name: &str,
+        include_make: bool,
+        include_opt: bool,
+        include_check: bool,
+    ) -> Result<Vec<(Relation, Vec<DatabasePackageInfo>)>> {
+        let relations = [
+            (Relation::Depends, true),
+            (Relation::MakeDepends, include_make),
+            (Relation::OptDepends, include_opt),
+            (Relation::CheckDepends, include_check),
+        ];
+        // `foo>=1.0`, or `foo: description` for optional dependencies
+        let constrained = format!("{}[<>=:]*", escape_glob(name));
+        let mut result = Vec::new();
+        for (relation, _) in relations.into_iter().filter(|(_, include)| *include) {
+            let query = format!(
+                r#"
+                {PKG_INFO_SELECT}
+                JOIN {table} r ON p.pkg_name = r.pkg_name AND p.branch = r.branch
+                WHERE r.{column} = ? OR r.{column} GLOB ?
+                ORDER BY p.pkg_name, p.branch
+            "#,
+                table = relation.table(),
+                column = relation.column(),
+            );
+            let packages = sqlx::query(&query)
+                .bind(name)
+                .bind(&constrained)
+                .fetch(&self.reader)
+                .map_ok(|row| package_info_from_row(&row))
+                .try_collect()
+                .await?;
+            result.push((relation, packages));
+        }
+        Ok(result)
+    }
+
+    /// Package names starting with `prefix`, or package base (branch) names with
+    /// `pkgbase_only`, each listed once and sorted case-insensitively.
+    pub async fn suggest_packages(
+        &self,
+        prefix: &str,
+        pkgbase_only: bool,
+        limit: u32,
+    ) -> Result<Vec<String>> {
+        if prefix.is_empty() {
+            return Ok(Vec::new());
+        }
+        let column = if pkgbase_only { "branch" } else { "pkg_name" };
+        let sql = format!(
+            r#"
+                SELECT DISTINCT {column} AS name FROM pkg_info
+                WHERE {column} LIKE ? ESCAPE '\'
+                ORDER BY {column} COLLATE NOCASE, {column}
+                LIMIT ?
+            "#
+        );
+        let rows = sqlx::query(&sql)
+            .bind(format!("{}%", escape_like(prefix)))
+            .bind(limit)
+            .fetch_all(&self.reader)
+            .await?;
+        Ok(rows.iter().map(|row| row.get("name")).collect())
+    }
+
+    pub async fn get_branch_commit_id(&self, branch: &str) -> Result<Option<String>> {
+        let row = sqlx::query("SELECT commit_id FROM branch_commits WHERE branch = ? LIMIT 1")
+            .bind(branch)
+            .fetch_optional(&self.reader)
             .await?;
 
         Ok(row.map(|r| r.get("commit_id")))
     }
+
+    /// Records something that went wrong while syncing a branch, e.g. an empty .SRCINFO.
+    pub async fn record_problem_with_tx(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+        branch: &str,
+        commit_id: &str,
+        kind: &str,
+        message: &str,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO sync_problems (branch, commit_id, kind, message, recorded_at)
+            VALUES (?, ?, ?, ?, ?)
+        "#,
+        )
+        .bind(branch)
+        .bind(commit_id)
+        .bind(kind)
+        .bind(message)
+        .bind(Utc::now().timestamp())
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+
+    /// Records a branch whose fetch failed. Consecutive failures of the same commit
+    /// increase its retry count, a new commit starts over.
+    pub async fn record_failed_branch_with_tx(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+        branch: &str,
+        commit_id: &str,
+        error: &str,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO failed_branches (branch, commit_id, last_error, retry_count, failed_at)
+            VALUES (?, ?, ?, 1, ?)
+            ON CONFLICT (branch) DO UPDATE SET
+                retry_count = CASE WHEN commit_id = excluded.commit_id
+                    THEN retry_count + 1 ELSE 1 END,
+                commit_id = excluded.commit_id,
+                last_error = excluded.last_error,
+                failed_at = excluded.failed_at
+        "#,
+        )
+        .bind(branch)
+        .bind(commit_id)
+        .bind(error)
+        .bind(Utc::now().timestamp())
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+
+    /// The failed commit and number of failures of every branch that has not been
+    /// fetched successfully since.
+    pub async fn get_failed_branches(&self) -> Result<HashMap<String, (String, u32)>> {
+        let mut rows = sqlx::query("SELECT branch, commit_id, retry_count FROM failed_branches")
+            .fetch(&self.reader);
+        let mut failed = HashMap::new();
+        while let Some(row) = rows.try_next().await? {
+            failed.insert(
+                row.get("branch"),
+                (row.get("commit_id"), row.get("retry_count")),
+            );
+        }
+        Ok(failed)
+    }
+
+    /// Forgets failures of branches that no longer exist upstream.
+    pub async fn forget_failed_branches(&self, branches: &[String]) -> Result<()> {
+        let mut tx = self.writer.begin().await?;
+        for branch in branches {
+            sqlx::query("DELETE FROM failed_branches WHERE branch = ?")
+                .bind(branch)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Deletes auxiliary rows recorded before the unix timestamp `before`.
+    /// Returns the number of rows deleted.
+    pub async fn prune_with_tx(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+        before: i64,
+    ) -> Result<u64> {
+        let problems = sqlx::query("DELETE FROM sync_problems WHERE recorded_at < ?")
+            .bind(before)
+            .execute(&mut **tx)
+            .await?;
+        let history = sqlx::query("DELETE FROM branch_commit_history WHERE seen_at < ?")
+            .bind(before)
+            .execute(&mut **tx)
+            .await?;
+        let changes = sqlx::query("DELETE FROM pkg_changes WHERE changed_at < ?")
+            .bind(before)
+            .execute(&mut **tx)
+            .await?;
+        Ok(problems.rows_affected() + history.rows_affected() + changes.rows_affected())
+    }
+
+    /// Versions and dependencies currently stored for the packages of a branch,
+    /// to compare a new commit against.
+    pub async fn get_package_states_with_tx(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+        branch: &str,
+    ) -> Result<HashMap<String, PackageState>> {
+        let mut states: HashMap<String, PackageState> =
+            sqlx::query("SELECT pkg_name, version FROM pkg_info WHERE branch = ?")
+                .bind(branch)
+                .fetch_all(&mut **tx)
+                .await?
+                .into_iter()
+                .map(|row| {
+                    let state = PackageState {
+                        version: row.get("version"),
+                        depends: HashSet::new(),
+                    };
+                    (row.get("pkg_name"), state)
+                })
+                .collect();
+        for relation in Relation::DEPENDENCIES {
+            let query = format!(
+                "SELECT pkg_name, {} FROM {} WHERE branch = ?",
+                relation.column(),
+                relation.table()
+            );
+            let rows = sqlx::query(&query)
+                .bind(branch)
+                .fetch_all(&mut **tx)
+                .await?;
+            for row in rows {
+                if let Some(state) = states.get_mut(&row.get::<String, _>(0)) {
+                    state.depends.insert((relation, row.get(1)));
+                }
+            }
+        }
+        Ok(states)
+    }
+
+    pub async fn record_package_change_with_tx(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+        change: &PackageChange,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO pkg_changes
+            (branch, pkg_name, kind, old_version, new_version, commit_id, changed_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+        "#,
+        )
+        .bind(&change.branch)
+        .bind(&change.pkg_name)
+        .bind(&change.kind)
+        .bind(&change.old_version)
+        .bind(&change.new_version)
+        .bind(&change.commit_id)
+        .bind(change.changed_at)
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+
+    /// Package changes recorded at or after the unix timestamp `since`, newest first.
+    pub async fn get_package_changes(&self, since: i64, limit: u32) -> Result<Vec<PackageChange>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT * FROM pkg_changes WHERE changed_at >= ?
+            ORDER BY changed_at DESC, rowid DESC
+            LIMIT ?
+        "#,
+        )
+        .bind(since)
+        .bind(limit)
+        .fetch_all(&self.reader)
+        .await?;
+        Ok(rows
+            .iter()
+            .map(|row| PackageChange {
+                branch: row.get("branch"),
+                pkg_name: row.get("pkg_name"),
+                kind: row.get("kind"),
+                old_version: row.get("old_version"),
+                new_version: row.get("new_version"),
+                commit_id: row.get("commit_id"),
+                changed_at: row.get("changed_at"),
+            })
+            .collect())
+    }
+
+    /// Returns the rows stored for one package, keyed by table name, exactly as they are
+    /// in the database. Meant for diagnosing indexing issues.
+    pub async fn get_raw_package_rows(
+        &self,
+        branch: &str,
+        pkg_name: &str,
+    ) -> Result<BTreeMap<&'static str, Vec<serde_json::Value>>> {
+        let mut tables = BTreeMap::new();
+        let rows = sqlx::query("SELECT * FROM branch_commits WHERE branch = ?")
+            .bind(branch)
+            .fetch_all(&self.reader)
+            .await?;
+        tables.insert("branch_commits", rows.iter().map(row_to_json).collect());
+
+        let table_names = ["pkg_info"]
+            .into_iter()
+            .chain(Relation::ALL.iter().map(|relation| relation.table()));
+        for table in table_names {
+            let rows = sqlx::query(&format!(
+                "SELECT * FROM {} WHERE branch = ? AND pkg_name = ?",
+                table
+            ))
+            .bind(branch)
+            .bind(pkg_name)
+            .fetch_all(&self.reader)
+            .await?;
+            tables.insert(table, rows.iter().map(row_to_json).collect());
+        }
+        Ok(tables)
+    }
+
+    /// Counts the runtime dependencies of every package in the given branches.
+    pub async fn get_depends_counts(
+        &self,
+        branches: &[String],
+    ) -> Result<HashMap<(String, String), u32>> {
+        let mut counts = HashMap::new();
+        for chunk in branches.chunks(MAX_BIND_PARAMS) {
+            let placeholders = vec!["?"; chunk.len()].join(",");
+            let query = format!(
+                r#"
+                SELECT branch, pkg_name, COUNT(DISTINCT depend) FROM pkg_depends
+                WHERE branch IN ({})
+                GROUP BY branch, pkg_name
+            "#,
+                placeholders
+            );
+            let mut query_builder = sqlx::query(&query);
+            for branch in chunk {
+                query_builder = query_builder.bind(branch);
+            }
+            let mut rows = query_builder.fetch(&self.reader);
+            while let Some(row) = rows.try_next().await? {
+                counts.insert((row.get(0), row.get(1)), row.get(2));
+            }
+        }
+        Ok(counts)
+    }
+
+    /// Checks that the database answers queries.
+    pub async fn ping(&self) -> Result<()> {
+        sqlx::query("SELECT 1").execute(&self.reader).await?;
+        Ok(())
+    }
+
+    /// Unix timestamp of the last sync that completed without failed branches.
+    pub async fn get_last_sync_at(&self) -> Result<Option<i64>> {
+        Ok(self
+            .get_meta(META_LAST_SYNC_AT)
+            .await?
+            .and_then(|value| value.parse().ok()))
+    }
+
+    /// Number of branches upstream when the branch list was last fetched, including
+    /// those a sync skipped or failed to index.
+    pub async fn get_branch_list_count(&self) -> Result<Option<i64>> {
+        Ok(self
+            .get_meta(META_BRANCH_LIST_COUNT)
+            .await?
+            .and_then(|value| value.parse().ok()))
+    }
+
+    pub async fn set_branch_list_count(&self, count: usize) -> Result<()> {
+        self.set_meta(META_BRANCH_LIST_COUNT, &count.to_string())
+            .await
+    }
+
+    /// How long the last sync that completed without failed branches took.
+    pub async fn get_last_sync_duration(&self) -> Result<Option<Duration>> {
+        Ok(self
+            .get_meta(META_LAST_SYNC_DURATION_MS)
+            .await?
+            .and_then(|value| value.parse().ok())
+            .map(Duration::from_millis))
+    }
+
+    pub async fn set_last_sync_with_tx(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+        timestamp: i64,
+        duration: Duration,
+    ) -> Result<()> {
+        for (key, value) in [
+            (META_LAST_SYNC_AT, timestamp.to_string()),
+            (META_LAST_SYNC_DURATION_MS, duration.as_millis().to_string()),
+        ] {
+            sqlx::query("INSERT OR REPLACE INTO sync_meta (key, value) VALUES (?, ?)")
+                .bind(key)
+                .bind(value)
+                .execute(&mut **tx)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Row count of every table.
+    pub async fn table_counts(&self) -> Result<HashMap<String, i64>> {
+        let tables = ["branch_commits", "pkg_info"]
+            .into_iter()
+            .chain(Relation::ALL.map(Relation::table))
+            .chain([
+                "sync_problems",
+                "failed_branches",
+                "pkg_meta",
+                "branch_commit_history",
+                "pkg_changes",
+            ]);
+        let mut counts = HashMap::new();
+        for table in tables {
+            let row = sqlx::query(&format!("SELECT COUNT(*) FROM {}", table))
+                .fetch_one(&self.reader)
+                .await?;
+            counts.insert(table.to_string(), row.get(0));
+        }
+        Ok(counts)
+    }
+
+    pub async fn count_branches(&self) -> Result<i64> {
+        let row = sqlx::query("SELECT COUNT(*) FROM branch_commits")
+            .fetch_one(&self.reader)
+            .await?;
+        Ok(row.get(0))
+    }
+
+    pub async fn get_meta(&self, key: &str) -> Result<Option<String>> {
+        Ok(
+            sqlx::query_scalar("SELECT value FROM sync_meta WHERE key = ?")
+                .bind(key)
+                .fetch_optional(&self.reader)
+                .await?,
+        )
+    }
+
+    pub async fn set_meta(&self, key: &str, value: &str) -> Result<()> {
+        sqlx::query("INSERT OR REPLACE INTO sync_meta (key, value) VALUES (?, ?)")
+            .bind(key)
+            .bind(value)
+            .execute(&self.writer)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_meta(&self, key: &str) -> Result<()> {
+        sqlx::query("DELETE FROM sync_meta WHERE key = ?")
+            .bind(key)
+            .execute(&self.writer)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Relation tables share one layout, `arch` is empty for values of all architectures.
+fn relation_table_sql(relation: Relation) -> String {
+    format!(
+        r#"CREATE TABLE IF NOT EXISTS {table} (
+                branch TEXT NOT NULL,
+                pkg_name TEXT NOT NULL,
+                arch TEXT NOT NULL DEFAULT '',
+                {column} TEXT NOT NULL,
+                PRIMARY KEY (branch, pkg_name, arch, {column})
+            )"#,
+        table = relation.table(),
+        column = relation.column()
+    )
+}
+
+/// The pool of streamed searches, connected on first use.
+fn stream_pool(options: SqliteConnectOptions) -> SqlitePool {
+    SqlitePoolOptions::new()
+        .max_connections(MAX_CONCURRENT_STREAMS as u32)
+        .connect_lazy_with(options)
+}
+
+/// Selects `pkg_info` rows as `p`, along with the commit date of their branch and their
+/// `pkg_meta` row, if any.
+const PKG_INFO_SELECT: &str = r#"SELECT DISTINCT p.*, b.committed_at,
+                m.pkg_name AS meta_pkg_name, m.maintainer, m.num_votes, m.popularity,
+                m.first_submitted, m.last_modified AS meta_last_modified
+                FROM pkg_info p
+                LEFT JOIN branch_commits b ON b.branch = p.branch
+                LEFT JOIN pkg_meta m ON m.pkg_name = p.pkg_name"#;
+
+/// `WHERE` clause of `get_package_details`, with one placeholder per name.
+fn package_names_filter(count: usize, case_insensitive: bool) -> String {
+    let collation = if case_insensitive {
+        "COLLATE NOCASE"
+    } else {
+        ""
+    };
+    format!(
+        "WHERE p.pkg_name {} IN ({})",
+        collation,
+        vec!["?"; count].join(",")
+    )
+}
+
+fn package_info_from_row(row: &SqliteRow) -> DatabasePackageInfo {
+    DatabasePackageInfo {
+        commit_id: row.get("commit_id"),
+        branch: row.get("branch"),
+        pkg_name: row.get("pkg_name"),
+        pkg_desc: row.get("pkg_desc"),
+        version: row.get("version"),
+        url: row.get("url"),
+        last_modified: row.get("committed_at"),
+        version_is_placeholder: row.get("version_is_placeholder"),
+        meta: row
+            .get::<Option<String>, _>("meta_pkg_name")
+            .map(|_| PackageMeta {
+                maintainer: row.get("maintainer"),
+                num_votes: row.get("num_votes"),
+                popularity: row.get("popularity"),
+                first_submitted: row.get("first_submitted"),
+                last_modified: row.get("meta_last_modified"),
+            }),
+    }
+}
+
+/// Escapes `%`, `_` and the escape character itself for a `LIKE ... ESCAPE '\'` pattern.
+fn escape_like(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// An FTS5 query matching every word of `keyword` as a prefix, or `None` if it has no
+/// words. Words are quoted, so characters like `-` or `:` cannot form operators.
+fn full_text_query(keyword: &str) -> Option<String> {
+    let words: Vec<String> = keyword
+        .split_whitespace()
+        .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+        .collect();
+    (!words.is_empty()).then(|| words.join(" "))
+}
+
+/// Escapes `*`, `?` and `[` so `s` matches literally in a GLOB pattern.
+fn escape_glob(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '*' | '?' | '[' => {
+                escaped.push('[');
+                escaped.push(c);
+                escaped.push(']');
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Converts a row of any table into a JSON object, keeping column names and types.
+fn row_to_json(row: &SqliteRow) -> serde_json::Value {
+    let object = row
+        .columns()
+        .iter()
+        .map(|column| {
+            let name = column.name();
+            let value = if let Ok(value) = row.try_get::<Option<String>, _>(name) {
+                value.into()
+            } else if let Ok(value) = row.try_get::<Option<i64>, _>(name) {
+                value.into()
+            } else {
+                serde_json::Value::Null
+            };
+            (name.to_string(), value)
+        })
+        .collect();
+    serde_json::Value::Object(object)
+}
+
+/// Returns the SQL, the bound parameter and how many times it must be bound.
+/// `full_text` tells whether `pkg_fts` exists, full-text searches fall back to
+/// `name-desc` without it.
+/// Without an explicit order, full-text matches are ranked, `paged` queries are sorted by
+/// name and branch so consecutive pages neither overlap nor skip rows, and other results
+/// come in whatever order the database reads them.
+fn search_query(
+    search_type: SearchType,
+    keyword: &str,
+    match_mode: MatchMode,
+    order: Option<SortOrder>,
+    paged: bool,
+    full_text: bool,
+) -> (String, String, usize) {
+    let (mut query, param, count) = search_filter(search_type, keyword, match_mode, full_text);
+    let ranked = search_type == SearchType::FullText && full_text && count > 0;
+    match order {
+        Some(order) => query.push_str(&format!(" ORDER BY {}", order.order_by())),
+        None if ranked => query.push_str(" ORDER BY f.rank"),
+        None if paged => query.push_str(" ORDER BY p.pkg_name, p.branch"),
+        None => {}
+    }
+    (query, param, count)
+}
+
+fn search_filter(
+    search_type: SearchType,
+    keyword: &str,
+    match_mode: MatchMode,
+    full_text: bool,
+) -> (String, String, usize) {
+    if let Some(relation) = search_type.relation() {
+        let (operator, param) = match match_mode {
+            MatchMode::Exact => ("=", keyword.to_string()),
+            MatchMode::Substring => ("LIKE", format!("%{}%", escape_like(keyword))),
+        };
+        let query = format!(
+            r#"
+                {PKG_INFO_SELECT}
+                JOIN {table} r ON p.pkg_name = r.pkg_name AND p.branch = r.branch
+                WHERE r.{column} {operator} ? {escape}
+            "#,
+            table = relation.table(),
+            column = relation.column(),
+            escape = if operator == "LIKE" {
+                r"ESCAPE '\'"
+            } else {
+                ""
+            },
+        );
+        return (query, param, 1);
+    }
+
+    match search_type {
+        SearchType::FullText if full_text => match full_text_query(keyword) {
+            Some(query) => (
+                format!(
+                    r#"
+                {PKG_INFO_SELECT}
+                JOIN pkg_fts f ON f.rowid = p.rowid
+                WHERE f.pkg_fts MATCH ?
+            "#
+                ),
+                query,
+                1,
+            ),
+            None => (format!("{PKG_INFO_SELECT} WHERE 0"), String::new(), 0),
+        },
+        // Compared in lowercase, since `LIKE` alone only ignores the case of ASCII letters
+        SearchType::NameDesc | SearchType::FullText => (
+            format!(
+                r#"
+                {PKG_INFO_SELECT}
+                WHERE (p.pkg_name_lc LIKE ? OR p.pkg_desc_lc LIKE ?)
+            "#
+            ),
+            format!("%{}%", keyword.to_lowercase()),
+            2,
+        ),
+        // Empty unless the AUR metadata dump was fetched
+        SearchType::Maintainer => (
+            format!("{PKG_INFO_SELECT} WHERE m.maintainer = ? COLLATE NOCASE"),
+            keyword.to_string(),
+            1,
+        ),
+        // Not in the metadata dump either
+        SearchType::Submitter => (format!("{PKG_INFO_SELECT} WHERE 0"), String::new(), 0),
+        // SearchType::Name
+        _ => (
+            format!(
+                r#"
+                {PKG_INFO_SELECT}
+                WHERE p.pkg_name_lc LIKE ?
+            "#
+            ),
+            format!("%{}%", keyword.to_lowercase()),
+            1,
+        ),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A fresh database in a temporary directory, deleted with it.
+    pub(crate) struct TestDb {
+        pub db: DatabaseOps,
+        _dir: tempfile::TempDir,
+    }
+
+    impl std::ops::Deref for TestDb {
+        type Target = DatabaseOps;
+
+        fn deref(&self) -> &DatabaseOps {
+            &self.db
+        }
+    }
+
+    pub(crate) async fn test_db() -> TestDb {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let db = DatabaseOps::new(path.to_str().unwrap(), 4).await.unwrap();
+        TestDb { db, _dir: dir }
+    }
+
+    pub(crate) fn test_package(branch: &str, pkg_name: &str) -> DatabasePackageDetails {
+        DatabasePackageDetails {
+            info: DatabasePackageInfo {
+                branch: branch.to_string(),
+                commit_id: format!("{}-commit", branch),
+                pkg_name: pkg_name.to_string(),
+                pkg_desc: None,
+                version: "1.0-1".to_string(),
+                url: None,
+                last_modified: None,
+                version_is_placeholder: false,
+                meta: None,
+            },
+            depends: Vec::new(),
+            make_depends: Vec::new(),
+            opt_depends: Vec::new(),
+            check_depends: Vec::new(),
+            provides: Vec::new(),
+            conflicts: Vec::new(),
+            replaces: Vec::new(),
+            groups: Vec::new(),
+            keywords: Vec::new(),
+            arch_values: HashMap::new(),
+        }
+    }
+
+    /// Stores `packages` as the index of their branches, like a sync does.
+    pub(crate) async fn insert_packages(db: &DatabaseOps, packages: &[DatabasePackageDetails]) {
+        let mut tx = db.begin_transaction().await.unwrap();
+        for pkg in packages {
+            db.update_branch_commit_with_tx(&mut tx, &pkg.info.branch, &pkg.info.commit_id, None)
+                .await
+                .unwrap();
+        }
+        db.update_index_with_tx(&mut tx, packages).await.unwrap();
+        tx.commit().await.unwrap();
+    }
+
+    fn names(packages: &[DatabasePackageDetails]) -> Vec<&str> {
+        packages.iter().map(|p| p.info.pkg_name.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn package_details_only_query_selected_relations() {
+        let db = test_db().await;
+        let mut pkg = test_package("foo", "foo");
+        pkg.depends = vec!["bar".to_string()];
+        pkg.provides = vec!["foo-bin".to_string()];
+        pkg.groups = vec!["tools".to_string()];
+        insert_packages(&db, &[pkg]).await;
+
+        let details = db
+            .get_package_details(
+                &["foo".to_string()],
+                &[Relation::Depends],
+                false,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(names(&details), ["foo"]);
+        assert_eq!(details[0].depends, ["bar"]);
+        assert!(details[0].provides.is_empty());
+        assert!(details[0].groups.is_empty());
+
+        let details = db
+            .get_package_details(&["foo".to_string()], &[], false, None, None)
+            .await
+            .unwrap();
+        assert!(details[0].depends.is_empty());
+    }
+
+    #[tokio::test]
+    async fn suggest_lists_names_once_in_case_insensitive_order() {
+        let db = test_db().await;
+        insert_packages(
+            &db,
+            &[
+                test_package("foo", "foo"),
+                test_package("foo-split", "foo"),
+                test_package("foo-split", "Foo-bar"),
+                test_package("foo-split", "foo-baz"),
+                test_package("other", "bar"),
+            ],
+        )
+        .await;
+
+        assert_eq!(
+            db.suggest_packages("foo", false, 20).await.unwrap(),
+            ["foo", "Foo-bar", "foo-baz"]
+        );
+        assert_eq!(
+            db.suggest_packages("foo", false, 2).await.unwrap(),
+            ["foo", "Foo-bar"]
+        );
+        assert_eq!(
+            db.suggest_packages("foo", true, 20).await.unwrap(),
+            ["foo", "foo-split"]
+        );
+    }
+
+    #[tokio::test]
+    async fn suggest_empty_prefix_is_empty() {
+        let db = test_db().await;
+        insert_packages(&db, &[test_package("foo", "foo")]).await;
+        assert!(db.suggest_packages("", false, 20).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn suggest_treats_wildcards_literally() {
+        let db = test_db().await;
+        insert_packages(
+            &db,
+            &[
+                test_package("a", "a_b"),
+                test_package("a", "axb"),
+                test_package("c", "c%d"),
+                test_package("c", "cxd"),
+            ],
+        )
+        .await;
+        assert_eq!(db.suggest_packages("a_", false, 20).await.unwrap(), ["a_b"]);
+        assert_eq!(db.suggest_packages("c%", false, 20).await.unwrap(), ["c%d"]);
+        assert!(db
+            .suggest_packages("%", false, 20)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn info_matches_names_case_insensitively_on_request() {
+        let db = test_db().await;
+        insert_packages(
+            &db,
+            &[
+                test_package("firefox", "firefox"),
+                test_package("foo", "Foo"),
+            ],
+        )
+        .await;
+        let requested = ["Firefox".to_string(), "FOO".to_string()];
+
+        let details = db
+            .get_package_details(&requested, &[], false, None, None)
+            .await
+            .unwrap();
+        assert!(details.is_empty());
+        assert_eq!(
+            db.count_package_details(&requested, false).await.unwrap(),
+            0
+        );
+
+        let details = db
+            .get_package_details(&requested, &[], true, None, None)
+            .await
+            .unwrap();
+        let mut found = names(&details);
+        found.sort();
+        assert_eq!(found, ["Foo", "firefox"]);
+        assert_eq!(db.count_package_details(&requested, true).await.unwrap(), 2);
+    }
+
+    async fn search_names(db: &DatabaseOps, search_type: SearchType, keyword: &str) -> Vec<String> {
+        let mut names: Vec<_> = db
+            .search_packages(search_type, keyword, MatchMode::Exact, None, None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|p| p.pkg_name)
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn described(branch: &str, pkg_name: &str, pkg_desc: &str) -> DatabasePackageDetails {
+        let mut pkg = test_package(branch, pkg_name);
+        pkg.info.pkg_desc = Some(pkg_desc.to_string());
+        pkg
+    }
+
+    #[tokio::test]
+    async fn search_folds_unicode_case() {
+        let db = test_db().await;
+        insert_packages(
+            &db,
+            &[
+                described("a", "Ärger-Tool", "Plain"),
+                described("b", "plain", "ÜBER fast Читалка"),
+                described("c", "strasse", "Große Straße"),
+            ],
+        )
+        .await;
+
+        assert_eq!(
+            search_names(&db, SearchType::Name, "ärger").await,
+            ["Ärger-Tool"]
+        );
+        assert_eq!(
+            search_names(&db, SearchType::Name, "ÄRGER").await,
+            ["Ärger-Tool"]
+        );
+        assert_eq!(
+            search_names(&db, SearchType::NameDesc, "über").await,
+            ["plain"]
+        );
+        assert_eq!(
+            search_names(&db, SearchType::NameDesc, "читалка").await,
+            ["plain"]
+        );
+        assert_eq!(
+            search_names(&db, SearchType::NameDesc, "PLAIN").await,
+            ["plain", "Ärger-Tool"]
+        );
+        // Lowercasing does not fold ß into ss
+        assert_eq!(
+            search_names(&db, SearchType::NameDesc, "STRAßE").await,
+            ["strasse"]
+        );
+        assert_eq!(
+            search_names(&db, SearchType::Name, "straße").await,
+            Vec::<String>::new()
+        );
+        // Descriptions are only searched by name-desc
+        assert!(search_names(&db, SearchType::Name, "über").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn lowercase_columns_are_filled_for_old_rows() {
+        let db = test_db().await;
+        insert_packages(&db, &[described("a", "Ärger", "ÜBER")]).await;
+        sqlx::query("UPDATE pkg_info SET pkg_name_lc = NULL, pkg_desc_lc = NULL")
+            .execute(&db.writer)
+            .await
+            .unwrap();
+        assert!(search_names(&db, SearchType::NameDesc, "über")
+            .await
+            .is_empty());
+
+        db.fill_lowercase_columns().await.unwrap();
+        assert_eq!(
+            search_names(&db, SearchType::NameDesc, "über").await,
+            ["Ärger"]
+        );
+        assert_eq!(
+            search_names(&db, SearchType::Name, "ärger").await,
+            ["Ärger"]
+        );
+    }
+
+    #[tokio::test]
+    async fn search_by_relations() {
+        let db = test_db().await;
+        let mut foo = test_package("foo", "foo");
+        foo.provides = vec!["foo-bin".to_string()];
+        foo.conflicts = vec!["foo-git".to_string()];
+        foo.replaces = vec!["oldfoo".to_string()];
+        foo.groups = vec!["tools".to_string()];
+        foo.keywords = vec!["editor".to_string()];
+        let mut bar = test_package("bar", "bar");
+        bar.provides = vec!["foo-bin".to_string()];
+        bar.groups = vec!["other".to_string()];
+        insert_packages(&db, &[foo, bar]).await;
+
+        for (search_type, keyword, expected) in [
+            (SearchType::Provides, "foo-bin", &["bar", "foo"][..]),
+            (SearchType::Conflicts, "foo-git", &["foo"]),
+            (SearchType::Replaces, "oldfoo", &["foo"]),
+            (SearchType::Groups, "tools", &["foo"]),
+            (SearchType::Keywords, "editor", &["foo"]),
+            (SearchType::Groups, "missing", &[]),
+            // Exact matches only
+            (SearchType::Provides, "foo", &[]),
+        ] {
+            assert_eq!(
+                search_names(&db, search_type, keyword).await,
+                expected,
+                "{:?} {}",
+                search_type,
+                keyword
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn search_by_maintainer_uses_metadata() {
+        let db = test_db().await;
+        insert_packages(
+            &db,
+            &[test_package("foo", "foo"), test_package("bar", "bar")],
+        )
+        .await;
+        assert!(search_names(&db, SearchType::Maintainer, "alice")
+            .await
+            .is_empty());
+
+        let meta = |maintainer: Option<&str>| PackageMeta {
+            maintainer: maintainer.map(str::to_string),
+            num_votes: 0,
+            popularity: 0.0,
+            first_submitted: 0,
+            last_modified: 0,
+        };
+        db.replace_package_meta(&[
+            AurDumpPackage {
+                name: "foo".to_string(),
+                meta: meta(Some("alice")),
+            },
+            AurDumpPackage {
+                name: "bar".to_string(),
+                meta: meta(None),
+            },
+        ])
+        .await
+        .unwrap();
+        assert_eq!(
+            search_names(&db, SearchType::Maintainer, "alice").await,
+            ["foo"]
+        );
+        assert!(search_names(&db, SearchType::Submitter, "alice")
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn pages_without_sort_follow_name_and_branch() {
+        let db = test_db().await;
+        insert_packages(
+            &db,
+            &[
+                test_package("zeta", "zeta"),
+                test_package("alpha-split", "alpha"),
+                test_package("mid", "mid"),
+                test_package("alpha", "alpha"),
+                test_package("beta", "beta"),
+            ],
+        )
+        .await;
+
+        let mut paged = Vec::new();
+        for offset in (0..6).step_by(2) {
+            let page = Page { offset, limit: 2 };
+            let rows = db
+                .search_packages(
+                    SearchType::Name,
+                    "a",
+                    MatchMode::Substring,
+                    None,
+                    Some(page),
+                )
+                .await
+                .unwrap();
+            paged.extend(rows.into_iter().map(|p| (p.pkg_name, p.branch)));
+        }
+        let expected: Vec<_> = [
+            ("alpha", "alpha"),
+            ("alpha", "alpha-split"),
+            ("beta", "beta"),
+            ("zeta", "zeta"),
+        ]
+        .iter()
+        .map(|(n, b)| (n.to_string(), b.to_string()))
+        .collect();
+        assert_eq!(paged, expected);
+    }
+
+    #[tokio::test]
+    async fn package_details_work_with_a_single_reader_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let db = DatabaseOps::new(path.to_str().unwrap(), 1).await.unwrap();
+        let mut foo = test_package("foo", "foo");
+        foo.depends = vec!["bar".to_string()];
+        foo.keywords = vec!["text".to_string()];
+        insert_packages(&db, &[foo, test_package("foo-split", "foo")]).await;
+
+        let details = tokio::time::timeout(
+            Duration::from_secs(5),
+            db.get_package_details(&["foo".to_string()], &Relation::ALL, false, None, None),
+        )
+        .await
+        .expect("package details waited for a second reader connection")
+        .unwrap();
+        assert_eq!(names(&details), ["foo", "foo"]);
+        assert_eq!(details[0].depends, ["bar"]);
+        assert_eq!(details[0].keywords, ["text"]);
+    }
+
+    #[tokio::test]
+    async fn readers_are_not_blocked_by_an_open_write_transaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let db = DatabaseOps::new(path.to_str().unwrap(), 2).await.unwrap();
+        let mut foo = test_package("foo", "foo");
+        foo.depends = vec!["bar".to_string()];
+        insert_packages(&db, &[foo.clone()]).await;
+
+        let mut tx = db.begin_transaction().await.unwrap();
+        foo.info.version = "2.0-1".to_string();
+        db.update_index_with_tx(&mut tx, &[foo]).await.unwrap();
+
+        // More queries than reader connections, each reading the committed version
+        let requested = ["foo".to_string()];
+        let queries =
+            (0..8).map(|_| db.get_package_details(&requested, &Relation::ALL, false, None, None));
+        let results = tokio::time::timeout(
+            Duration::from_secs(5),
+            futures::future::try_join_all(queries),
+        )
+        .await
+        .expect("readers waited for the write transaction")
+        .unwrap();
+        for details in results {
+            assert_eq!(details[0].info.version, "1.0-1");
+            assert_eq!(details[0].depends, ["bar"]);
+        }
+
+        tx.commit().await.unwrap();
+        let details = db
+            .get_package_details(&requested, &[], false, None, None)
+            .await
+            .unwrap();
+        assert_eq!(details[0].info.version, "2.0-1");
+    }
+
+    #[tokio::test]
+    async fn substring_relation_search_escapes_wildcards() {
+        let db = test_db().await;
+        let mut foo = test_package("foo", "foo");
+        foo.depends = vec!["python_foo".to_string()];
+        let mut bar = test_package("bar", "bar");
+        bar.depends = vec!["python-bar".to_string()];
+        let mut baz = test_package("baz", "baz");
+        baz.depends = vec!["glibc".to_string()];
+        insert_packages(&db, &[foo, bar, baz]).await;
+
+        let search = async |keyword: &str| {
+            let mut names: Vec<_> = db
+                .search_packages(
+                    SearchType::Depends,
+                    keyword,
+                    MatchMode::Substring,
+                    None,
+                    None,
+                )
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|p| p.pkg_name)
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(search("python").await, ["bar", "foo"]);
+        assert_eq!(search("lib").await, ["baz"]);
+        assert_eq!(search("_").await, ["foo"]);
+        assert_eq!(search("python_").await, ["foo"]);
+        assert!(search("%").await.is_empty());
+        assert!(search("\\").await.is_empty());
+        assert_eq!(
+            search_names(&db, SearchType::Depends, "python").await,
+            Vec::<String>::new()
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_are_limited_and_leave_the_reader_pool_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let db = DatabaseOps::new(path.to_str().unwrap(), 1).await.unwrap();
+        // More rows than a stream buffers, so unread streams keep their connection
+        let packages: Vec<_> = (0..SEARCH_STREAM_BUFFER_SIZE * 3)
+            .map(|i| test_package(&format!("pkg{i}"), &format!("pkg{i}")))
+            .collect();
+        insert_packages(&db, &packages).await;
+
+        let stream = || db.search_packages_stream(SearchType::Name, "pkg", MatchMode::Exact, None);
+        let mut streams: Vec<_> = (0..MAX_CONCURRENT_STREAMS)
+            .map(|_| Box::pin(stream().unwrap()))
+            .collect();
+        for stream in &mut streams {
+            assert!(stream.try_next().await.unwrap().is_some());
+        }
+        assert!(stream().is_none());
+
+        let details = tokio::time::timeout(
+            Duration::from_secs(5),
+            db.get_package_details(&["pkg0".to_string()], &[], false, None, None),
+        )
+        .await
+        .expect("info waited for a connection held by a stream")
+        .unwrap();
+        assert_eq!(names(&details), ["pkg0"]);
+
+        // The slot is free again once the reader of a stream has gone away
+        streams.pop();
+        let mut count = 0;
+        let mut stream = loop {
+            if let Some(stream) = stream() {
+                break Box::pin(stream);
+            }
+            count += 1;
+            assert!(count < 500, "the stream slot was never released");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        let mut rows = 0;
+        while stream.try_next().await.unwrap().is_some() {
+            rows += 1;
+        }
+        assert_eq!(rows, packages.len());
+    }
 }
@@ -0,0 +1,129 @@
+//! Client address and public URL of a request, optionally taken from the
+//! `X-Forwarded-*` headers set by a reverse proxy.
+
+use axum::http::{header, HeaderMap};
+use std::net::{IpAddr, SocketAddr};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+
+/// The originating client address. With `trust_forwarded` this is the last address in
+/// `X-Forwarded-For`, the one our reverse proxy appended, otherwise the peer of the
+/// connection. Earlier entries are written by the client and cannot be trusted. A
+/// malformed last entry falls back to the peer.
+pub fn client_ip(headers: &HeaderMap, peer: SocketAddr, trust_forwarded: bool) -> IpAddr {
+    trust_forwarded
+        .then(|| forwarded_client_ip(headers))
+        .flatten()
+        // IPv4 clients of a dual-stack listener appear as `::ffff:a.b.c.d`
+        .unwrap_or_else(|| peer.ip().to_canonical())
+}
+
+/// The last address in `X-Forwarded-For`, if it is a valid one.
+pub fn forwarded_client_ip(headers: &HeaderMap) -> Option<IpAddr> {
+    forwarded_value(headers, X_FORWARDED_FOR)?.parse().ok()
+}
+
+/// Scheme and host the client used to reach us, e.g. `https://aur.example.com`.
+pub fn base_url(headers: &HeaderMap, trust_forwarded: bool) -> String {
+    let forwarded = |name| {
+        trust_forwarded
+            .then(|| forwarded_value(headers, name))
+            .flatten()
+    };
+    let scheme = forwarded(X_FORWARDED_PROTO).unwrap_or("http");
+    let host = forwarded(X_FORWARDED_HOST)
+        .or_else(|| headers.get(header::HOST).and_then(|v| v.to_str().ok()))
+        .unwrap_or("localhost");
+    format!("{}://{}", scheme, host)
+}
+
+/// Last entry of a comma separated header, possibly split over several header lines,
+/// which is the one added by the reverse proxy in front of us.
+fn forwarded_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .next_back()?
+        .to_str()
+        .ok()?
+        .rsplit(',')
+        .next()
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    const PEER: &str = "10.0.0.1:1234";
+
+    fn client_ip_with(values: &[&str], trust_forwarded: bool) -> String {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(X_FORWARDED_FOR, HeaderValue::from_str(value).unwrap());
+        }
+        client_ip(&headers, PEER.parse().unwrap(), trust_forwarded).to_string()
+    }
+
+    #[test]
+    fn uses_last_hop() {
+        assert_eq!(client_ip_with(&["203.0.113.7"], true), "203.0.113.7");
+        assert_eq!(
+            client_ip_with(&["198.51.100.1, 203.0.113.7"], true),
+            "203.0.113.7"
+        );
+        assert_eq!(client_ip_with(&["2001:db8::1"], true), "2001:db8::1");
+    }
+
+    #[test]
+    fn ignores_spoofed_leading_entries() {
+        assert_eq!(
+            client_ip_with(&["1.2.3.4, 5.6.7.8,203.0.113.7"], true),
+            "203.0.113.7"
+        );
+        assert_eq!(
+            client_ip_with(&["1.2.3.4", "203.0.113.7"], true),
+            "203.0.113.7"
+        );
+    }
+
+    #[test]
+    fn malformed_values_fall_back_to_peer() {
+        assert_eq!(client_ip_with(&["203.0.113.7, garbage"], true), "10.0.0.1");
+        assert_eq!(client_ip_with(&["203.0.113.7,"], true), "10.0.0.1");
+        assert_eq!(client_ip_with(&[""], true), "10.0.0.1");
+        assert_eq!(client_ip_with(&["203.0.113.7:80"], true), "10.0.0.1");
+    }
+
+    #[test]
+    fn untrusted_headers_are_ignored() {
+        assert_eq!(client_ip_with(&["203.0.113.7"], false), "10.0.0.1");
+    }
+
+    #[test]
+    fn mapped_ipv4_peers_are_canonical() {
+        let peer = "[::ffff:192.0.2.1]:80".parse().unwrap();
+        assert_eq!(
+            client_ip(&HeaderMap::new(), peer, false).to_string(),
+            "192.0.2.1"
+        );
+    }
+
+    #[test]
+    fn base_url_from_forwarded_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static("internal:3000"));
+        assert_eq!(base_url(&headers, true), "http://internal:3000");
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static("https"));
+        headers.insert(
+            X_FORWARDED_HOST,
+            HeaderValue::from_static("evil.example, aur.example.com"),
+        );
+        assert_eq!(base_url(&headers, true), "https://aur.example.com");
+        assert_eq!(base_url(&headers, false), "http://internal:3000");
+    }
+}
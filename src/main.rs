@@ -1,22 +1,37 @@
 use anyhow::{anyhow, Result};
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
+use itertools::Itertools;
 use std::path::PathBuf;
 use std::process::Command;
-use tracing::{debug, info};
+use std::time::{Duration, Instant};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, info, warn};
 
 mod app_state;
 mod aur_fetcher;
+mod commit_cache;
 mod config;
 mod database;
+mod forwarded;
+mod metrics;
+mod pkt_line;
+mod rate_limit;
+mod response_cache;
 mod rpc_server;
 mod srcinfo_parse;
+mod sync_status;
 mod syncer;
 mod types;
+mod version;
 
 use app_state::AppState;
 use config::Config;
-use rpc_server::RpcServer;
-use syncer::Syncer;
+use rpc_server::{RpcServer, ServerOptions};
+use syncer::{ProgressFormat, SyncFilter, SyncOptions, Syncer};
+use types::{DatabasePackageDetails, DatabasePackageInfo, MatchMode, Relation, SearchType};
+
+/// Upper bound of `daemon --interval`, a week.
+const MAX_SYNC_INTERVAL_MINUTES: u64 = 7 * 24 * 60;
 
 #[derive(Parser)]
 #[command(name = "aur-mirror-meta")]
@@ -26,6 +41,10 @@ struct Cli {
     #[arg(long)]
     config: Option<PathBuf>,
 
+    /// Path to database file, overriding the configured one
+    #[arg(long)]
+    db: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -38,30 +57,231 @@ enum Commands {
         token: String,
     },
     /// Sync metadata from AUR GitHub Mirror
-    Sync,
+    Sync(SyncArgs),
+    /// Rebuild all indexes and refresh query planner statistics
+    Reindex,
+    /// Search the local database without starting the server
+    Search {
+        /// Search keyword
+        keyword: String,
+        /// Search field, as in RPC searches (defaults to name-desc)
+        #[arg(long)]
+        by: Option<String>,
+        /// Print the matching packages as JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show everything stored for the given packages
+    Info {
+        /// Package names
+        #[arg(required = true)]
+        packages: Vec<String>,
+        /// Print the packages as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print package, branch and per-table row counts and the last sync time
+    Stats,
     /// Start HTTP RPC server
-    Serve {
-        /// Address to bind to
-        #[arg(long, default_values_t = vec!["[::]:3000".to_string()])]
-        bind: Vec<String>,
+    Serve(ServeArgs),
+    /// Start HTTP RPC server and sync in the background on a schedule
+    Daemon {
+        #[command(flatten)]
+        serve: ServeArgs,
+        #[command(flatten)]
+        sync: SyncArgs,
+        /// Minutes between the start of one sync and the next; the first one starts right away
+        #[arg(long, value_name = "MINUTES", default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..=MAX_SYNC_INTERVAL_MINUTES))]
+        interval: u64,
     },
 }
 
+#[derive(Args)]
+struct SyncArgs {
+    /// Only sync the given branches (comma separated)
+    #[arg(long, value_delimiter = ',')]
+    include_branches: Vec<String>,
+    /// Only index packages built for the given architecture (or `any`)
+    #[arg(long)]
+    arch: Option<String>,
+    /// Only write rows that changed instead of rewriting whole branches
+    #[arg(long)]
+    diff_writes: bool,
+    /// Also fetch commit dates to populate `LastModified`
+    #[arg(long)]
+    commit_dates: bool,
+    /// How many times to retry a failed batch before giving up on its branches
+    #[arg(long, default_value_t = 2)]
+    batch_retries: u32,
+    /// Emit machine-readable progress on stdout (logs always go to stderr)
+    #[arg(long, value_enum)]
+    progress: Option<ProgressFormat>,
+    /// Prune recorded sync problems and commit history older than this many days (0 keeps them forever)
+    #[arg(long, value_name = "DAYS", default_value_t = 30)]
+    prune_after: u32,
+    /// Abort on the first batch that fails after retries (batches before it are kept)
+    #[arg(long, overrides_with = "keep_going")]
+    fail_fast: bool,
+    /// Log failed batches and continue with the rest (default)
+    #[arg(long, overrides_with = "fail_fast")]
+    keep_going: bool,
+    /// Stop after this many seconds, keeping the batches written so far
+    #[arg(long, value_name = "SECONDS")]
+    max_runtime: Option<u64>,
+    /// Report changed packages (added, removed, new version or dependencies), not
+    /// just changed branches, in `/changes` and the progress output
+    #[arg(long)]
+    only_changed_packages: bool,
+    /// Log the commits and GraphQL query of each batch before sending it
+    #[arg(long)]
+    dump_queries: bool,
+    /// Number of GraphQL batches fetched at the same time (overrides `sync_concurrency`)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    concurrency: Option<u32>,
+}
+
+#[derive(Args)]
+struct ServeArgs {
+    /// Address to bind to, or `unix:<path>` for a Unix socket
+    #[arg(long, default_values_t = vec!["[::]:3000".to_string()])]
+    bind: Vec<String>,
+    /// Log request counts, cache hit rate and package count every SECS seconds
+    #[arg(long, value_name = "SECS")]
+    stats_interval: Option<u64>,
+    /// Do not serve the git smart-HTTP proxy (`/<branch>/info/refs`, `git-upload-pack`)
+    #[arg(long)]
+    no_git: bool,
+    /// Do not serve `/cgit/aur.git/snapshot/*`
+    #[arg(long)]
+    no_snapshot: bool,
+    /// Reject RPC requests with a JSONP `callback`
+    #[arg(long)]
+    no_jsonp: bool,
+    /// Redirect snapshots with 308 Permanent Redirect (overrides `snapshot_redirect_permanent`)
+    #[arg(long)]
+    snapshot_redirect_permanent: bool,
+}
+
+impl SyncArgs {
+    fn sync_options(self, config: &Config) -> Result<SyncOptions> {
+        let SyncArgs {
+            include_branches,
+            arch,
+            diff_writes,
+            commit_dates,
+            batch_retries,
+            progress,
+            prune_after,
+            fail_fast,
+            keep_going: _,
+            max_runtime,
+            only_changed_packages,
+            dump_queries,
+            concurrency,
+        } = self;
+        Ok(SyncOptions {
+            filter: SyncFilter {
+                include_branches: (!include_branches.is_empty())
+                    .then(|| include_branches.into_iter().collect()),
+                arch,
+            },
+            diff_writes,
+            commit_dates,
+            batch_retries,
+            version_placeholders: config.version_placeholders(),
+            progress,
+            branch_list_source: config.branch_list_source(),
+            prune_after_days: prune_after,
+            commit_history: config.commit_history(),
+            max_value_length: config.max_value_length(),
+            fail_fast,
+            max_runtime: max_runtime.map(Duration::from_secs),
+            track_package_changes: only_changed_packages,
+            dump_queries,
+            concurrency: concurrency
+                .map(|n| n as usize)
+                .unwrap_or_else(|| config.sync_concurrency()),
+            empty_srcinfo: config.empty_srcinfo(),
+            request_max_attempts: config.request_max_attempts(),
+            http_timeouts: config.http_timeouts(),
+            upstream_repo: config.upstream_repo()?,
+            package_meta_url: config.package_meta_url(),
+            failed_branch_max_retries: config.failed_branch_max_retries(),
+        })
+    }
+}
+
+impl ServeArgs {
+    fn server_options(&self, config: &Config) -> Result<ServerOptions> {
+        let ServeArgs {
+            bind: _,
+            stats_interval,
+            no_git,
+            no_snapshot,
+            no_jsonp,
+            snapshot_redirect_permanent,
+        } = *self;
+        Ok(ServerOptions {
+            allowed_upstream_hosts: config.allowed_upstream_hosts(),
+            debug_endpoints: config.debug_endpoints(),
+            metrics_endpoint: config.metrics_endpoint(),
+            admin_token: config.admin_token(),
+            suggest_limit: config.suggest_limit(),
+            info_case_insensitive: config.info_case_insensitive(),
+            stats_interval: stats_interval
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs),
+            rpc_post_body_limit: config.rpc_post_body_limit(),
+            rpc_post_timeout: config.rpc_post_timeout(),
+            shutdown_timeout: config.shutdown_timeout(),
+            response_cache_size: config.response_cache_size(),
+            response_cache_ttl: config.response_cache_ttl(),
+            http_timeouts: config.http_timeouts(),
+            upstream_repo: config.upstream_repo()?,
+            trust_forwarded_headers: config.trust_forwarded_headers(),
+            warm_commit_cache: config.warm_commit_cache(),
+            aur_web_url: config.aur_web_url(),
+            snapshot_mode: config.snapshot_mode(),
+            snapshot_redirect_permanent: snapshot_redirect_permanent
+                || config.snapshot_redirect_permanent(),
+            rate_limit_per_minute: config.rate_limit_per_minute(),
+            rate_limit_exempt: config.rate_limit_exempt()?,
+            git_routes: !no_git,
+            snapshot_routes: !no_snapshot,
+            jsonp: !no_jsonp,
+        })
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    // Keep stdout free for machine-readable output
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
 
     let cli = Cli::parse();
 
-    let config = Config::new(cli.config);
+    let mut config = Config::new(cli.config);
     if let Some(config_path) = config.config_path() {
         info!("Config file: {}", config_path.display());
     }
 
-    let db_path = config
-        .db_path()
+    // `--db` overrides both configured paths
+    let query_db_path = match cli.command {
+        Commands::Serve { .. } if cli.db.is_none() => config.query_db_path(),
+        _ => None,
+    };
+    let db_path = cli
+        .db
+        .or_else(|| query_db_path.clone())
+        .or_else(|| config.db_path())
         .ok_or(anyhow!("Database path is not configured."))?;
-    info!("Database file: {}", db_path);
+    if query_db_path.is_some() {
+        info!("Query database file (read-only): {}", db_path);
+    } else {
+        info!("Database file: {}", db_path);
+    }
 
     let github_token = config.github_token().or_else(|| {
         debug!("GitHub token is not set. Try `gh auth token`.");
@@ -81,7 +301,12 @@ async fn main() -> Result<()> {
             })
     });
 
-    let app_state = AppState::new(&db_path, github_token).await?;
+    let pool_size = config.db_pool_size();
+    let app_state = if query_db_path.is_some() {
+        AppState::read_only(&db_path, pool_size, github_token).await?
+    } else {
+        AppState::new(&db_path, pool_size, github_token).await?
+    };
 
     match cli.command {
         Commands::Login { token } => {
@@ -90,15 +315,211 @@ async fn main() -> Result<()> {
             })?;
             info!("GitHub token saved to config file.");
         }
-        Commands::Sync => {
-            let syncer = Syncer::new(app_state);
+        Commands::Sync(args) => {
+            let syncer = Syncer::new(app_state, args.sync_options(&config)?);
             syncer.sync().await?;
         }
-        Commands::Serve { bind } => {
-            let server = RpcServer::new(app_state);
-            server.run(bind.iter()).await?;
+        Commands::Reindex => {
+            let start = Instant::now();
+            app_state.db.reindex().await?;
+            info!("Reindexed database in {:.2?}", start.elapsed());
+        }
+        Commands::Search { keyword, by, json } => {
+            let by = by.as_deref().unwrap_or("name-desc");
+            let search_type = SearchType::from_str(by).ok_or_else(|| {
+                let valid = SearchType::ALL.map(SearchType::name).join(", ");
+                anyhow!("Invalid search field '{}', valid values: {}", by, valid)
+            })?;
+            let packages = app_state
+                .db
+                .search_packages(search_type, &keyword, MatchMode::Exact, None, None)
+                .await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&packages)?);
+            } else {
+                print_package_table(&packages);
+            }
+        }
+        Commands::Stats => {
+            let db = &app_state.db;
+            let last_sync = db
+                .get_last_sync_at()
+                .await?
+                .and_then(|ts| chrono::DateTime::from_timestamp(ts, 0))
+                .map_or("never".to_string(), |time| time.to_rfc3339());
+            let upstream_branches = db
+                .get_branch_list_count()
+                .await?
+                .map_or("unknown".to_string(), |count| count.to_string());
+            println!("Branches:          {}", db.count_branches().await?);
+            println!("Upstream branches: {}", upstream_branches);
+            println!("Packages:          {}", db.count_packages().await?);
+            println!("Last sync:         {}", last_sync);
+            println!();
+            let counts = db.table_counts().await?;
+            let width = counts.keys().map(String::len).max().unwrap_or_default();
+            for (table, count) in counts.iter().sorted() {
+                println!("{:<width$}  {:>10}", table, count);
+            }
+        }
+        Commands::Info { packages, json } => {
+            let details = app_state
+                .db
+                .get_package_details(&packages, &Relation::ALL, false, None, None)
+                .await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&details)?);
+            } else {
+                for (i, pkg) in details.iter().enumerate() {
+                    if i > 0 {
+                        println!();
+                    }
+                    print_package_details(pkg);
+                }
+            }
+            for name in &packages {
+                if !details.iter().any(|pkg| &pkg.info.pkg_name == name) {
+                    warn!("Package '{}' not found", name);
+                }
+            }
+            if details.is_empty() {
+                return Err(anyhow!("None of the requested packages were found"));
+            }
+        }
+        Commands::Serve(args) => {
+            let server = RpcServer::new(app_state, args.server_options(&config)?);
+            server.run(args.bind.iter()).await?;
+        }
+        Commands::Daemon {
+            serve,
+            sync,
+            interval,
+        } => {
+            let syncer = Syncer::new(app_state.clone(), sync.sync_options(&config)?);
+            let server = RpcServer::new(app_state, serve.server_options(&config)?);
+            let scheduled_syncs = tokio::spawn(run_scheduled_syncs(
+                syncer,
+                Duration::from_secs(interval * 60),
+            ));
+            // Dropping a running sync rolls back the batch it is writing, so it is stopped
+            // as soon as the signal arrives instead of after in-flight requests drain
+            let stop_syncs = scheduled_syncs.abort_handle();
+            let shutdown = async move {
+                rpc_server::shutdown_signal().await;
+                stop_syncs.abort();
+            };
+            let result = server.run_until(serve.bind.iter(), shutdown).await;
+            scheduled_syncs.abort();
+            result?;
         }
     }
 
     Ok(())
 }
+
+/// Syncs right away and then every `interval`. A sync that overruns the interval
+/// delays the next one instead of overlapping it.
+async fn run_scheduled_syncs(syncer: Syncer, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let start = Instant::now();
+        match syncer.sync().await {
+            Ok(()) => info!("Scheduled sync finished in {:.2?}", start.elapsed()),
+            Err(e) => error!(
+                "Scheduled sync failed after {:.2?}: {:#}",
+                start.elapsed(),
+                e
+            ),
+        }
+    }
+}
+
+/// Prints name, version and description columns to stdout.
+fn print_package_table(packages: &[DatabasePackageInfo]) {
+    const HEADER: [&str; 3] = ["NAME", "VERSION", "DESCRIPTION"];
+    let name_width = packages
+        .iter()
+        .map(|p| p.pkg_name.len())
+        .chain([HEADER[0].len()])
+        .max()
+        .unwrap_or_default();
+    let version_width = packages
+        .iter()
+        .map(|p| p.version.len())
+        .chain([HEADER[1].len()])
+        .max()
+        .unwrap_or_default();
+    let rows = packages.iter().map(|p| {
+        [
+            p.pkg_name.as_str(),
+            p.version.as_str(),
+            p.pkg_desc.as_deref().unwrap_or_default(),
+        ]
+    });
+    for [name, version, description] in std::iter::once(HEADER).chain(rows) {
+        let line = format!("{name:<name_width$}  {version:<version_width$}  {description}");
+        println!("{}", line.trim_end());
+    }
+}
+
+/// Prints one `key : value` line per field, relations with all values on one line.
+fn print_package_details(pkg: &DatabasePackageDetails) {
+    let info = &pkg.info;
+    let last_modified = info
+        .last_modified
+        .and_then(|ts| chrono::DateTime::from_timestamp(ts, 0))
+        .map(|time| time.to_rfc3339());
+    let fields = [
+        ("pkgname", Some(info.pkg_name.clone())),
+        ("pkgbase", Some(info.branch.clone())),
+        ("version", Some(info.version.clone())),
+        ("pkgdesc", info.pkg_desc.clone()),
+        ("url", info.url.clone()),
+        ("commit", Some(info.commit_id.clone())),
+        ("lastmodified", last_modified),
+    ]
+    .into_iter()
+    .chain(Relation::ALL.map(|relation| {
+        let values = pkg.relation(relation);
+        (
+            relation.name(),
+            (!values.is_empty()).then(|| values.join("  ")),
+        )
+    }));
+    for (key, value) in fields {
+        println!("{:<13}: {}", key, value.as_deref().unwrap_or("None"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn daemon_interval(args: &[&str]) -> Result<u64, clap::Error> {
+        let cli = Cli::try_parse_from(["aur-mirror-meta", "daemon"].iter().chain(args))?;
+        match cli.command {
+            Commands::Daemon { interval, .. } => Ok(interval),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn daemon_interval_is_bounded() {
+        assert_eq!(daemon_interval(&[]).unwrap(), 30);
+        assert_eq!(daemon_interval(&["--interval", "1"]).unwrap(), 1);
+        let max = MAX_SYNC_INTERVAL_MINUTES.to_string();
+        assert_eq!(
+            daemon_interval(&["--interval", &max]).unwrap(),
+            MAX_SYNC_INTERVAL_MINUTES
+        );
+        let above_max = (MAX_SYNC_INTERVAL_MINUTES + 1).to_string();
+        for interval in ["0", &above_max, "18446744073709551615"] {
+            assert!(
+                daemon_interval(&["--interval", interval]).is_err(),
+                "{interval}"
+            );
+        }
+    }
+}
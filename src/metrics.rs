@@ -0,0 +1,320 @@
+//! Server metrics in the Prometheus text and OpenMetrics exposition formats.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+pub const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricsFormat {
+    Prometheus,
+    OpenMetrics,
+}
+
+impl MetricsFormat {
+    /// OpenMetrics if the `Accept` header asks for it, Prometheus text otherwise.
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        let wants_openmetrics = accept.is_some_and(|accept| {
+            accept.split(',').any(|media_type| {
+                media_type
+                    .trim()
+                    .starts_with("application/openmetrics-text")
+            })
+        });
+        if wants_openmetrics {
+            Self::OpenMetrics
+        } else {
+            Self::Prometheus
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Prometheus => PROMETHEUS_CONTENT_TYPE,
+            Self::OpenMetrics => OPENMETRICS_CONTENT_TYPE,
+        }
+    }
+}
+
+/// Upper bounds of the latency histogram buckets in seconds, Prometheus' defaults.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    /// Observations per bucket of [`LATENCY_BUCKETS`], not cumulative.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|&bound| value <= bound) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+#[derive(Clone)]
+pub struct Metrics {
+    start_time: f64,
+    /// Request counts by matched route and status code.
+    requests: Arc<Mutex<BTreeMap<(String, u16), u64>>>,
+    commit_cache_hits: Arc<AtomicU64>,
+    commit_cache_misses: Arc<AtomicU64>,
+    /// RPC requests by their `type`.
+    rpc_requests: Arc<Mutex<BTreeMap<String, u64>>>,
+    /// `search` and `info` handling times in seconds.
+    rpc_latencies: Arc<Mutex<BTreeMap<&'static str, Histogram>>>,
+    /// Response bytes passed through from the upstream `git-upload-pack`.
+    git_upload_pack_bytes: Arc<AtomicU64>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            start_time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            requests: Default::default(),
+            commit_cache_hits: Default::default(),
+            commit_cache_misses: Default::default(),
+            rpc_requests: Default::default(),
+            rpc_latencies: Default::default(),
+            git_upload_pack_bytes: Default::default(),
+        }
+    }
+
+    pub fn record_request(&self, route: &str, status: u16) {
+        let mut requests = self.requests.lock().unwrap();
+        *requests.entry((route.to_string(), status)).or_default() += 1;
+    }
+
+    pub fn record_commit_cache_lookup(&self, hit: bool) {
+        let counter = if hit {
+            &self.commit_cache_hits
+        } else {
+            &self.commit_cache_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rpc_request(&self, request_type: &str) {
+        let mut rpc_requests = self.rpc_requests.lock().unwrap();
+        *rpc_requests.entry(request_type.to_string()).or_default() += 1;
+    }
+
+    pub fn record_rpc_latency(&self, request_type: &'static str, elapsed: Duration) {
+        let mut rpc_latencies = self.rpc_latencies.lock().unwrap();
+        rpc_latencies
+            .entry(request_type)
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn add_git_upload_pack_bytes(&self, bytes: usize) {
+        self.git_upload_pack_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Requests served since the start, over all routes.
+    pub fn requests_total(&self) -> u64 {
+        self.requests.lock().unwrap().values().sum()
+    }
+
+    /// Commit cache hits and misses since the start.
+    pub fn commit_cache_lookups(&self) -> (u64, u64) {
+        (
+            self.commit_cache_hits.load(Ordering::Relaxed),
+            self.commit_cache_misses.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Renders all metrics, `commit_cache_entries` and `last_sync_duration` are
+    /// sampled by the caller.
+    pub fn render(
+        &self,
+        format: MetricsFormat,
+        commit_cache_entries: usize,
+        last_sync_duration: Option<Duration>,
+    ) -> String {
+        let mut out = String::new();
+        let openmetrics = format == MetricsFormat::OpenMetrics;
+
+        // OpenMetrics names counter families without the `_total` suffix
+        let counter_family = |name: &'static str| {
+            if openmetrics {
+                name.strip_suffix("_total").unwrap_or(name)
+            } else {
+                name
+            }
+        };
+
+        let family = counter_family("amm_http_requests_total");
+        writeln!(out, "# HELP {} HTTP requests by route and status.", family).unwrap();
+        writeln!(out, "# TYPE {} counter", family).unwrap();
+        for ((route, status), count) in self.requests.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "amm_http_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                escape_label(route),
+                status,
+                count
+            )
+            .unwrap();
+        }
+
+        let family = counter_family("amm_rpc_requests_total");
+        writeln!(out, "# HELP {} RPC requests by type.", family).unwrap();
+        writeln!(out, "# TYPE {} counter", family).unwrap();
+        for (request_type, count) in self.rpc_requests.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "amm_rpc_requests_total{{type=\"{}\"}} {}",
+                escape_label(request_type),
+                count
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            out,
+            "# HELP amm_rpc_request_duration_seconds Time to answer search and info requests."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE amm_rpc_request_duration_seconds histogram").unwrap();
+        if openmetrics {
+            writeln!(out, "# UNIT amm_rpc_request_duration_seconds seconds").unwrap();
+        }
+        for (request_type, histogram) in self.rpc_latencies.lock().unwrap().iter() {
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                writeln!(
+                    out,
+                    "amm_rpc_request_duration_seconds_bucket{{type=\"{}\",le=\"{}\"}} {}",
+                    request_type, bound, cumulative
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                "amm_rpc_request_duration_seconds_bucket{{type=\"{}\",le=\"+Inf\"}} {}",
+                request_type, histogram.count
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "amm_rpc_request_duration_seconds_sum{{type=\"{}\"}} {}",
+                request_type, histogram.sum
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "amm_rpc_request_duration_seconds_count{{type=\"{}\"}} {}",
+                request_type, histogram.count
+            )
+            .unwrap();
+        }
+
+        let family = counter_family("amm_git_upload_pack_bytes_total");
+        writeln!(
+            out,
+            "# HELP {} Bytes proxied from the upstream git-upload-pack.",
+            family
+        )
+        .unwrap();
+        writeln!(out, "# TYPE {} counter", family).unwrap();
+        if openmetrics {
+            writeln!(out, "# UNIT {} bytes", family).unwrap();
+        }
+        writeln!(
+            out,
+            "amm_git_upload_pack_bytes_total {}",
+            self.git_upload_pack_bytes.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        let family = counter_family("amm_commit_cache_lookups_total");
+        let (hits, misses) = self.commit_cache_lookups();
+        writeln!(out, "# HELP {} Commit cache lookups by result.", family).unwrap();
+        writeln!(out, "# TYPE {} counter", family).unwrap();
+        writeln!(
+            out,
+            "amm_commit_cache_lookups_total{{result=\"hit\"}} {}",
+            hits
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "amm_commit_cache_lookups_total{{result=\"miss\"}} {}",
+            misses
+        )
+        .unwrap();
+
+        writeln!(
+            out,
+            "# HELP amm_commit_cache_entries Branches in the commit cache."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE amm_commit_cache_entries gauge").unwrap();
+        writeln!(out, "amm_commit_cache_entries {}", commit_cache_entries).unwrap();
+
+        writeln!(
+            out,
+            "# HELP amm_start_time_seconds Server start time since the epoch."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE amm_start_time_seconds gauge").unwrap();
+        if openmetrics {
+            writeln!(out, "# UNIT amm_start_time_seconds seconds").unwrap();
+        }
+        writeln!(out, "amm_start_time_seconds {}", self.start_time).unwrap();
+
+        if let Some(duration) = last_sync_duration {
+            writeln!(
+                out,
+                "# HELP amm_last_sync_duration_seconds Duration of the last successful sync."
+            )
+            .unwrap();
+            writeln!(out, "# TYPE amm_last_sync_duration_seconds gauge").unwrap();
+            if openmetrics {
+                writeln!(out, "# UNIT amm_last_sync_duration_seconds seconds").unwrap();
+            }
+            writeln!(
+                out,
+                "amm_last_sync_duration_seconds {}",
+                duration.as_secs_f64()
+            )
+            .unwrap();
+        }
+
+        if openmetrics {
+            out.push_str("# EOF\n");
+        }
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
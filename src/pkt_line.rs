@@ -0,0 +1,95 @@
+//! git's pkt-line framing, used by the smart HTTP protocol.
+
+use anyhow::{anyhow, Result};
+
+/// Ends a section of a pkt-line stream.
+pub const FLUSH: &str = "0000";
+
+/// Frames `data` as a single data packet, prefixed with its length in 4 hex digits
+/// (which include the prefix itself).
+pub fn encode(data: &str) -> String {
+    format!("{:04x}{}", data.len() + 4, data)
+}
+
+/// Splits a pkt-line stream into the payloads of its data packets, without their
+/// trailing newline. Flush (`0000`), delimiter (`0001`) and response end (`0002`)
+/// packets carry no data and are dropped.
+pub fn decode(mut data: &[u8]) -> Result<Vec<&[u8]>> {
+    let mut packets = Vec::new();
+    while !data.is_empty() {
+        let len = data
+            .get(..4)
+            .filter(|prefix| prefix.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|prefix| std::str::from_utf8(prefix).ok())
+            .and_then(|prefix| usize::from_str_radix(prefix, 16).ok())
+            .filter(|&len| len != 3)
+            .ok_or_else(|| {
+                // Only the prefix, the rest may be a large binary request body
+                let prefix = &data[..data.len().min(4)];
+                anyhow!("Invalid pkt-line length: {}", prefix.escape_ascii())
+            })?;
+        if len < 4 {
+            data = &data[4..];
+            continue;
+        }
+        let packet = data
+            .get(4..len)
+            .ok_or_else(|| anyhow!("Truncated pkt-line of length {}", len))?;
+        packets.push(packet.strip_suffix(b"\n").unwrap_or(packet));
+        data = &data[len..];
+    }
+    Ok(packets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_prefixes_length() {
+        assert_eq!(
+            encode("# service=git-upload-pack\n"),
+            "001e# service=git-upload-pack\n"
+        );
+        assert_eq!(encode(""), "0004");
+    }
+
+    #[test]
+    fn decode_data_and_special_packets() {
+        let stream = format!(
+            "{}{}0001{}{}0002",
+            encode("want abc\n"),
+            FLUSH,
+            encode("done"),
+            FLUSH
+        );
+        assert_eq!(
+            decode(stream.as_bytes()).unwrap(),
+            [b"want abc".as_slice(), b"done".as_slice()]
+        );
+        assert!(decode(b"").unwrap().is_empty());
+        assert_eq!(decode(b"0004").unwrap(), [b"".as_slice()]);
+    }
+
+    #[test]
+    fn decode_rejects_bad_lengths() {
+        for stream in [&b"zzzz"[..], b"+004", b"00", b"0003", b"00g4data"] {
+            assert!(decode(stream).is_err(), "{}", stream.escape_ascii());
+        }
+    }
+
+    #[test]
+    fn decode_rejects_truncated_packets() {
+        let error = decode(b"000awant").unwrap_err();
+        assert_eq!(error.to_string(), "Truncated pkt-line of length 10");
+        assert!(decode(b"0008want0009do").is_err());
+    }
+
+    #[test]
+    fn decode_errors_only_show_the_prefix() {
+        let mut stream = b"x\x00yz".to_vec();
+        stream.extend(vec![b'a'; 100_000]);
+        let error = decode(&stream).unwrap_err().to_string();
+        assert_eq!(error, "Invalid pkt-line length: x\\x00yz");
+    }
+}
@@ -0,0 +1,267 @@
+//! Per-client token bucket rate limiting for the public endpoints.
+
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Buckets that refilled completely are dropped once this many clients are tracked,
+/// and beyond that an arbitrary one is evicted for each new client.
+const MAX_TRACKED_CLIENTS: usize = 65536;
+
+/// Minimum time between sweeps of a full bucket table.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// An address or CIDR block, e.g. `10.0.0.0/8` or `::1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_eq(a: &[u8], b: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = usize::from(prefix_len / 8);
+    let rest_bits = prefix_len % 8;
+    if a[..full_bytes] != b[..full_bytes] {
+        return false;
+    }
+    rest_bits == 0 || {
+        let mask = 0xffu8 << (8 - rest_bits);
+        a[full_bytes] & mask == b[full_bytes] & mask
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| anyhow!("Invalid IP address in '{}'", s))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse()
+                .ok()
+                .filter(|&len| len <= max_len)
+                .ok_or_else(|| anyhow!("Invalid prefix length in '{}'", s))?,
+            None => max_len,
+        };
+        Ok(Self {
+            addr: addr.to_canonical(),
+            prefix_len,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    /// Bucket size, also the number of tokens refilled per minute.
+    capacity: f64,
+    exempt: Arc<[IpRange]>,
+    max_clients: usize,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+#[derive(Default)]
+struct Buckets {
+    tokens: HashMap<IpAddr, (f64, Instant)>,
+    /// Full buckets are only swept this often, so a full table does not cost a scan per
+    /// request.
+    next_sweep: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_minute: u32, exempt: Vec<IpRange>) -> Self {
+        Self::with_max_clients(requests_per_minute, exempt, MAX_TRACKED_CLIENTS)
+    }
+
+    fn with_max_clients(requests_per_minute: u32, exempt: Vec<IpRange>, max: usize) -> Self {
+        Self {
+            capacity: f64::from(requests_per_minute),
+            exempt: exempt.into(),
+            max_clients: max,
+            buckets: Default::default(),
+        }
+    }
+
+    /// Takes a token for `ip`, or returns how long until one is available.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        if self.exempt.iter().any(|range| range.contains(ip)) {
+            return Ok(());
+        }
+        let key = client_key(ip);
+        let refill_per_sec = self.capacity / 60.0;
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.tokens.len() >= self.max_clients && !buckets.tokens.contains_key(&key) {
+            buckets.make_room(now, self.max_clients);
+        }
+        let (tokens, updated_at) = buckets.tokens.entry(key).or_insert((self.capacity, now));
+        *tokens = (*tokens + now.duration_since(*updated_at).as_secs_f64() * refill_per_sec)
+            .min(self.capacity);
+        *updated_at = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - *tokens) / refill_per_sec))
+        }
+    }
+}
+
+impl Buckets {
+    /// Drops buckets that refilled completely, at most once per [`SWEEP_INTERVAL`], and
+    /// evicts an arbitrary client if the table is still full.
+    fn make_room(&mut self, now: Instant, max_clients: usize) {
+        if self.next_sweep.is_none_or(|next_sweep| now >= next_sweep) {
+            let full_after = Duration::from_secs(60);
+            self.tokens
+                .retain(|_, (_, updated_at)| now.duration_since(*updated_at) < full_after);
+            self.next_sweep = Some(now + SWEEP_INTERVAL);
+        }
+        if self.tokens.len() >= max_clients {
+            if let Some(&evicted) = self.tokens.keys().next() {
+                self.tokens.remove(&evicted);
+            }
+        }
+    }
+}
+
+/// The bucket an address counts against. IPv6 clients usually get a whole /64, so
+/// they are limited per /64 rather than per address.
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6((u128::from(ip) & !u128::from(u64::MAX)).into()),
+        ip => ip,
+    }
+}
+
+/// `Retry-After` is in whole seconds, so round up for the retry to succeed.
+pub fn retry_after_secs(wait: Duration) -> u64 {
+    wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn tokens_refill_over_time() {
+        let limiter = RateLimiter::new(60, Vec::new());
+        let start = Instant::now();
+        for _ in 0..60 {
+            assert!(limiter.check_at(ip("192.0.2.1"), start).is_ok());
+        }
+        let wait = limiter.check_at(ip("192.0.2.1"), start).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(1));
+        // Other clients have their own bucket
+        assert!(limiter.check_at(ip("192.0.2.2"), start).is_ok());
+
+        let later = start + Duration::from_secs(2);
+        assert!(limiter.check_at(ip("192.0.2.1"), later).is_ok());
+        assert!(limiter.check_at(ip("192.0.2.1"), later).is_ok());
+        assert!(limiter.check_at(ip("192.0.2.1"), later).is_err());
+        // Never refilled beyond the capacity
+        let much_later = later + Duration::from_secs(3600);
+        for _ in 0..60 {
+            assert!(limiter.check_at(ip("192.0.2.1"), much_later).is_ok());
+        }
+        assert!(limiter.check_at(ip("192.0.2.1"), much_later).is_err());
+    }
+
+    #[test]
+    fn retry_after_rounds_up() {
+        let limiter = RateLimiter::new(2, Vec::new());
+        let start = Instant::now();
+        assert!(limiter.check_at(ip("192.0.2.1"), start).is_ok());
+        assert!(limiter.check_at(ip("192.0.2.1"), start).is_ok());
+        let wait = limiter
+            .check_at(ip("192.0.2.1"), start + Duration::from_millis(100))
+            .unwrap_err();
+        assert_eq!(retry_after_secs(wait), 30);
+        assert_eq!(retry_after_secs(Duration::from_secs(30)), 30);
+        assert_eq!(retry_after_secs(Duration::from_millis(1)), 1);
+    }
+
+    #[test]
+    fn exempt_ranges_are_not_limited() {
+        let exempt = ["10.0.0.0/8", "2001:db8::/32", "192.0.2.1"]
+            .map(|s| s.parse().unwrap())
+            .to_vec();
+        let limiter = RateLimiter::new(1, exempt);
+        let start = Instant::now();
+        for addr in ["10.1.2.3", "2001:db8::1", "::ffff:10.0.0.1", "192.0.2.1"] {
+            for _ in 0..5 {
+                assert!(limiter.check_at(ip(addr), start).is_ok(), "{}", addr);
+            }
+        }
+        assert!(limiter.check_at(ip("192.0.2.2"), start).is_ok());
+        assert!(limiter.check_at(ip("192.0.2.2"), start).is_err());
+    }
+
+    #[test]
+    fn ip_range_parsing() {
+        let range: IpRange = "192.168.0.0/17".parse().unwrap();
+        assert!(range.contains(ip("192.168.127.255")));
+        assert!(!range.contains(ip("192.168.128.0")));
+        assert!(!range.contains(ip("::1")));
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("::/129".parse::<IpRange>().is_err());
+        assert!("10.0.0/8".parse::<IpRange>().is_err());
+        assert!("10.0.0.0/x".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn ipv6_clients_share_their_64() {
+        let limiter = RateLimiter::new(1, Vec::new());
+        let start = Instant::now();
+        assert!(limiter.check_at(ip("2001:db8:1:2::1"), start).is_ok());
+        assert!(limiter.check_at(ip("2001:db8:1:2::ffff"), start).is_err());
+        assert!(limiter.check_at(ip("2001:db8:1:3::1"), start).is_ok());
+        // Mapped addresses count as their IPv4 address
+        assert!(limiter.check_at(ip("192.0.2.1"), start).is_ok());
+        assert!(limiter.check_at(ip("::ffff:192.0.2.1"), start).is_err());
+    }
+
+    #[test]
+    fn tracked_clients_are_capped() {
+        let limiter = RateLimiter::with_max_clients(1, Vec::new(), 4);
+        let start = Instant::now();
+        for i in 0..100u8 {
+            let _ = limiter.check_at(IpAddr::from([192, 0, 2, i]), start);
+            assert!(limiter.buckets.lock().unwrap().tokens.len() <= 4);
+        }
+        // Full buckets are swept once the interval passed
+        let later = start + SWEEP_INTERVAL + Duration::from_secs(60);
+        assert!(limiter.check_at(ip("198.51.100.1"), later).is_ok());
+        assert_eq!(limiter.buckets.lock().unwrap().tokens.len(), 1);
+    }
+}
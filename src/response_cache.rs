@@ -0,0 +1,206 @@
+//! In-memory LRU cache of serialized `info` and `search` responses.
+
+use axum::body::Bytes;
+use axum::http::HeaderValue;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Total size of the cached bodies, the least recently used entries are evicted
+/// beyond it. Bodies over a sixteenth of it, e.g. of searches with thousands of
+/// results, are not cached, so a few of them cannot evict everything else.
+const MAX_TOTAL_BYTES: usize = 64 << 20;
+
+/// A response body with its content type.
+#[derive(Clone)]
+pub struct CachedResponse {
+    pub content_type: HeaderValue,
+    pub body: Bytes,
+    /// Whether the body is an RPC error.
+    pub is_error: bool,
+}
+
+struct Entry {
+    response: CachedResponse,
+    cached_at: Instant,
+    /// `SyncStatus::completed_syncs` when the entry was added.
+    sync_generation: u64,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<String, Entry>,
+    /// Keys by their last use, oldest first.
+    by_use: BTreeMap<u64, String>,
+    clock: u64,
+    /// Sum of the body sizes.
+    bytes: usize,
+}
+
+impl Entries {
+    fn touch(&mut self, key: &str) {
+        self.clock += 1;
+        if let Some(entry) = self.by_key.get_mut(key) {
+            self.by_use.remove(&entry.last_used);
+            entry.last_used = self.clock;
+            self.by_use.insert(self.clock, key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.by_key.remove(key) {
+            self.by_use.remove(&entry.last_used);
+            self.bytes -= entry.response.body.len();
+        }
+    }
+}
+
+/// Disabled when its capacity is 0.
+#[derive(Clone)]
+pub struct ResponseCache {
+    entries: Arc<Mutex<Entries>>,
+    capacity: usize,
+    max_bytes: usize,
+    /// Entries are dropped after this long, so changes written by a separate sync
+    /// process are picked up.
+    ttl: Duration,
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self::with_max_bytes(capacity, ttl, MAX_TOTAL_BYTES)
+    }
+
+    fn with_max_bytes(capacity: usize, ttl: Duration, max_bytes: usize) -> Self {
+        Self {
+            entries: Default::default(),
+            capacity,
+            max_bytes,
+            ttl,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// The response cached under `key`, unless it expired or a sync finished since.
+    pub fn get(&self, key: &str, sync_generation: u64) -> Option<CachedResponse> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.by_key.get(key)?;
+        if entry.cached_at.elapsed() >= self.ttl || entry.sync_generation != sync_generation {
+            entries.remove(key);
+            return None;
+        }
+        let response = entry.response.clone();
+        entries.touch(key);
+        Some(response)
+    }
+
+    /// Adds a response, evicting the least recently used ones while the cache is full.
+    pub fn insert(&self, key: String, response: CachedResponse, sync_generation: u64) {
+        let size = response.body.len();
+        if !self.is_enabled() || size > self.max_bytes / 16 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(&key);
+        while entries.by_key.len() >= self.capacity || entries.bytes + size > self.max_bytes {
+            let Some((_, oldest)) = entries.by_use.first_key_value() else {
+                break;
+            };
+            let oldest = oldest.clone();
+            entries.remove(&oldest);
+        }
+        entries.bytes += size;
+        entries.clock += 1;
+        let last_used = entries.clock;
+        entries.by_use.insert(last_used, key.clone());
+        entries.by_key.insert(
+            key,
+            Entry {
+                response,
+                cached_at: Instant::now(),
+                sync_generation,
+                last_used,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(size: usize) -> CachedResponse {
+        CachedResponse {
+            content_type: HeaderValue::from_static("application/json"),
+            body: Bytes::from(vec![b' '; size]),
+            is_error: false,
+        }
+    }
+
+    fn cached_keys(cache: &ResponseCache) -> Vec<String> {
+        let entries = cache.entries.lock().unwrap();
+        entries.by_use.values().cloned().collect()
+    }
+
+    #[test]
+    fn evicts_least_recently_used_by_count() {
+        let cache = ResponseCache::new(2, Duration::from_secs(60));
+        cache.insert("a".to_string(), response(1), 0);
+        cache.insert("b".to_string(), response(1), 0);
+        assert!(cache.get("a", 0).is_some());
+        cache.insert("c".to_string(), response(1), 0);
+        assert_eq!(cached_keys(&cache), ["a", "c"]);
+    }
+
+    #[test]
+    fn evicts_least_recently_used_by_size() {
+        let cache = ResponseCache::with_max_bytes(100, Duration::from_secs(60), 1600);
+        for key in ["a", "b", "c", "d"] {
+            cache.insert(key.to_string(), response(100), 0);
+        }
+        cache.insert("e".to_string(), response(100), 0);
+        assert_eq!(cache.entries.lock().unwrap().bytes, 500);
+        // Replacing an entry does not count it twice
+        cache.insert("e".to_string(), response(100), 0);
+        assert_eq!(cache.entries.lock().unwrap().bytes, 500);
+
+        let cache = ResponseCache::with_max_bytes(100, Duration::from_secs(60), 1600);
+        for i in 0..17 {
+            cache.insert(i.to_string(), response(100), 0);
+        }
+        assert_eq!(cache.entries.lock().unwrap().bytes, 1600);
+        assert_eq!(cached_keys(&cache).first().map(String::as_str), Some("1"));
+    }
+
+    #[test]
+    fn skips_large_bodies() {
+        let cache = ResponseCache::with_max_bytes(100, Duration::from_secs(60), 1600);
+        cache.insert("small".to_string(), response(100), 0);
+        cache.insert("large".to_string(), response(101), 0);
+        assert!(cache.get("large", 0).is_none());
+        assert_eq!(cached_keys(&cache), ["small"]);
+    }
+
+    #[test]
+    fn expires_on_ttl_and_sync() {
+        let cache = ResponseCache::new(10, Duration::from_secs(60));
+        cache.insert("a".to_string(), response(1), 0);
+        assert!(cache.get("a", 1).is_none());
+        assert_eq!(cache.entries.lock().unwrap().bytes, 0);
+
+        let cache = ResponseCache::new(10, Duration::ZERO);
+        cache.insert("a".to_string(), response(1), 0);
+        assert!(cache.get("a", 0).is_none());
+    }
+
+    #[test]
+    fn disabled_cache_stores_nothing() {
+        let cache = ResponseCache::new(0, Duration::from_secs(60));
+        cache.insert("a".to_string(), response(1), 0);
+        assert!(cache.get("a", 0).is_none());
+    }
+}
@@ -1,33 +1,159 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use axum::{
     body::Body,
-    extract::{Path, Query, State},
-    http::{header, HeaderMap, StatusCode},
-    response::{Redirect, Response},
+    extract::{ConnectInfo, MatchedPath, Path, Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Redirect, Response},
     routing::{get, post},
-    Router,
+    Extension, Json, Router,
 };
-use serde::Deserialize;
-use std::collections::HashMap;
-use tower_http::cors::CorsLayer;
-use tracing::{error, info};
+use futures::{StreamExt, TryStreamExt};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tower_http::{
+    compression::{
+        predicate::{Predicate, SizeAbove},
+        CompressionLayer,
+    },
+    cors::CorsLayer,
+    limit::RequestBodyLimitLayer,
+    timeout::TimeoutLayer,
+};
+use tracing::{error, info, warn};
 
-use crate::types::{RpcPackageDetails, RpcPackageInfo};
+use crate::types::{DatabasePackageInfo, PackageChange, RpcPackageDetails, RpcPackageInfo};
+use crate::version::{vercmp, Version};
 use crate::{
     app_state::AppState,
+    aur_fetcher::{HttpTimeouts, UpstreamRepo},
+    commit_cache::{self, CommitCache},
     database::DatabaseOps,
-    types::{RpcResponse, SearchType},
+    forwarded,
+    metrics::{Metrics, MetricsFormat},
+    pkt_line,
+    rate_limit::{self, IpRange, RateLimiter},
+    response_cache::{CachedResponse, ResponseCache},
+    sync_status::SyncStatus,
+    types::{MatchMode, Page, Relation, RpcResponse, SearchType, SortOrder},
 };
 
+const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+const JSONP_CONTENT_TYPE: &str = "application/javascript";
+/// Searches matching more packages fail like on aurweb, except for `/rpc/stream`.
+const MAX_SEARCH_RESULTS: usize = 5000;
+/// Results per page when `offset` is given without `limit`.
+const DEFAULT_PAGE_SIZE: usize = 250;
+/// Larger `limit` values are lowered to this.
+const MAX_PAGE_SIZE: usize = MAX_SEARCH_RESULTS;
+/// Marks a `--bind` value as the path of a Unix socket.
+const UNIX_SOCKET_PREFIX: &str = "unix:";
+/// Stands in for the client address of Unix socket connections, which come from the
+/// same host, e.g. a reverse proxy.
+const UNIX_SOCKET_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+/// Smaller bodies are sent as is, compressing them saves next to nothing.
+const MIN_COMPRESS_SIZE: u16 = 1024;
+/// Streamed NDJSON, proxied archives and packs are not compressed.
+const COMPRESSIBLE_CONTENT_TYPES: &[&str] = &[
+    "application/json",
+    MSGPACK_CONTENT_TYPE,
+    JSONP_CONTENT_TYPE,
+    "text/plain",
+];
+/// Same as aurweb.
+const MAX_CALLBACK_LENGTH: usize = 128;
+/// Lifetime of a permanent redirect to a historical (`?id=`) snapshot, which never changes.
+/// Redirects to a branch's latest snapshot change with its next commit, so they live as
+/// long as a commit cache entry, which may be as stale.
+const HISTORICAL_SNAPSHOT_MAX_AGE: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+/// Advertised with the refs of a virtual repository.
+const GIT_UPLOAD_PACK_CAPABILITIES: &str = "multi_ack thin-pack side-band side-band-64k ofs-delta no-progress include-tag multi_ack_detailed no-done symref=HEAD:refs/heads/master object-format=sha1 agent=git/aur-mirror";
+
 #[derive(Clone)]
 pub struct RpcState {
     db: DatabaseOps,
     client: reqwest::Client,
     github_token: Option<String>,
+    options: Arc<ServerOptions>,
+    commit_cache: CommitCache,
+    response_cache: ResponseCache,
+    rate_limiter: Option<RateLimiter>,
+    metrics: Metrics,
+    sync_status: SyncStatus,
+}
+
+/// How snapshot requests are served.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotMode {
+    /// Redirect clients to the GitHub archive.
+    #[default]
+    Redirect,
+    /// Download the archive from GitHub and pass it through, for clients that
+    /// cannot reach GitHub themselves.
+    Proxy,
+}
+
+/// Server-only settings, derived from the config file and command line.
+pub struct ServerOptions {
+    /// Hosts that snapshot redirects and git proxying may point to.
+    pub allowed_upstream_hosts: Vec<String>,
+    /// Expose `/debug/*` routes showing raw database contents.
+    pub debug_endpoints: bool,
+    /// Expose server metrics at `/metrics`.
+    pub metrics_endpoint: bool,
+    /// Bearer token guarding admin routes, which are open when it is unset.
+    pub admin_token: Option<String>,
+    /// Maximum number of names returned by `type=suggest` and `type=suggest-pkgbase`.
+    pub suggest_limit: u32,
+    /// Match `type=info` names regardless of ASCII case.
+    pub info_case_insensitive: bool,
+    /// Take the client address and public URL from `X-Forwarded-*` headers.
+    pub trust_forwarded_headers: bool,
+    /// Load all branch commits into the cache before accepting requests.
+    pub warm_commit_cache: bool,
+    /// Base URL of the AUR web interface, without a trailing slash.
+    pub aur_web_url: String,
+    pub snapshot_mode: SnapshotMode,
+    /// Redirect snapshots permanently, with a `Cache-Control` lifetime.
+    pub snapshot_redirect_permanent: bool,
+    /// Period of the stats log, disabled when `None`.
+    pub stats_interval: Option<Duration>,
+    /// Maximum size of RPC POST form bodies in bytes.
+    pub rpc_post_body_limit: usize,
+    /// Time limit for handling an RPC POST request.
+    pub rpc_post_timeout: Duration,
+    /// Maximum number of cached `info` and `search` responses, 0 disables the cache.
+    pub response_cache_size: usize,
+    /// How long a cached response is served, unless a sync in this process finishes first.
+    pub response_cache_ttl: Duration,
+    /// How long in-flight requests may take to finish once shutdown begins.
+    pub shutdown_timeout: Duration,
+    /// Timeouts of proxied requests to GitHub.
+    pub http_timeouts: HttpTimeouts,
+    /// Repository snapshots and git requests are served from.
+    pub upstream_repo: UpstreamRepo,
+    /// Requests per minute each client may make to `/rpc` and snapshot routes.
+    pub rate_limit_per_minute: Option<u32>,
+    /// Clients the rate limit does not apply to.
+    pub rate_limit_exempt: Vec<IpRange>,
+    /// Serve the git smart-HTTP proxy routes.
+    pub git_routes: bool,
+    /// Serve `/cgit/aur.git/snapshot/*`.
+    pub snapshot_routes: bool,
+    /// Wrap RPC responses in `callback`, otherwise a callback is rejected.
+    pub jsonp: bool,
 }
 
 pub struct RpcServer {
     app: Router,
+    state: RpcState,
 }
 
 #[derive(Debug, Deserialize)]
@@ -42,6 +168,26 @@ struct RpcQuery {
     #[serde(default, rename = "arg[]")]
     args1: Vec<String>,
     callback: Option<String>,
+    #[serde(flatten)]
+    extensions: RpcExtensions,
+}
+
+/// Path segments of the `/rpc/v5/<type>/...` form of requests.
+#[derive(Debug, Deserialize)]
+struct RpcPath {
+    version: String,
+    #[serde(rename = "type")]
+    request_type: String,
+    #[serde(rename = "by")]
+    search_by: Option<String>,
+    arg: Option<String>,
+}
+
+impl RpcPath {
+    /// The version without its `v` prefix, or `None` if the prefix is missing.
+    fn version(&self) -> Option<String> {
+        self.version.strip_prefix('v').map(str::to_string)
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -55,58 +201,456 @@ struct RpcForm {
     args0: Vec<String>,
     #[serde(default, rename = "arg[]")]
     args1: Vec<String>,
+    /// Only to reject it, POST responses are never JSONP.
+    callback: Option<String>,
+    #[serde(flatten)]
+    extensions: RpcExtensions,
+}
+
+/// Request parameters understood on top of the ones aurweb supports.
+#[derive(Debug, Default, Deserialize)]
+struct RpcExtensions {
+    /// How relation searches (`by=depends` etc.) match: `exact` (default) or `substring`.
+    #[serde(rename = "match")]
+    match_mode: Option<String>,
+    /// Comma separated list of extra response fields, e.g. `depends_count`, `version_placeholder` or `aur_page`.
+    ext: Option<String>,
+    /// Comma separated relation arrays `info` should return, e.g. `depends,provides`.
+    include: Option<String>,
+    /// Comma separated relation arrays `info` should leave empty, e.g. `groups`.
+    exclude: Option<String>,
+    /// Architecture `info` resolves relations for, e.g. `x86_64`, instead of merging
+    /// the values of all architectures.
+    arch: Option<String>,
+    /// `1` or `true` to answer errors with `400 Bad Request` instead of aurweb's
+    /// `200 OK`, for clients that check status codes.
+    strict: Option<String>,
+    /// `popularity`, `votes` or `name` to sort search results, instead of the
+    /// database's order (or relevance for full-text searches).
+    sort: Option<String>,
+    /// Number of `search` or `info` results to skip, for pagination.
+    offset: Option<String>,
+    /// Maximum number of `search` or `info` results to return, for pagination.
+    limit: Option<String>,
+}
+
+impl RpcExtensions {
+    fn is_strict(&self) -> bool {
+        matches!(self.strict.as_deref(), Some("1" | "true"))
+    }
+
+    /// The page requested with `offset` and `limit`, `None` without either.
+    fn page(&self) -> Result<Option<Page>, RpcResponse<()>> {
+        if self.offset.is_none() && self.limit.is_none() {
+            return Ok(None);
+        }
+        let offset = match self.offset.as_deref() {
+            None => 0,
+            Some(offset) => offset
+                .parse()
+                .map_err(|_| error_response("Incorrect offset specified.".to_string(), Some(5)))?,
+        };
+        let limit = match self.limit.as_deref() {
+            None => DEFAULT_PAGE_SIZE,
+            Some(limit) => limit
+                .parse::<usize>()
+                .map_err(|_| error_response("Incorrect limit specified.".to_string(), Some(5)))?,
+        };
+        Ok(Some(Page {
+            offset,
+            limit: limit.min(MAX_PAGE_SIZE),
+        }))
+    }
+
+    fn has_ext(&self, name: &str) -> bool {
+        self.ext
+            .as_deref()
+            .is_some_and(|ext| ext.split(',').any(|e| e.trim() == name))
+    }
+
+    /// Relations selected by `include`/`exclude`, or `None` if either names an unknown one.
+    fn relations(&self) -> Option<Vec<Relation>> {
+        let parse = |list: &str| {
+            list.split(',')
+                .map(|name| Relation::from_str(name.trim()))
+                .collect::<Option<Vec<_>>>()
+        };
+        let mut relations = match self.include.as_deref() {
+            Some(list) => parse(list)?,
+            None => Relation::ALL.to_vec(),
+        };
+        if let Some(list) = self.exclude.as_deref() {
+            let excluded = parse(list)?;
+            relations.retain(|r| !excluded.contains(r));
+        }
+        Some(relations)
+    }
 }
 
 impl RpcServer {
-    pub fn new(app_state: AppState) -> Self {
+    pub fn new(app_state: AppState, options: ServerOptions) -> Self {
+        let rate_limiter = options
+            .rate_limit_per_minute
+            .map(|per_minute| RateLimiter::new(per_minute, options.rate_limit_exempt.clone()));
+        let response_cache =
+            ResponseCache::new(options.response_cache_size, options.response_cache_ttl);
         let state = RpcState {
             db: app_state.db,
-            client: reqwest::Client::new(),
+            // Proxied git packs and snapshots can take long, so only idle reads time out
+            client: options.http_timeouts.streaming_client(),
             github_token: app_state.github_token,
+            options: Arc::new(options),
+            commit_cache: app_state.commit_cache,
+            response_cache,
+            sync_status: app_state.sync_status,
+            rate_limiter,
+            metrics: Metrics::new(),
         };
 
-        let app = Router::new()
+        // Form bodies are buffered, unlike the streamed git-upload-pack requests
+        let post_limits = (
+            RequestBodyLimitLayer::new(state.options.rpc_post_body_limit),
+            TimeoutLayer::with_status_code(
+                StatusCode::REQUEST_TIMEOUT,
+                state.options.rpc_post_timeout,
+            ),
+        );
+        let mut limited = Router::new()
             .route("/rpc", get(handle_rpc_get))
-            .route("/rpc", post(handle_rpc_post))
+            .route("/rpc", post(handle_rpc_post).layer(post_limits))
+            .route("/rpc/stream", get(handle_rpc_stream))
+            .route("/rpc/{version}/{type}", get(handle_rpc_path_get))
             .route(
+                "/rpc/{version}/{type}",
+                post(handle_rpc_path_post).layer(post_limits),
+            )
+            .route("/rpc/{version}/{type}/{arg}", get(handle_rpc_path_get))
+            .route("/rpc/{version}/{type}/{by}/{arg}", get(handle_rpc_path_get))
+            .route("/pkgbase-of/{pkg_name}", get(handle_pkgbase_of))
+            .route("/revdeps/{name}", get(handle_revdeps));
+        if state.options.snapshot_routes {
+            limited = limited.route(
                 "/cgit/aur.git/snapshot/{snapshot_name}",
                 get(handle_snapshot),
-            )
-            .route("/{branch}/info/refs", get(handle_git_info_refs))
-            .route(
-                "/{branch}/git-upload-pack",
-                post(handle_git_upload_pack_post),
-            )
+            );
+        }
+        if state.rate_limiter.is_some() {
+            limited =
+                limited.route_layer(middleware::from_fn_with_state(state.clone(), limit_rate));
+        }
+        let mut app = Router::new()
+            .merge(limited)
+            .route("/health", get(handle_health))
+            .route("/stats", get(handle_stats))
+            .route("/changes", get(handle_changes))
+            .route("/vercmp", get(handle_vercmp))
+            // Only covers the routes above, git responses are already packed
+            .layer(CompressionLayer::new().compress_when(
+                SizeAbove::new(MIN_COMPRESS_SIZE).and(has_compressible_content_type),
+            ));
+        if state.options.git_routes {
+            app = app
+                .route("/{branch}/info/refs", get(handle_git_info_refs))
+                .route(
+                    "/{branch}/git-upload-pack",
+                    post(handle_git_upload_pack_post),
+                );
+        }
+        // Everything except the public read endpoints above
+        let mut admin = Router::new();
+        if state.options.debug_endpoints {
+            admin = admin.route("/debug/package/{branch}/{name}", get(handle_debug_package));
+        }
+        if state.options.metrics_endpoint {
+            admin = admin.route("/metrics", get(handle_metrics));
+        }
+        if state.options.admin_token.is_some() && admin.has_routes() {
+            admin = admin.route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                require_admin_token,
+            ));
+        }
+        app = app.merge(admin);
+        let app = app
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                record_metrics,
+            ))
+            .layer(middleware::from_fn_with_state(state.clone(), log_requests))
             .layer(CorsLayer::permissive())
-            .with_state(state);
+            .with_state(state.clone());
+
+        Self { app, state }
+    }
 
-        Self { app }
+    /// Fills the commit cache in one pass, avoiding a cold-start latency spike.
+    async fn warm_commit_cache(&self) -> Result<()> {
+        let start = Instant::now();
+        let branches = self.state.db.list_branches().await?;
+        let commits = self.state.db.get_branch_commit_ids(&branches).await?;
+        self.state.commit_cache.extend(commits);
+        info!(
+            "Warmed commit cache with {} branches in {:.2?}",
+            self.state.commit_cache.len(),
+            start.elapsed()
+        );
+        Ok(())
     }
 
+    /// Serves until `SIGINT` or `SIGTERM`, see `run_until`.
     pub async fn run(self, addrs: impl Iterator<Item = impl AsRef<str>>) -> Result<()> {
-        futures::future::try_join_all(addrs.map(async |addr| -> Result<()> {
-            info!("Listening on http://{}", addr.as_ref());
-            let listener = tokio::net::TcpListener::bind(addr.as_ref()).await?;
-            axum::serve(listener, self.app.clone()).await?;
+        self.run_until(addrs, shutdown_signal()).await
+    }
+
+    /// Serves on every address until `shutdown` completes, then gives in-flight requests
+    /// up to `shutdown_timeout` to finish.
+    pub async fn run_until(
+        self,
+        addrs: impl Iterator<Item = impl AsRef<str>>,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<()> {
+        if self.state.options.warm_commit_cache {
+            self.warm_commit_cache().await?;
+        }
+        if let Some(stats_interval) = self.state.options.stats_interval {
+            tokio::spawn(log_stats(self.state.clone(), stats_interval));
+        }
+        let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(());
+        let servers = futures::future::try_join_all(addrs.map(async |addr| -> Result<()> {
+            let mut shutdown_rx = shutdown_rx.clone();
+            let shutdown = async move {
+                let _ = shutdown_rx.changed().await;
+            };
+            if let Some(path) = addr.as_ref().strip_prefix(UNIX_SOCKET_PREFIX) {
+                return self.serve_unix(std::path::Path::new(path), shutdown).await;
+            }
+            let listener = bind(addr.as_ref()).await?;
+            axum::serve(
+                listener,
+                self.app
+                    .clone()
+                    .into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(shutdown)
+            .await?;
             Ok(())
-        }))
-        .await?;
+        }));
+        tokio::pin!(servers);
+        tokio::select! {
+            result = &mut servers => {
+                result?;
+                return Ok(());
+            }
+            () = shutdown => {}
+        }
+
+        let timeout = self.state.options.shutdown_timeout;
+        info!(
+            "Shutting down, waiting up to {:?} for in-flight requests",
+            timeout
+        );
+        let _ = shutdown_tx.send(());
+        match tokio::time::timeout(timeout, servers).await {
+            Ok(result) => {
+                result?;
+                info!("Server shut down");
+            }
+            Err(_) => warn!(
+                "Shutdown timed out after {:?}, dropping remaining connections",
+                timeout
+            ),
+        }
         Ok(())
     }
+
+    #[cfg(unix)]
+    async fn serve_unix(
+        &self,
+        path: &std::path::Path,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<()> {
+        let listener = bind_unix(path)?;
+        // Handlers take the client address from the connection, which a socket peer
+        // does not have
+        let app = self
+            .app
+            .clone()
+            .layer(Extension(ConnectInfo(UNIX_SOCKET_PEER)))
+            .layer(Extension(UnixSocketConnection));
+        let result = axum::serve(listener, app.into_make_service())
+            .with_graceful_shutdown(shutdown)
+            .await;
+        if let Err(e) = std::fs::remove_file(path) {
+            warn!("Failed to remove socket {}: {}", path.display(), e);
+        }
+        Ok(result?)
+    }
+
+    #[cfg(not(unix))]
+    async fn serve_unix(
+        &self,
+        _path: &std::path::Path,
+        _shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<()> {
+        Err(anyhow!("Unix sockets are not supported on this platform"))
+    }
+}
+
+/// Completes on Ctrl-C or, on unix, `SIGTERM` as sent by systemd.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+}
+
+/// Periodically logs cumulative request and cache counters and the package count.
+async fn log_stats(state: RpcState, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    // The first tick completes immediately, when there is nothing to report yet
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let (hits, misses) = state.metrics.commit_cache_lookups();
+        let hit_rate = match hits + misses {
+            0 => 0.0,
+            lookups => hits as f64 * 100.0 / lookups as f64,
+        };
+        let packages = match state.db.count_packages().await {
+            Ok(count) => count.to_string(),
+            Err(e) => {
+                warn!("Failed to count packages: {}", e);
+                "?".to_string()
+            }
+        };
+        info!(
+            "Stats: {} requests, commit cache hit rate {:.1}% ({} entries), {} packages",
+            state.metrics.requests_total(),
+            hit_rate,
+            state.commit_cache.len(),
+            packages
+        );
+    }
+}
+
+/// Request extension marking connections accepted on a Unix socket.
+#[derive(Clone, Copy)]
+struct UnixSocketConnection;
+
+/// Binds a Unix socket, replacing a stale socket file left by a server that did not
+/// shut down cleanly. The socket is made group-writable for a reverse proxy.
+///
+/// It is bound in a private directory and only moved into place once its permissions
+/// are set, as its initial mode depends on the umask.
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path) -> Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(anyhow!("{} exists and is not a socket", path.display()));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(anyhow!("{} is in use by another server", path.display()));
+        }
+        info!("Removing stale socket {}", path.display());
+        std::fs::remove_file(path)?;
+    }
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is not a socket path", path.display()))?;
+    let private_dir = path.with_file_name(format!(
+        ".{}.{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)?;
+    let private_path = private_dir.join(file_name);
+    let bound = tokio::net::UnixListener::bind(&private_path).and_then(|listener| {
+        std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(0o660))?;
+        std::fs::rename(&private_path, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&private_path);
+    std::fs::remove_dir(&private_dir)?;
+    let listener = bound?;
+    info!("Listening on unix:{}", path.display());
+    Ok(listener)
+}
+
+/// Binds a TCP listener. An unspecified IPv6 address (the default `[::]`) is bound
+/// dual-stack, since some systems default to `IPV6_V6ONLY` and would refuse IPv4.
+async fn bind(addr: &str) -> Result<tokio::net::TcpListener> {
+    let dual_stack_addr = addr
+        .parse::<SocketAddr>()
+        .ok()
+        .filter(|a| a.is_ipv6() && a.ip().is_unspecified());
+    let Some(socket_addr) = dual_stack_addr else {
+        info!("Listening on http://{}", addr);
+        return Ok(tokio::net::TcpListener::bind(addr).await?);
+    };
+
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    let dual_stack = socket.set_only_v6(false).is_ok();
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&socket_addr.into())?;
+    socket.listen(1024)?;
+    if dual_stack {
+        info!("Listening on http://{} (IPv4 and IPv6)", addr);
+    } else {
+        warn!(
+            "Listening on http://{} (IPv6 only, dual-stack is not supported here)",
+            addr
+        );
+    }
+    Ok(tokio::net::TcpListener::from_std(socket.into())?)
 }
 
 async fn handle_rpc_get(
     State(state): State<RpcState>,
+    headers: HeaderMap,
     axum_extra::extract::Query(query): axum_extra::extract::Query<RpcQuery>,
-) -> Result<Response<String>, StatusCode> {
+) -> Result<Response, StatusCode> {
     let all_args = query.args0.into_iter().chain(query.args1).collect();
+    let format = match ResponseFormat::negotiate(&headers, query.callback, state.options.jsonp) {
+        Ok(format) => format,
+        Err(error) => {
+            let response = create_error_response(&error, ResponseFormat::Json);
+            return Ok(with_strict_status(response, query.extensions.is_strict()));
+        }
+    };
 
     handle_rpc_request(
         query.v,
         query.request_type,
         query.search_by,
         all_args,
-        query.callback,
+        query.extensions,
+        format,
         state,
     )
     .await
@@ -114,16 +658,90 @@ async fn handle_rpc_get(
 
 async fn handle_rpc_post(
     State(state): State<RpcState>,
+    headers: HeaderMap,
     axum_extra::extract::Form(form): axum_extra::extract::Form<RpcForm>,
-) -> Result<Response<String>, StatusCode> {
+) -> Result<Response, StatusCode> {
     let all_args = form.args0.into_iter().chain(form.args1).collect();
+    let format = match ResponseFormat::negotiate_post(&headers, form.callback) {
+        Ok(format) => format,
+        Err(error) => {
+            let response = create_error_response(&error, ResponseFormat::Json);
+            return Ok(with_strict_status(response, form.extensions.is_strict()));
+        }
+    };
 
     handle_rpc_request(
         form.v,
         form.request_type,
         form.search_by,
         all_args,
-        None, // POST doesn't support JSONP
+        form.extensions,
+        format,
+        state,
+    )
+    .await
+}
+
+/// Path-style requests such as `/rpc/v5/info/<name>` or `/rpc/v5/search/<by>/<term>`.
+/// Values missing from the path are taken from the query string.
+async fn handle_rpc_path_get(
+    State(state): State<RpcState>,
+    Path(path): Path<RpcPath>,
+    headers: HeaderMap,
+    axum_extra::extract::Query(query): axum_extra::extract::Query<RpcQuery>,
+) -> Result<Response, StatusCode> {
+    let version = path.version().ok_or(StatusCode::NOT_FOUND)?;
+    // Only searches take a second path parameter
+    if path.search_by.is_some() && path.request_type != "search" {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let all_args = match path.arg {
+        Some(arg) => vec![arg],
+        None => query.args0.into_iter().chain(query.args1).collect(),
+    };
+    let format = match ResponseFormat::negotiate(&headers, query.callback, state.options.jsonp) {
+        Ok(format) => format,
+        Err(error) => {
+            let response = create_error_response(&error, ResponseFormat::Json);
+            return Ok(with_strict_status(response, query.extensions.is_strict()));
+        }
+    };
+
+    handle_rpc_request(
+        Some(version),
+        Some(path.request_type),
+        path.search_by.or(query.search_by),
+        all_args,
+        query.extensions,
+        format,
+        state,
+    )
+    .await
+}
+
+async fn handle_rpc_path_post(
+    State(state): State<RpcState>,
+    Path(path): Path<RpcPath>,
+    headers: HeaderMap,
+    axum_extra::extract::Form(form): axum_extra::extract::Form<RpcForm>,
+) -> Result<Response, StatusCode> {
+    let version = path.version().ok_or(StatusCode::NOT_FOUND)?;
+    let all_args = form.args0.into_iter().chain(form.args1).collect();
+    let format = match ResponseFormat::negotiate_post(&headers, form.callback) {
+        Ok(format) => format,
+        Err(error) => {
+            let response = create_error_response(&error, ResponseFormat::Json);
+            return Ok(with_strict_status(response, form.extensions.is_strict()));
+        }
+    };
+
+    handle_rpc_request(
+        Some(version),
+        Some(path.request_type),
+        form.search_by,
+        all_args,
+        form.extensions,
+        format,
         state,
     )
     .await
@@ -134,24 +752,37 @@ async fn handle_rpc_request(
     request_type: Option<String>,
     search_by: Option<String>,
     args: Vec<String>,
-    callback: Option<String>,
+    extensions: RpcExtensions,
+    format: ResponseFormat,
     state: RpcState,
-) -> Result<Response<String>, StatusCode> {
+) -> Result<Response, StatusCode> {
+    let strict = extensions.is_strict();
+    dispatch_rpc_request(
+        version,
+        request_type,
+        search_by,
+        args,
+        extensions,
+        format,
+        state,
+    )
+    .await
+    .map(|response| with_strict_status(response, strict))
+}
+
+async fn dispatch_rpc_request(
+    version: Option<String>,
+    request_type: Option<String>,
+    search_by: Option<String>,
+    args: Vec<String>,
+    extensions: RpcExtensions,
+    format: ResponseFormat,
+    state: RpcState,
+) -> Result<Response, StatusCode> {
     // Validate version
-    let version_num = match version {
-        None => {
-            let error = error_response("Please specify an API version.".to_string(), None);
-            return Ok(create_response(&error, callback));
-        }
-        Some(v) => match v.as_str() {
-            "5" => 5,
-            _ => {
-                let parsed_version = v.parse::<u32>().ok();
-                let error =
-                    error_response("Invalid version specified.".to_string(), parsed_version);
-                return Ok(create_response(&error, callback));
-            }
-        },
+    let version_num = match validate_version(version) {
+        Ok(v) => v,
+        Err(error) => return Ok(create_error_response(&error, format)),
     };
 
     // Validate request type
@@ -161,70 +792,286 @@ async fn handle_rpc_request(
                 "No request type/data specified.".to_string(),
                 Some(version_num),
             );
-            return Ok(create_response(&error, callback));
+            return Ok(create_error_response(&error, format));
         }
         Some(t) => t,
     };
 
+    // Unknown types share one series, so clients cannot create arbitrary ones
+    let type_label = match req_type.as_str() {
+        "search" | "msearch" | "info" | "suggest" | "suggest-pkgbase" => req_type.as_str(),
+        _ => "invalid",
+    };
+    state.metrics.record_rpc_request(type_label);
+
     match req_type.as_str() {
-        "search" => {
-            handle_search(
+        // `msearch` is aurweb's shorthand for `by=maintainer`, which needs maintainers
+        // from the AUR metadata dump, see `SearchType::Maintainer`
+        "search" | "msearch" => {
+            let started_at = Instant::now();
+            let (response_type, search_by) = if req_type == "msearch" {
+                ("msearch", SearchType::Maintainer.name().to_string())
+            } else {
+                (
+                    "search",
+                    search_by.unwrap_or_else(|| "name-desc".to_string()),
+                )
+            };
+            let keyword = args.first().map(|s| s.as_str()).unwrap_or("");
+            let (format, callback) = format.split_callback();
+            // Debug output quotes and escapes the values, so keys never collide
+            let key = format!(
+                "{} {:?} {:?} {:?} {:?}",
+                response_type, search_by, keyword, extensions, format
+            );
+            let response = handle_search(
+                state.clone(),
+                response_type,
+                Some(search_by.clone()),
+                keyword,
+                &extensions,
+                format,
+            );
+            let response = with_response_cache(&state, key, response).await;
+            state
+                .metrics
+                .record_rpc_latency("search", started_at.elapsed());
+            wrap_jsonp(response?, callback).await
+        }
+        "info" => {
+            let started_at = Instant::now();
+            let (format, callback) = format.split_callback();
+            let key = format!("info {:?} {:?} {:?}", args, extensions, format);
+            let response = handle_info(state.clone(), args, &extensions, format);
+            let response = with_response_cache(&state, key, response).await;
+            state
+                .metrics
+                .record_rpc_latency("info", started_at.elapsed());
+            wrap_jsonp(response?, callback).await
+        }
+        "suggest" | "suggest-pkgbase" => {
+            handle_suggest(
                 state,
-                search_by,
                 args.first().map(|s| s.as_str()).unwrap_or(""),
-                callback,
+                req_type == "suggest-pkgbase",
+                format,
             )
             .await
         }
-        "info" => handle_info(state, args, callback).await,
         _ => {
             let error = error_response(
                 "Incorrect request type specified.".to_string(),
                 Some(version_num),
             );
-            Ok(create_response(&error, callback))
+            Ok(create_error_response(&error, format))
+        }
+    }
+}
+
+/// Serves a response from the response cache, or awaits `response` and caches it
+/// if it succeeded.
+async fn with_response_cache(
+    state: &RpcState,
+    key: String,
+    response: impl Future<Output = Result<Response, StatusCode>>,
+) -> Result<Response, StatusCode> {
+    if !state.response_cache.is_enabled() {
+        return response.await;
+    }
+    // Read before querying, so a sync finishing meanwhile invalidates the result
+    let sync_generation = state.sync_status.completed_syncs();
+    if let Some(cached) = state.response_cache.get(&key, sync_generation) {
+        let mut response = Response::builder()
+            .header(header::CONTENT_TYPE, cached.content_type)
+            .body(Body::from(cached.body))
+            .unwrap();
+        if cached.is_error {
+            response.extensions_mut().insert(RpcErrorMarker);
         }
+        return Ok(response);
+    }
+
+    let response = response.await?;
+    let Some(content_type) = response.headers().get(header::CONTENT_TYPE).cloned() else {
+        return Ok(response);
+    };
+    if response.status() != StatusCode::OK {
+        return Ok(response);
+    }
+    let (parts, body) = response.into_parts();
+    let body = axum::body::to_bytes(body, usize::MAX).await.map_err(|e| {
+        error!("Failed to read response body for caching: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    state.response_cache.insert(
+        key,
+        CachedResponse {
+            content_type,
+            body: body.clone(),
+            is_error: parts.extensions.get::<RpcErrorMarker>().is_some(),
+        },
+        sync_generation,
+    );
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+/// Wraps a JSON response in `callback`, if there is one. Done after the response
+/// cache, so responses are cached once for all callbacks.
+async fn wrap_jsonp(response: Response, callback: Option<String>) -> Result<Response, StatusCode> {
+    let Some(callback) = callback else {
+        return Ok(response);
+    };
+    let (mut parts, body) = response.into_parts();
+    let json = axum::body::to_bytes(body, usize::MAX).await.map_err(|e| {
+        error!("Failed to read response body for JSONP: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let mut jsonp = Vec::with_capacity(callback.len() + json.len() + 3);
+    jsonp.extend_from_slice(callback.as_bytes());
+    jsonp.push(b'(');
+    jsonp.extend_from_slice(&json);
+    jsonp.extend_from_slice(b");");
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(JSONP_CONTENT_TYPE),
+    );
+    Ok(Response::from_parts(parts, Body::from(jsonp)))
+}
+
+fn validate_version(version: Option<String>) -> Result<u32, RpcResponse<()>> {
+    match version {
+        None => Err(error_response(
+            "Please specify an API version.".to_string(),
+            None,
+        )),
+        Some(v) => match v.as_str() {
+            "5" => Ok(5),
+            _ => {
+                let parsed_version = v.parse::<u32>().ok();
+                Err(error_response(
+                    "Invalid version specified.".to_string(),
+                    parsed_version,
+                ))
+            }
+        },
     }
 }
 
+fn parse_search_params(
+    search_by: Option<&str>,
+    extensions: &RpcExtensions,
+) -> Result<(SearchType, MatchMode, Option<SortOrder>), RpcResponse<()>> {
+    let search_type = search_by.unwrap_or("name-desc");
+    let search_enum = SearchType::from_str(search_type).ok_or_else(|| {
+        let valid = SearchType::ALL.map(SearchType::name).join(", ");
+        error_response(
+            format!("Incorrect by field specified. Valid values: {}.", valid),
+            Some(5),
+        )
+    })?;
+    let match_mode = match extensions.match_mode.as_deref() {
+        None => MatchMode::Exact,
+        Some(m) => MatchMode::from_str(m).ok_or_else(|| {
+            error_response("Incorrect match field specified.".to_string(), Some(5))
+        })?,
+    };
+    let order = match extensions.sort.as_deref() {
+        None => None,
+        Some(s) => Some(SortOrder::from_str(s).ok_or_else(|| {
+            error_response("Incorrect sort field specified.".to_string(), Some(5))
+        })?),
+    };
+    Ok((search_enum, match_mode, order))
+}
+
 async fn handle_search(
     state: RpcState,
+    response_type: &str,
     search_by: Option<String>,
     keyword: &str,
-    callback: Option<String>,
-) -> Result<Response<String>, StatusCode> {
+    extensions: &RpcExtensions,
+    format: ResponseFormat,
+) -> Result<Response, StatusCode> {
     if keyword.is_empty() {
         let error = error_response("Query arg too small.".to_string(), Some(5));
-        return Ok(create_response(&error, callback));
+        return Ok(create_error_response(&error, format));
     }
 
-    let search_type = search_by.as_deref().unwrap_or("name-desc");
-    let search_enum = SearchType::from_str(search_type);
-    if search_enum.is_none() {
-        let error = error_response("Incorrect by field specified.".to_string(), Some(5));
-        return Ok(create_response(&error, callback));
-    }
-    let search_enum = search_enum.unwrap();
+    let (search_enum, match_mode, order) =
+        match parse_search_params(search_by.as_deref(), extensions) {
+            Ok(params) => params,
+            Err(error) => return Ok(create_error_response(&error, format)),
+        };
+    let page = match extensions.page() {
+        Ok(page) => page,
+        Err(error) => return Ok(create_error_response(&error, format)),
+    };
+
+    // Unpaginated searches fetch one result more than allowed to detect too many results
+    let rows = state.db.search_packages(
+        search_enum,
+        keyword,
+        match_mode,
+        order,
+        Some(page.unwrap_or(Page {
+            offset: 0,
+            limit: MAX_SEARCH_RESULTS + 1,
+        })),
+    );
+    let total = async {
+        match page {
+            Some(_) => Ok(Some(
+                state
+                    .db
+                    .count_search_results(search_enum, keyword, match_mode)
+                    .await?,
+            )),
+            None => Ok(None),
+        }
+    };
+
+    match tokio::try_join!(rows, total) {
+        Ok((rows, None)) if rows.len() > MAX_SEARCH_RESULTS => {
+            let error = error_response("Too many package results.".to_string(), Some(5));
+            Ok(create_error_response(&error, format))
+        }
+        Ok((rows, total)) => {
+            let depends_counts = if extensions.has_ext("depends_count") {
+                let branches: Vec<String> =
+                    rows.iter().map(|r| r.branch.clone()).unique().collect();
+                match state.db.get_depends_counts(&branches).await {
+                    Ok(counts) => Some(counts),
+                    Err(e) => {
+                        error!("Database error during depends count: {}", e);
+                        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                    }
+                }
+            } else {
+                None
+            };
 
-    match state.db.search_packages(search_enum, keyword).await {
-        Ok(rows) => {
             let results: Vec<RpcPackageInfo> = rows
                 .into_iter()
-                .map(|row| RpcPackageInfo {
-                    id: 0,
-                    name: row.pkg_name.clone(),
-                    description: row.pkg_desc.clone().unwrap_or_default(),
-                    package_base: row.branch.clone(),
-                    package_base_id: 0,
-                    version: row.version.clone(),
-                    url: row.url.clone().unwrap_or_default(),
-                    url_path: format!("/cgit/aur.git/snapshot/{}.tar.gz", row.branch),
-                    maintainer: String::new(),
-                    num_votes: 0,
-                    popularity: 0.0,
-                    first_submitted: 0,
-                    last_modified: 0,
-                    out_of_date: None,
+                .map(|row| {
+                    let depends_count = depends_counts.as_ref().map(|counts| {
+                        counts
+                            .get(&(row.branch.clone(), row.pkg_name.clone()))
+                            .copied()
+                            .unwrap_or(0)
+                    });
+                    let version_is_placeholder = extensions
+                        .has_ext("version_placeholder")
+                        .then_some(row.version_is_placeholder);
+                    let aur_page = extensions
+                        .has_ext("aur_page")
+                        .then(|| aur_page_url(&state, &row.pkg_name));
+                    RpcPackageInfo {
+                        depends_count,
+                        version_is_placeholder,
+                        aur_page,
+                        ..to_rpc_package_info(row)
+                    }
                 })
                 .collect();
 
@@ -232,11 +1079,12 @@ async fn handle_search(
                 error: None,
                 result_count: results.len(),
                 results,
-                response_type: "search".to_string(),
+                response_type: response_type.to_string(),
                 version: Some(5),
+                total,
             };
 
-            Ok(create_response(&response, callback))
+            Ok(create_response(&response, format))
         }
         Err(e) => {
             error!("Database error during search: {}", e);
@@ -245,97 +1093,733 @@ async fn handle_search(
     }
 }
 
-async fn handle_info(
-    state: RpcState,
-    args: Vec<String>,
-    callback: Option<String>,
-) -> Result<Response<String>, StatusCode> {
-    if args.is_empty() {
-        let error = error_response("No request type/data specified.".to_string(), Some(5));
-        return Ok(create_response(&error, callback));
+/// Streams search results as newline-delimited JSON, one `RpcPackageInfo` per line,
+/// for bulk consumers that would otherwise have to buffer a huge response.
+async fn handle_rpc_stream(
+    State(state): State<RpcState>,
+    axum_extra::extract::Query(query): axum_extra::extract::Query<RpcQuery>,
+) -> Result<Response<Body>, StatusCode> {
+    let strict = query.extensions.is_strict();
+    stream_search(state, query)
+        .await
+        .map(|response| with_strict_status(response, strict))
+}
+
+async fn stream_search(state: RpcState, query: RpcQuery) -> Result<Response<Body>, StatusCode> {
+    // The stream itself is always NDJSON, so errors are never MessagePack either
+    let format = match ResponseFormat::jsonp_or_json(query.callback, state.options.jsonp) {
+        Ok(format) => format,
+        Err(error) => return Ok(create_error_response(&error, ResponseFormat::Json)),
+    };
+    let version_num = match validate_version(query.v) {
+        Ok(v) => v,
+        Err(error) => return Ok(create_error_response(&error, format)),
+    };
+
+    if query.request_type.as_deref() != Some("search") {
+        let error = error_response(
+            "Incorrect request type specified.".to_string(),
+            Some(version_num),
+        );
+        return Ok(create_error_response(&error, format));
     }
 
-    match state.db.get_package_details(&args).await {
-        Ok(package_details) => {
-            let results: Vec<RpcPackageDetails> = package_details
-                .into_iter()
-                .map(|details| RpcPackageDetails {
-                    id: 0,
-                    name: details.info.pkg_name.clone(),
-                    description: details.info.pkg_desc.clone().unwrap_or_default(),
-                    package_base: details.info.branch.clone(),
-                    package_base_id: 0,
-                    version: details.info.version.clone(),
-                    url: details.info.url.clone().unwrap_or_default(),
-                    url_path: format!("/cgit/aur.git/snapshot/{}.tar.gz", details.info.branch),
-                    maintainer: String::new(),
-                    submitter: String::new(),
-                    num_votes: 0,
-                    popularity: 0.0,
-                    first_submitted: 0,
-                    last_modified: 0,
-                    out_of_date: None,
-                    license: Vec::new(),
-                    depends: details.depends,
-                    makedepends: details.make_depends,
-                    optdepends: details.opt_depends,
-                    checkdepends: details.check_depends,
-                    provides: details.provides,
-                    conflicts: details.conflicts,
-                    replaces: details.replaces,
-                    groups: details.groups,
-                    keywords: Vec::new(),
-                    co_maintainers: Vec::new(),
-                })
-                .collect();
+    let keyword = query
+        .args0
+        .into_iter()
+        .chain(query.args1)
+        .next()
+        .unwrap_or_default();
+    if keyword.is_empty() {
+        let error = error_response("Query arg too small.".to_string(), Some(version_num));
+        return Ok(create_error_response(&error, format));
+    }
 
-            let response = RpcResponse {
-                error: None,
-                result_count: results.len(),
-                results,
-                response_type: "multiinfo".to_string(),
-                version: Some(5),
-            };
+    let (search_enum, match_mode, order) =
+        match parse_search_params(query.search_by.as_deref(), &query.extensions) {
+            Ok(params) => params,
+            Err(error) => return Ok(create_error_response(&error, format)),
+        };
 
-            Ok(create_response(&response, callback))
-        }
+    let Some(rows) = state
+        .db
+        .search_packages_stream(search_enum, &keyword, match_mode, order)
+    else {
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    };
+    let lines = rows
+        .map(|row| -> anyhow::Result<Vec<u8>> {
+            let mut line = serde_json::to_vec(&to_rpc_package_info(row?))?;
+            line.push(b'\n');
+            Ok(line)
+        })
+        .inspect_err(|e| error!("Database error during streaming search: {}", e));
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-ndjson")
+        .body(Body::from_stream(lines))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn log_requests(
+    State(state): State<RpcState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let trust_forwarded = state.options.trust_forwarded_headers;
+    let client_ip = forwarded::client_ip(request.headers(), peer, trust_forwarded);
+    let method = request.method().clone();
+    let url = format!(
+        "{}{}",
+        forwarded::base_url(request.headers(), trust_forwarded),
+        request.uri()
+    );
+    let start = Instant::now();
+    let response = next.run(request).await;
+    info!(
+        "{} {} {} {} {:.2?}",
+        client_ip,
+        method,
+        url,
+        response.status().as_u16(),
+        start.elapsed()
+    );
+    response
+}
+
+/// Counts requests by the route they matched, so path parameters do not inflate
+/// the number of series.
+async fn record_metrics(State(state): State<RpcState>, request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_default();
+    let response = next.run(request).await;
+    state
+        .metrics
+        .record_request(&route, response.status().as_u16());
+    response
+}
+
+/// Package bases (branches to clone) building the package, 404 if there is none.
+async fn handle_pkgbase_of(
+    State(state): State<RpcState>,
+    Path(pkg_name): Path<String>,
+) -> Result<Json<Vec<String>>, StatusCode> {
+    match state.db.get_pkgbases_of(&pkg_name).await {
+        Ok(pkgbases) if pkgbases.is_empty() => Err(StatusCode::NOT_FOUND),
+        Ok(pkgbases) => Ok(Json(pkgbases)),
         Err(e) => {
-            error!("Database error during info lookup: {}", e);
+            error!("Database error looking up package bases: {}", e);
             Err(StatusCode::INTERNAL_SERVER_ERROR)
         }
     }
 }
 
-async fn handle_snapshot(
+/// Dependency kinds `/revdeps` includes besides runtime dependencies.
+#[derive(Debug, Deserialize)]
+struct RevdepsQuery {
+    #[serde(default)]
+    make: bool,
+    #[serde(default)]
+    opt: bool,
+    #[serde(default)]
+    check: bool,
+}
+
+/// Packages depending on `name`, keyed by dependency kind, e.g. `depends`.
+async fn handle_revdeps(
     State(state): State<RpcState>,
-    Path(snapshot_name): Path<String>,
-) -> Result<Redirect, StatusCode> {
-    let branch_name = snapshot_name.strip_suffix(".tar.gz");
-
-    if let Some(branch_name) = branch_name {
-        match state.db.get_branch_commit_id(branch_name).await {
-            Ok(Some(commit_id)) => {
-                let github_url = format!(
-                    "https://github.com/archlinux/aur/archive/{}.tar.gz",
-                    commit_id
-                );
-                Ok(Redirect::temporary(&github_url))
-            }
-            Ok(None) => Err(StatusCode::NOT_FOUND),
-            Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    Path(name): Path<String>,
+    Query(query): Query<RevdepsQuery>,
+) -> Result<Json<BTreeMap<&'static str, Vec<DatabasePackageInfo>>>, StatusCode> {
+    match state
+        .db
+        .reverse_depends(&name, query.make, query.opt, query.check)
+        .await
+    {
+        Ok(groups) => Ok(Json(
+            groups
+                .into_iter()
+                .map(|(relation, packages)| (relation.name(), packages))
+                .collect(),
+        )),
+        Err(e) => {
+            error!("Database error looking up reverse dependencies: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
         }
-    } else {
-        Err(StatusCode::NOT_FOUND)
     }
 }
 
-async fn handle_git_info_refs(
-    State(state): State<RpcState>,
-    Path(branch): Path<String>,
+#[derive(Serialize)]
+struct StatsResponse {
+    packages: i64,
+    branches: i64,
+    /// Branches in the last fetched upstream branch list, `null` before the first sync.
+    upstream_branches: Option<i64>,
+    /// Unix timestamp of the last successful sync, `null` if there was none.
+    last_sync_at: Option<i64>,
+}
+
+/// How much data the mirror has and how fresh it is.
+async fn handle_stats(State(state): State<RpcState>) -> Result<Json<StatsResponse>, StatusCode> {
+    let stats = async {
+        anyhow::Ok(StatsResponse {
+            packages: state.db.count_packages().await?,
+            branches: state.db.count_branches().await?,
+            upstream_branches: state.db.get_branch_list_count().await?,
+            last_sync_at: state.db.get_last_sync_at().await?,
+        })
+    };
+    match stats.await {
+        Ok(stats) => Ok(Json(stats)),
+        Err(e) => {
+            error!("Database error reading stats: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Default and maximum number of entries returned by `/changes`.
+const CHANGES_DEFAULT_LIMIT: u32 = 100;
+const CHANGES_MAX_LIMIT: u32 = 1000;
+
+#[derive(Debug, Deserialize)]
+struct ChangesQuery {
+    /// Unix timestamp, only changes made at or after it are returned.
+    #[serde(default)]
+    since: i64,
+    limit: Option<u32>,
+}
+
+/// Package changes recorded by syncs with `--only-changed-packages`, newest first.
+async fn handle_changes(
+    State(state): State<RpcState>,
+    Query(query): Query<ChangesQuery>,
+) -> Result<Json<Vec<PackageChange>>, StatusCode> {
+    let limit = query
+        .limit
+        .unwrap_or(CHANGES_DEFAULT_LIMIT)
+        .min(CHANGES_MAX_LIMIT);
+    match state.db.get_package_changes(query.since, limit).await {
+        Ok(changes) => Ok(Json(changes)),
+        Err(e) => {
+            error!("Database error reading package changes: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct VercmpQuery {
+    a: String,
+    b: String,
+}
+
+/// `-1`, `0` or `1` like pacman's `vercmp`, or 400 if either version is malformed.
+async fn handle_vercmp(Query(query): Query<VercmpQuery>) -> Response {
+    for version in [&query.a, &query.b] {
+        if let Err(e) = Version::parse(version) {
+            return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+        }
+    }
+    let result = vercmp(&query.a, &query.b) as i32;
+    Json(result).into_response()
+}
+
+#[derive(Serialize)]
+struct HealthResponse {
+    /// `ok`, or `error` if the database cannot be queried.
+    status: &'static str,
+    /// `syncing` while a sync runs in this process, `idle` otherwise.
+    state: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    processed: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<usize>,
+    /// Unix timestamp of the last successful sync.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_sync_at: Option<i64>,
+}
+
+async fn handle_health(State(state): State<RpcState>) -> (StatusCode, Json<HealthResponse>) {
+    let progress = state.sync_status.progress();
+    let db_status = match state.db.ping().await {
+        Ok(()) => state.db.get_last_sync_at().await,
+        Err(e) => Err(e),
+    };
+    let (status_code, status, last_sync_at) = match db_status {
+        Ok(last_sync_at) => (StatusCode::OK, "ok", last_sync_at),
+        Err(e) => {
+            error!("Health check failed: {}", e);
+            (StatusCode::SERVICE_UNAVAILABLE, "error", None)
+        }
+    };
+    let response = HealthResponse {
+        status,
+        state: if progress.is_some() {
+            "syncing"
+        } else {
+            "idle"
+        },
+        processed: progress.map(|(processed, _)| processed),
+        total: progress.map(|(_, total)| total),
+        last_sync_at,
+    };
+    (status_code, Json(response))
+}
+
+async fn handle_metrics(State(state): State<RpcState>, headers: HeaderMap) -> Response {
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok());
+    let format = MetricsFormat::negotiate(accept);
+    let last_sync_duration = state.db.get_last_sync_duration().await.unwrap_or_else(|e| {
+        warn!("Failed to read the last sync duration: {}", e);
+        None
+    });
+    let body = state
+        .metrics
+        .render(format, state.commit_cache.len(), last_sync_duration);
+    ([(header::CONTENT_TYPE, format.content_type())], body).into_response()
+}
+
+fn has_compressible_content_type(
+    _: StatusCode,
+    _: axum::http::Version,
+    headers: &HeaderMap,
+    _: &axum::http::Extensions,
+) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| {
+            COMPRESSIBLE_CONTENT_TYPES
+                .iter()
+                .any(|prefix| content_type.starts_with(prefix))
+        })
+}
+
+/// Rejects clients that ran out of rate limit tokens with `429 Too Many Requests`.
+async fn limit_rate(
+    State(state): State<RpcState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    // All Unix socket clients share one address, so they are only limited by the
+    // address a trusted proxy forwarded
+    let client_ip = if request.extensions().get::<UnixSocketConnection>().is_some() {
+        state
+            .options
+            .trust_forwarded_headers
+            .then(|| forwarded::forwarded_client_ip(request.headers()))
+            .flatten()
+    } else {
+        Some(forwarded::client_ip(
+            request.headers(),
+            peer,
+            state.options.trust_forwarded_headers,
+        ))
+    };
+    if let (Some(limiter), Some(client_ip)) = (&state.rate_limiter, client_ip) {
+        if let Err(retry_after) = limiter.check(client_ip) {
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(
+                    header::RETRY_AFTER,
+                    rate_limit::retry_after_secs(retry_after).to_string(),
+                )],
+            )
+                .into_response();
+        }
+    }
+    next.run(request).await
+}
+
+/// Rejects requests without `Authorization: Bearer <admin_token>`.
+async fn require_admin_token(
+    State(state): State<RpcState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let expected = state.options.admin_token.as_deref().unwrap_or_default();
+    let provided = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if !constant_time_eq(provided.as_bytes(), expected.as_bytes()) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// Compares without short-circuiting, so the time taken does not reveal the matching prefix.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Dumps the raw rows stored for a package, for debugging indexing issues.
+async fn handle_debug_package(
+    State(state): State<RpcState>,
+    Path((branch, name)): Path<(String, String)>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let tables = state
+        .db
+        .get_raw_package_rows(&branch, &name)
+        .await
+        .map_err(|e| {
+            error!("Database error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Json(serde_json::json!(tables)))
+}
+
+fn aur_page_url(state: &RpcState, pkg_name: &str) -> String {
+    format!("{}/packages/{}", state.options.aur_web_url, pkg_name)
+}
+
+/// Maintainer, votes, popularity, first submission and last modification time, from
+/// the AUR metadata dump if it lists the package. Otherwise the commit time stands in
+/// for the last modification and the rest is zero like for packages aurweb lacks data on.
+fn aur_stats(info: &DatabasePackageInfo) -> (String, u32, f64, u64, u64) {
+    match &info.meta {
+        Some(meta) => (
+            meta.maintainer.clone().unwrap_or_default(),
+            meta.num_votes,
+            meta.popularity,
+            meta.first_submitted as u64,
+            meta.last_modified as u64,
+        ),
+        None => (
+            String::new(),
+            0,
+            0.0,
+            0,
+            info.last_modified.unwrap_or_default() as u64,
+        ),
+    }
+}
+
+fn to_rpc_package_info(row: DatabasePackageInfo) -> RpcPackageInfo {
+    let (maintainer, num_votes, popularity, first_submitted, last_modified) = aur_stats(&row);
+    RpcPackageInfo {
+        id: 0,
+        name: row.pkg_name,
+        description: row.pkg_desc.unwrap_or_default(),
+        url_path: format!("/cgit/aur.git/snapshot/{}.tar.gz", row.branch),
+        package_base: row.branch,
+        package_base_id: 0,
+        version: row.version,
+        url: row.url.unwrap_or_default(),
+        maintainer,
+        num_votes,
+        popularity,
+        first_submitted,
+        last_modified,
+        out_of_date: None,
+        depends_count: None,
+        version_is_placeholder: None,
+        aur_page: None,
+    }
+}
+
+/// Returns a bare array of package or package base names, like aurweb.
+async fn handle_suggest(
+    state: RpcState,
+    prefix: &str,
+    pkgbase_only: bool,
+    format: ResponseFormat,
+) -> Result<Response, StatusCode> {
+    match state
+        .db
+        .suggest_packages(prefix, pkgbase_only, state.options.suggest_limit)
+        .await
+    {
+        Ok(names) => Ok(create_response(&names, format)),
+        Err(e) => {
+            error!("Database error during suggest: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn handle_info(
+    state: RpcState,
+    args: Vec<String>,
+    extensions: &RpcExtensions,
+    format: ResponseFormat,
+) -> Result<Response, StatusCode> {
+    if args.is_empty() {
+        let error = error_response("No request type/data specified.".to_string(), Some(5));
+        return Ok(create_error_response(&error, format));
+    }
+
+    let relations = match extensions.relations() {
+        Some(relations) => relations,
+        None => {
+            let error = error_response("Incorrect relation name specified.".to_string(), Some(5));
+            return Ok(create_error_response(&error, format));
+        }
+    };
+    let page = match extensions.page() {
+        Ok(page) => page,
+        Err(error) => return Ok(create_error_response(&error, format)),
+    };
+
+    let package_details = state.db.get_package_details(
+        &args,
+        &relations,
+        state.options.info_case_insensitive,
+        extensions.arch.as_deref(),
+        page,
+    );
+    let total = async {
+        match page {
+            Some(_) => Ok(Some(
+                state
+                    .db
+                    .count_package_details(&args, state.options.info_case_insensitive)
+                    .await?,
+            )),
+            None => Ok(None),
+        }
+    };
+
+    match tokio::try_join!(package_details, total) {
+        Ok((package_details, total)) => {
+            let results: Vec<RpcPackageDetails> = package_details
+                .into_iter()
+                .map(|details| {
+                    let (maintainer, num_votes, popularity, first_submitted, last_modified) =
+                        aur_stats(&details.info);
+                    RpcPackageDetails {
+                        id: 0,
+                        name: details.info.pkg_name.clone(),
+                        description: details.info.pkg_desc.clone().unwrap_or_default(),
+                        package_base: details.info.branch.clone(),
+                        package_base_id: 0,
+                        version: details.info.version.clone(),
+                        url: details.info.url.clone().unwrap_or_default(),
+                        url_path: format!("/cgit/aur.git/snapshot/{}.tar.gz", details.info.branch),
+                        maintainer,
+                        submitter: String::new(),
+                        num_votes,
+                        popularity,
+                        first_submitted,
+                        last_modified,
+                        out_of_date: None,
+                        license: Vec::new(),
+                        depends: details.depends,
+                        makedepends: details.make_depends,
+                        optdepends: details.opt_depends,
+                        checkdepends: details.check_depends,
+                        provides: details.provides,
+                        conflicts: details.conflicts,
+                        replaces: details.replaces,
+                        groups: details.groups,
+                        keywords: details.keywords,
+                        co_maintainers: Vec::new(),
+                        version_is_placeholder: extensions
+                            .has_ext("version_placeholder")
+                            .then_some(details.info.version_is_placeholder),
+                        aur_page: extensions
+                            .has_ext("aur_page")
+                            .then(|| aur_page_url(&state, &details.info.pkg_name)),
+                    }
+                })
+                .collect();
+
+            let response = RpcResponse {
+                error: None,
+                result_count: results.len(),
+                results,
+                response_type: "multiinfo".to_string(),
+                version: Some(5),
+                total,
+            };
+
+            Ok(create_response(&response, format))
+        }
+        Err(e) => {
+            error!("Database error during info lookup: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Looks up the commit of a branch, through the commit cache.
+async fn branch_commit_id(state: &RpcState, branch: &str) -> Result<Option<String>> {
+    let cached = state.commit_cache.get(branch);
+    state.metrics.record_commit_cache_lookup(cached.is_some());
+    if let Some(commit_id) = cached {
+        return Ok(Some(commit_id));
+    }
+    let commit_id = state.db.get_branch_commit_id(branch).await?;
+    if let Some(commit_id) = &commit_id {
+        state
+            .commit_cache
+            .insert(branch.to_string(), commit_id.clone());
+    }
+    Ok(commit_id)
+}
+
+/// Archive suffixes GitHub can produce, with their content types.
+const SNAPSHOT_FORMATS: &[(&str, &str)] =
+    &[(".tar.gz", "application/gzip"), (".zip", "application/zip")];
+
+#[derive(Debug, Deserialize)]
+struct SnapshotQuery {
+    /// A past commit of the branch, as recorded in the commit history.
+    id: Option<String>,
+}
+
+async fn handle_snapshot(
+    State(state): State<RpcState>,
+    Path(snapshot_name): Path<String>,
+    Query(query): Query<SnapshotQuery>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let (branch_name, suffix, content_type) = SNAPSHOT_FORMATS
+        .iter()
+        .find_map(|&(suffix, content_type)| {
+            let branch_name = snapshot_name.strip_suffix(suffix)?;
+            Some((branch_name, suffix, content_type))
+        })
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let historical = query.id.is_some();
+    let commit_id = match query.id {
+        Some(id) => state
+            .db
+            .is_known_commit(branch_name, &id)
+            .await
+            .map(|known| known.then_some(id)),
+        None => branch_commit_id(&state, branch_name).await,
+    }
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    // A commit always produces the same archive, so clients holding it can skip the redirect
+    let etag = format!("\"{}\"", commit_id);
+    if etag_matches(&headers, &etag) {
+        return Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::ETAG, &etag)
+            .body(Body::empty())
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    let github_url = check_upstream_url(
+        &state,
+        state.options.upstream_repo.archive_url(&commit_id, suffix),
+    )?;
+    match state.options.snapshot_mode {
+        SnapshotMode::Redirect if state.options.snapshot_redirect_permanent => {
+            let max_age = if historical {
+                HISTORICAL_SNAPSHOT_MAX_AGE
+            } else {
+                commit_cache::ENTRY_TTL
+            };
+            let cache_control = format!("public, max-age={}", max_age.as_secs());
+            Ok((
+                [(header::ETAG, etag), (header::CACHE_CONTROL, cache_control)],
+                Redirect::permanent(&github_url),
+            )
+                .into_response())
+        }
+        SnapshotMode::Redirect => {
+            Ok(([(header::ETAG, etag)], Redirect::temporary(&github_url)).into_response())
+        }
+        SnapshotMode::Proxy => {
+            // The stored version only describes the latest commit
+            let version = if historical {
+                None
+            } else {
+                state
+                    .db
+                    .get_branch_version(branch_name)
+                    .await
+                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            };
+            let file_stem = match version {
+                Some(version) => format!("{}-{}", branch_name, version),
+                None => format!("{}-{}", branch_name, &commit_id[..commit_id.len().min(8)]),
+            };
+
+            let upstream = state.client.get(github_url).send().await.map_err(|e| {
+                error!("Snapshot proxy error: {}", e);
+                StatusCode::BAD_GATEWAY
+            })?;
+            if !upstream.status().is_success() {
+                error!(
+                    "Snapshot proxy error: upstream returned {}",
+                    upstream.status()
+                );
+                return Err(StatusCode::BAD_GATEWAY);
+            }
+            // Derived from the request, GitHub's own headers may differ
+            Response::builder()
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::ETAG, etag)
+                .header(
+                    header::CONTENT_DISPOSITION,
+                    format!(
+                        "attachment; filename=\"{}{}\"",
+                        file_stem.replace(['"', '\\'], "_"),
+                        suffix
+                    ),
+                )
+                .body(Body::from_stream(upstream.bytes_stream()))
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Whether `If-None-Match` lists `etag` or is `*`. Weak tags compare equal to the
+/// strong one, as `If-None-Match` requires.
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+/// The branch of a git route's `{branch}` segment, without its optional `.git`
+/// extension. Anything outside the AUR package name charset is rejected before it
+/// reaches the database or the upstream proxy, as are names git could take for an option.
+fn git_branch_name(branch: &str) -> Result<&str, StatusCode> {
+    let branch_name = branch.strip_suffix(".git").unwrap_or(branch);
+    let valid = !branch_name.is_empty()
+        && !branch_name.starts_with('-')
+        && !matches!(branch_name, "." | "..")
+        && branch_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "@._+-".contains(c));
+    if valid {
+        Ok(branch_name)
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
+
+async fn handle_git_info_refs(
+    State(state): State<RpcState>,
+    Path(branch): Path<String>,
     Query(params): Query<HashMap<String, String>>,
 ) -> Result<Response<String>, StatusCode> {
-    // Remove .git extension if present
-    let branch_name = branch.strip_suffix(".git").unwrap_or(&branch);
+    let branch_name = git_branch_name(&branch)?;
 
     let service = match params.get("service") {
         Some(s) => s,
@@ -355,12 +1839,19 @@ async fn handle_git_info_refs(
     }
 
     // Check if package exists and get commit ID
-    match state.db.get_branch_commit_id(branch_name).await {
+    match branch_commit_id(&state, branch_name).await {
         Ok(Some(commit_id)) => {
-            let response_body = format!("001e# service=git-upload-pack\n000000e1{} HEAD\u{0000}multi_ack thin-pack side-band side-band-64k ofs-delta no-progress include-tag multi_ack_detailed no-done symref=HEAD:refs/heads/master object-format=sha1 agent=git/aur-mirror\n003f{} refs/heads/master\n0000",
-                commit_id,
-                commit_id
-            );
+            let response_body = [
+                pkt_line::encode("# service=git-upload-pack\n"),
+                pkt_line::FLUSH.to_string(),
+                pkt_line::encode(&format!(
+                    "{} HEAD\0{}\n",
+                    commit_id, GIT_UPLOAD_PACK_CAPABILITIES
+                )),
+                pkt_line::encode(&format!("{} refs/heads/master\n", commit_id)),
+                pkt_line::FLUSH.to_string(),
+            ]
+            .concat();
 
             Ok(Response::builder()
                 .header(
@@ -375,6 +1866,29 @@ async fn handle_git_info_refs(
     }
 }
 
+/// Rejects outbound URLs whose host is not in the configured allowlist.
+fn check_upstream_url(state: &RpcState, url: String) -> Result<String, StatusCode> {
+    let allowed = reqwest::Url::parse(&url)
+        .ok()
+        .and_then(|u| u.host_str().map(|h| h.to_string()))
+        .is_some_and(|host| {
+            state
+                .options
+                .allowed_upstream_hosts
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(&host))
+        });
+    if allowed {
+        Ok(url)
+    } else {
+        error!(
+            "Refusing to use upstream URL outside the allowlist: {}",
+            url
+        );
+        Err(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
 fn error_response(message: String, version: Option<u32>) -> RpcResponse<()> {
     RpcResponse::<()> {
         error: Some(message),
@@ -382,26 +1896,138 @@ fn error_response(message: String, version: Option<u32>) -> RpcResponse<()> {
         results: Vec::new(),
         response_type: "error".to_string(),
         version,
+        total: None,
     }
 }
 
-fn create_response<T: serde::Serialize>(data: &T, callback: Option<String>) -> Response<String> {
-    let json = serde_json::to_string(data).unwrap();
+/// Encoding of an RPC response.
+#[derive(Debug)]
+enum ResponseFormat {
+    Json,
+    Jsonp(String),
+    MessagePack,
+}
 
-    if let Some(callback_fn) = callback {
-        // JSONP response
-        let jsonp = format!("{}({});", callback_fn, json);
-        Response::builder()
-            .header(header::CONTENT_TYPE, "application/javascript")
-            .body(jsonp)
-            .unwrap()
-    } else {
-        // Regular JSON response
-        Response::builder()
-            .header(header::CONTENT_TYPE, "application/json")
-            .body(json)
-            .unwrap()
+impl ResponseFormat {
+    /// JSONP if a callback is given, otherwise MessagePack if the client accepts it.
+    fn negotiate(
+        headers: &HeaderMap,
+        callback: Option<String>,
+        jsonp_enabled: bool,
+    ) -> Result<Self, RpcResponse<()>> {
+        if let Some(callback) = callback {
+            return Self::jsonp(callback, jsonp_enabled);
+        }
+        Ok(Self::negotiate_encoding(headers))
+    }
+
+    /// Like `negotiate`, but POST responses are never wrapped in a callback, so
+    /// one is rejected rather than silently ignored.
+    fn negotiate_post(
+        headers: &HeaderMap,
+        callback: Option<String>,
+    ) -> Result<Self, RpcResponse<()>> {
+        if callback.is_some() {
+            return Err(error_response(
+                "JSONP callbacks are only supported in GET requests.".to_string(),
+                None,
+            ));
+        }
+        Ok(Self::negotiate_encoding(headers))
+    }
+
+    /// JSON in place of JSONP, along with the callback to wrap it in.
+    fn split_callback(self) -> (Self, Option<String>) {
+        match self {
+            Self::Jsonp(callback) => (Self::Json, Some(callback)),
+            format => (format, None),
+        }
     }
+
+    /// JSONP if a callback is given, plain JSON otherwise.
+    fn jsonp_or_json(
+        callback: Option<String>,
+        jsonp_enabled: bool,
+    ) -> Result<Self, RpcResponse<()>> {
+        callback.map_or(Ok(Self::Json), |callback| {
+            Self::jsonp(callback, jsonp_enabled)
+        })
+    }
+
+    /// The callback is reflected into a script, so only the characters aurweb
+    /// allows are accepted.
+    fn jsonp(callback: String, enabled: bool) -> Result<Self, RpcResponse<()>> {
+        if !enabled {
+            return Err(error_response(
+                "JSONP callbacks are disabled.".to_string(),
+                None,
+            ));
+        }
+        let valid = (1..=MAX_CALLBACK_LENGTH).contains(&callback.len())
+            && callback
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "()_.".contains(c));
+        if valid {
+            Ok(Self::Jsonp(callback))
+        } else {
+            Err(error_response("Invalid callback name.".to_string(), None))
+        }
+    }
+
+    fn negotiate_encoding(headers: &HeaderMap) -> Self {
+        let accepts_msgpack = headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|media_type| media_type.split(';').next().unwrap_or_default().trim())
+            .any(|media_type| {
+                media_type == MSGPACK_CONTENT_TYPE || media_type == "application/x-msgpack"
+            });
+        if accepts_msgpack {
+            Self::MessagePack
+        } else {
+            Self::Json
+        }
+    }
+}
+
+/// Marks responses carrying an RPC error, see [`with_strict_status`].
+#[derive(Debug, Clone, Copy)]
+struct RpcErrorMarker;
+
+fn create_error_response(error: &RpcResponse<()>, format: ResponseFormat) -> Response {
+    let mut response = create_response(error, format);
+    response.extensions_mut().insert(RpcErrorMarker);
+    response
+}
+
+/// Gives RPC errors `400 Bad Request` if the client asked for `strict` status codes.
+/// The body is the same as with `200 OK`.
+fn with_strict_status(mut response: Response, strict: bool) -> Response {
+    if strict && response.extensions().get::<RpcErrorMarker>().is_some() {
+        *response.status_mut() = StatusCode::BAD_REQUEST;
+    }
+    response
+}
+
+fn create_response<T: serde::Serialize>(data: &T, format: ResponseFormat) -> Response {
+    let (content_type, body) = match format {
+        ResponseFormat::Json => ("application/json", serde_json::to_vec(data).unwrap()),
+        ResponseFormat::Jsonp(callback_fn) => {
+            let json = serde_json::to_string(data).unwrap();
+            let jsonp = format!("{}({});", callback_fn, json);
+            (JSONP_CONTENT_TYPE, jsonp.into_bytes())
+        }
+        // Named fields, so the envelope keeps its `type`/`resultcount` keys
+        ResponseFormat::MessagePack => {
+            (MSGPACK_CONTENT_TYPE, rmp_serde::to_vec_named(data).unwrap())
+        }
+    };
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .unwrap()
 }
 
 async fn handle_git_upload_pack_post(
@@ -410,14 +2036,16 @@ async fn handle_git_upload_pack_post(
     headers: HeaderMap,
     body: Body,
 ) -> Result<Response<Body>, StatusCode> {
-    let branch_name = branch.strip_suffix(".git").unwrap_or(&branch);
+    let branch_name = git_branch_name(&branch)?;
 
     // Check if package exists and get commit ID
-    match state.db.get_branch_commit_id(branch_name).await {
+    match branch_commit_id(&state, branch_name).await {
         Ok(Some(_)) => {
-            let mut req = state
-                .client
-                .post("https://github.com/archlinux/aur.git/git-upload-pack");
+            let upstream_url = check_upstream_url(
+                &state,
+                format!("{}/git-upload-pack", state.options.upstream_repo.git_url()),
+            )?;
+            let mut req = state.client.post(upstream_url);
             for (key, value) in headers.iter() {
                 match *key {
                     header::HOST => {
@@ -441,11 +2069,627 @@ async fn handle_git_upload_pack_post(
                 .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
             let mut response_builder = Response::builder().status(upstream.status());
             *response_builder.headers_mut().unwrap() = upstream.headers().clone();
+            let metrics = state.metrics.clone();
+            let stream = upstream
+                .bytes_stream()
+                .inspect_ok(move |chunk| metrics.add_git_upload_pack_bytes(chunk.len()));
             response_builder
-                .body(Body::from_stream(upstream.bytes_stream()))
+                .body(Body::from_stream(stream))
                 .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
         }
         Ok(None) => Err(StatusCode::NOT_FOUND),
         Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tests::{insert_packages, test_db, test_package, TestDb};
+    use crate::types::DatabasePackageDetails;
+    use tower::ServiceExt;
+
+    fn test_options() -> ServerOptions {
+        ServerOptions {
+            allowed_upstream_hosts: vec!["github.com".to_string()],
+            debug_endpoints: false,
+            metrics_endpoint: false,
+            admin_token: None,
+            suggest_limit: 20,
+            info_case_insensitive: false,
+            trust_forwarded_headers: false,
+            warm_commit_cache: false,
+            aur_web_url: "https://aur.archlinux.org".to_string(),
+            snapshot_mode: SnapshotMode::Redirect,
+            snapshot_redirect_permanent: false,
+            stats_interval: None,
+            rpc_post_body_limit: 1024,
+            rpc_post_timeout: Duration::from_secs(10),
+            response_cache_size: 0,
+            response_cache_ttl: Duration::from_secs(60),
+            shutdown_timeout: Duration::from_secs(1),
+            http_timeouts: HttpTimeouts::default(),
+            upstream_repo: UpstreamRepo::default(),
+            rate_limit_per_minute: None,
+            rate_limit_exempt: Vec::new(),
+            git_routes: true,
+            snapshot_routes: true,
+            jsonp: true,
+        }
+    }
+
+    /// A server on a fresh database, holding the `foo` and `bar` packages unless
+    /// others are given.
+    struct TestServer {
+        app: Router,
+        db: TestDb,
+    }
+
+    impl TestServer {
+        async fn new(configure: impl FnOnce(&mut ServerOptions)) -> Self {
+            let packages = [test_package("foo", "foo"), test_package("bar", "bar")];
+            Self::with_packages(&packages, configure).await
+        }
+
+        async fn with_packages(
+            packages: &[DatabasePackageDetails],
+            configure: impl FnOnce(&mut ServerOptions),
+        ) -> Self {
+            let db = test_db().await;
+            insert_packages(&db, packages).await;
+            let mut options = test_options();
+            configure(&mut options);
+            let app_state = AppState {
+                db: db.db.clone(),
+                github_token: None,
+                commit_cache: CommitCache::default(),
+                sync_status: SyncStatus::default(),
+            };
+            let app = RpcServer::new(app_state, options).app;
+            Self { app, db }
+        }
+
+        async fn send(&self, mut request: Request) -> Response {
+            let peer: SocketAddr = "192.0.2.1:1234".parse().unwrap();
+            request.extensions_mut().insert(ConnectInfo(peer));
+            self.app.clone().oneshot(request).await.unwrap()
+        }
+
+        async fn get(&self, uri: &str) -> Response {
+            self.send(Request::get(uri).body(Body::empty()).unwrap())
+                .await
+        }
+    }
+
+    async fn body_text(response: Response) -> String {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    async fn body_json(response: Response) -> serde_json::Value {
+        serde_json::from_str(&body_text(response).await).unwrap()
+    }
+
+    fn result_names(json: &serde_json::Value) -> Vec<&str> {
+        json["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| result["Name"].as_str().unwrap())
+            .collect()
+    }
+
+    fn extensions(include: Option<&str>, exclude: Option<&str>) -> RpcExtensions {
+        RpcExtensions {
+            include: include.map(str::to_string),
+            exclude: exclude.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn relations_default_to_all() {
+        assert_eq!(
+            extensions(None, None).relations(),
+            Some(Relation::ALL.to_vec())
+        );
+    }
+
+    #[test]
+    fn relations_include_and_exclude() {
+        assert_eq!(
+            extensions(Some("depends, provides"), None).relations(),
+            Some(vec![Relation::Depends, Relation::Provides])
+        );
+        let relations = extensions(None, Some("groups,keywords"))
+            .relations()
+            .unwrap();
+        assert_eq!(relations.len(), Relation::ALL.len() - 2);
+        assert!(!relations.contains(&Relation::Groups));
+        assert_eq!(
+            extensions(Some("depends,groups"), Some("groups")).relations(),
+            Some(vec![Relation::Depends])
+        );
+    }
+
+    #[test]
+    fn relations_reject_unknown_names() {
+        assert_eq!(extensions(Some("depends,bogus"), None).relations(), None);
+        assert_eq!(extensions(None, Some("bogus")).relations(), None);
+    }
+
+    #[test]
+    fn search_by_defaults_to_name_desc() {
+        let (search_type, _, order) = parse_search_params(None, &Default::default()).unwrap();
+        assert_eq!(search_type, SearchType::NameDesc);
+        assert_eq!(order, None);
+    }
+
+    #[test]
+    fn search_by_accepts_every_listed_value() {
+        for search_type in SearchType::ALL {
+            let (parsed, _, _) =
+                parse_search_params(Some(search_type.name()), &Default::default()).unwrap();
+            assert_eq!(parsed, search_type);
+        }
+    }
+
+    #[test]
+    fn unknown_search_by_lists_valid_values() {
+        let error = parse_search_params(Some("bogus"), &Default::default()).unwrap_err();
+        assert_eq!(
+            error.error.as_deref(),
+            Some(
+                "Incorrect by field specified. Valid values: name, name-desc, depends, \
+                 makedepends, optdepends, checkdepends, provides, conflicts, replaces, groups, \
+                 keywords, fulltext, maintainer, submitter."
+            )
+        );
+        assert_eq!(error.version, Some(5));
+    }
+
+    #[tokio::test]
+    async fn path_style_info() {
+        let server = TestServer::new(|_| {}).await;
+        let response = server.get("/rpc/v5/info/foo").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let json = body_json(response).await;
+        assert_eq!(json["type"], "multiinfo");
+        assert_eq!(result_names(&json), ["foo"]);
+
+        let json = body_json(server.get("/rpc/v5/info?arg[]=foo&arg[]=bar").await).await;
+        let mut names = result_names(&json);
+        names.sort();
+        assert_eq!(names, ["bar", "foo"]);
+    }
+
+    #[tokio::test]
+    async fn path_style_search() {
+        let server = TestServer::new(|_| {}).await;
+        let json = body_json(server.get("/rpc/v5/search/name/foo").await).await;
+        assert_eq!(json["type"], "search");
+        assert_eq!(result_names(&json), ["foo"]);
+        let json = body_json(server.get("/rpc/v5/search/ba").await).await;
+        assert_eq!(result_names(&json), ["bar"]);
+        // Same results as the query-style form
+        let json = body_json(server.get("/rpc?v=5&type=search&by=name&arg=foo").await).await;
+        assert_eq!(result_names(&json), ["foo"]);
+    }
+
+    #[tokio::test]
+    async fn path_style_rejects_bad_versions_and_by_on_info() {
+        let server = TestServer::new(|_| {}).await;
+        let response = server.get("/rpc/5/info/foo").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let json = body_json(server.get("/rpc/v4/info/foo").await).await;
+        assert_eq!(json["type"], "error");
+        let response = server.get("/rpc/v5/info/name/foo").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    fn form_post(uri: &str, body: Body) -> Request {
+        Request::post(uri)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(body)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn rpc_post_accepts_small_forms() {
+        let server = TestServer::new(|_| {}).await;
+        let response = server
+            .send(form_post("/rpc", Body::from("v=5&type=info&arg[]=foo")))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(result_names(&body_json(response).await), ["foo"]);
+    }
+
+    #[tokio::test]
+    async fn rpc_post_rejects_oversized_forms() {
+        let server = TestServer::new(|options| options.rpc_post_body_limit = 64).await;
+        let body = format!("v=5&type=info{}", "&arg[]=foo".repeat(10));
+        for uri in ["/rpc", "/rpc/v5/info"] {
+            let response = server.send(form_post(uri, Body::from(body.clone()))).await;
+            assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE, "{}", uri);
+        }
+        // GET requests carry no form body and are not limited
+        let response = server.get(&format!("/rpc?{}", body)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rpc_post_times_out_on_stalled_bodies() {
+        let server = TestServer::new(|options| {
+            options.rpc_post_timeout = Duration::from_millis(50);
+        })
+        .await;
+        let stalled = futures::stream::pending::<Result<axum::body::Bytes, std::io::Error>>();
+        let response = server
+            .send(form_post("/rpc", Body::from_stream(stalled)))
+            .await;
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn route_groups_are_enabled_by_default() {
+        let server = TestServer::new(|_| {}).await;
+        // Without `service` the git route answers before contacting GitHub
+        let response = server.get("/foo/info/refs").await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = server.get("/cgit/aur.git/snapshot/foo.tar.gz").await;
+        assert!(response.status().is_redirection());
+        let response = server.get("/rpc?v=5&type=info&arg=foo&callback=cb").await;
+        assert!(body_text(response).await.starts_with("cb({"));
+    }
+
+    #[tokio::test]
+    async fn disabled_route_groups_are_not_found() {
+        let server = TestServer::new(|options| {
+            options.git_routes = false;
+            options.snapshot_routes = false;
+            options.jsonp = false;
+        })
+        .await;
+        let response = server.get("/foo/info/refs?service=git-upload-pack").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = server
+            .send(
+                Request::post("/foo/git-upload-pack")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = server.get("/cgit/aur.git/snapshot/foo.tar.gz").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let json = body_json(server.get("/rpc?v=5&type=info&arg=foo&callback=cb").await).await;
+        assert_eq!(json["error"], "JSONP callbacks are disabled.");
+        // The plain RPC is still served
+        let json = body_json(server.get("/rpc?v=5&type=info&arg=foo").await).await;
+        assert_eq!(result_names(&json), ["foo"]);
+    }
+
+    fn from_unix_socket(uri: &str, forwarded_for: Option<&str>) -> Request {
+        let mut request = Request::get(uri);
+        if let Some(forwarded_for) = forwarded_for {
+            request = request.header("x-forwarded-for", forwarded_for);
+        }
+        let mut request = request.body(Body::empty()).unwrap();
+        request.extensions_mut().insert(UnixSocketConnection);
+        request
+    }
+
+    #[tokio::test]
+    async fn rate_limit_applies_per_client() {
+        let server = TestServer::new(|options| options.rate_limit_per_minute = Some(1)).await;
+        let response = server.get("/rpc?v=5&type=info&arg=foo").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = server.get("/rpc?v=5&type=info&arg=foo").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "60");
+        // Not limited
+        assert_eq!(server.get("/health").await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn unix_socket_clients_do_not_share_a_bucket() {
+        let server = TestServer::new(|options| options.rate_limit_per_minute = Some(1)).await;
+        for _ in 0..3 {
+            let response = server
+                .send(from_unix_socket("/rpc?v=5&type=info&arg=foo", None))
+                .await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        // Forwarded addresses are ignored unless trusted
+        let response = server
+            .send(from_unix_socket(
+                "/rpc?v=5&type=info&arg=foo",
+                Some("192.0.2.7"),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn unix_socket_clients_are_limited_by_forwarded_address() {
+        let server = TestServer::new(|options| {
+            options.rate_limit_per_minute = Some(1);
+            options.trust_forwarded_headers = true;
+        })
+        .await;
+        let uri = "/rpc?v=5&type=info&arg=foo";
+        let response = server.send(from_unix_socket(uri, Some("192.0.2.7"))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = server.send(from_unix_socket(uri, Some("192.0.2.7"))).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let response = server.send(from_unix_socket(uri, Some("192.0.2.8"))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        // Without a forwarded address there is nothing to limit by
+        let response = server.send(from_unix_socket(uri, None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket_is_group_writable_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("aur.sock");
+        let listener = bind_unix(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        // Only the socket is left behind
+        let entries: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, ["aur.sock"]);
+
+        assert!(bind_unix(&path).is_err(), "socket in use was replaced");
+        drop(listener);
+        // A stale socket is replaced
+        bind_unix(&path).unwrap();
+    }
+
+    /// A server whose `type=search&arg=pkg` response is large enough to be compressed.
+    async fn server_with_many_packages() -> TestServer {
+        let packages: Vec<_> = (0..20)
+            .map(|i| test_package(&format!("pkg-{}", i), &format!("pkg-{}", i)))
+            .collect();
+        TestServer::with_packages(&packages, |_| {}).await
+    }
+
+    async fn get_encoded(server: &TestServer, uri: &str, accept_encoding: &str) -> Response {
+        let request = Request::get(uri)
+            .header(header::ACCEPT_ENCODING, accept_encoding)
+            .body(Body::empty())
+            .unwrap();
+        server.send(request).await
+    }
+
+    fn content_encoding(response: &Response) -> Option<&str> {
+        response
+            .headers()
+            .get(header::CONTENT_ENCODING)
+            .map(|value| value.to_str().unwrap())
+    }
+
+    #[tokio::test]
+    async fn responses_are_compressed_as_negotiated() {
+        let server = server_with_many_packages().await;
+        let uri = "/rpc?v=5&type=search&by=name&arg=pkg";
+        for (accept_encoding, expected) in [
+            ("gzip", Some("gzip")),
+            ("deflate", Some("deflate")),
+            ("gzip, deflate", Some("gzip")),
+            ("gzip;q=0, deflate", Some("deflate")),
+            ("gzip;q=0.2, deflate;q=0.8", Some("deflate")),
+            // A wildcard never overrides an explicit refusal, identity is always fine
+            ("*", None),
+            ("*, gzip;q=0", None),
+            ("gzip;q=0, deflate;q=0", None),
+            ("identity", None),
+            ("br", None),
+        ] {
+            let response = get_encoded(&server, uri, accept_encoding).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(content_encoding(&response), expected, "{}", accept_encoding);
+            let vary = response.headers().get_all(header::VARY);
+            assert!(
+                vary.iter().any(|v| v == "accept-encoding"),
+                "{}: {:?}",
+                accept_encoding,
+                vary
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn small_and_streamed_responses_are_not_compressed() {
+        let server = server_with_many_packages().await;
+        let response = get_encoded(&server, "/rpc?v=5&type=info&arg=pkg-1", "gzip").await;
+        assert_eq!(content_encoding(&response), None);
+        let uri = "/rpc/stream?v=5&type=search&by=name&arg=pkg";
+        let response = get_encoded(&server, uri, "gzip").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(content_encoding(&response), None);
+        assert_eq!(body_text(response).await.lines().count(), 20);
+    }
+
+    #[tokio::test]
+    async fn cached_responses_are_shared_by_jsonp_callbacks() {
+        let server = TestServer::new(|options| options.response_cache_size = 10).await;
+        let response = server
+            .get("/rpc?v=5&type=search&by=name&arg=ba&callback=one")
+            .await;
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/javascript"
+        );
+        let first = body_text(response).await;
+        assert!(
+            first.starts_with("one({") && first.ends_with("});"),
+            "{}",
+            first
+        );
+        let json = &first["one(".len()..first.len() - ");".len()];
+
+        // Changes are only visible once the cached response expires
+        insert_packages(&server.db, &[test_package("baz", "baz")]).await;
+        let second = body_text(
+            server
+                .get("/rpc?v=5&type=search&by=name&arg=ba&callback=two")
+                .await,
+        )
+        .await;
+        assert_eq!(second, format!("two({});", json));
+        let plain = server.get("/rpc?v=5&type=search&by=name&arg=ba").await;
+        assert_eq!(plain.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(body_text(plain).await, json);
+    }
+
+    #[tokio::test]
+    async fn jsonp_wraps_errors() {
+        let server = TestServer::new(|options| options.response_cache_size = 10).await;
+        let response = server
+            .get("/rpc?v=5&type=search&by=bogus&arg=foo&callback=cb")
+            .await;
+        let body = body_text(response).await;
+        assert!(
+            body.starts_with("cb({") && body.contains("Incorrect by field"),
+            "{}",
+            body
+        );
+    }
+
+    #[test]
+    fn git_branch_names_stay_in_the_package_name_charset() {
+        assert_eq!(git_branch_name("foo"), Ok("foo"));
+        assert_eq!(git_branch_name("foo.git"), Ok("foo"));
+        assert_eq!(git_branch_name("lib32-foo@2+x_y"), Ok("lib32-foo@2+x_y"));
+        for branch in [
+            "",
+            ".git",
+            ".",
+            "..",
+            "...git",
+            "a/b",
+            "a\\b",
+            "%2e%2e",
+            "-foo",
+            "--upload-pack=x",
+            "-foo.git",
+            "föo",
+            "foo bar",
+        ] {
+            assert_eq!(
+                git_branch_name(branch),
+                Err(StatusCode::NOT_FOUND),
+                "{branch}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_git_branches_never_reach_the_database_or_upstream() {
+        // Stored under the invalid names, so only validation can turn them away, and with
+        // no allowed upstream host a proxied request fails with 500 instead of 404
+        let packages = [
+            test_package("foo", "foo"),
+            test_package("..", "dots"),
+            test_package("a/b", "slash"),
+            test_package("-foo", "dash"),
+            test_package("föo", "umlaut"),
+        ];
+        let server = TestServer::with_packages(&packages, |options| {
+            options.allowed_upstream_hosts.clear();
+        })
+        .await;
+
+        let response = server.get("/foo/info/refs?service=git-upload-pack").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let request = Request::post("/foo/git-upload-pack").body(Body::empty());
+        let response = server.send(request.unwrap()).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        for branch in [
+            "..",
+            "%2e%2e",
+            "%2E%2E.git",
+            "a/b",
+            "a%2Fb",
+            "-foo",
+            "-foo.git",
+            "f%C3%B6o",
+        ] {
+            let uri = format!("/{branch}/info/refs?service=git-upload-pack");
+            let response = server.get(&uri).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{uri}");
+
+            let uri = format!("/{branch}/git-upload-pack");
+            let request = Request::post(&uri).body(Body::empty());
+            let response = server.send(request.unwrap()).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn run_until_returns_once_shutdown_completes() {
+        let db = test_db().await;
+        let app_state = AppState {
+            db: db.db.clone(),
+            github_token: None,
+            commit_cache: CommitCache::default(),
+            sync_status: SyncStatus::default(),
+        };
+        let server = RpcServer::new(app_state, test_options());
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let run = tokio::spawn(server.run_until(["127.0.0.1:0"].iter(), async move {
+            let _ = stop_rx.await;
+        }));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!run.is_finished());
+
+        stop_tx.send(()).unwrap();
+        let result = tokio::time::timeout(Duration::from_secs(5), run).await;
+        result.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_streams_are_limited() {
+        let packages: Vec<_> = (0..200)
+            .map(|i| test_package(&format!("pkg-{}", i), &format!("pkg-{}", i)))
+            .collect();
+        let server = TestServer::with_packages(&packages, |_| {}).await;
+        let uri = "/rpc/stream?v=5&type=search&by=name&arg=pkg";
+
+        // Bodies left unread, as by slow clients
+        let mut held = Vec::new();
+        for _ in 0..crate::database::MAX_CONCURRENT_STREAMS {
+            let response = server.get(uri).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            held.push(response);
+        }
+        let response = server.get(uri).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let response = server.get("/rpc?v=5&type=info&arg=pkg-1").await;
+        assert_eq!(result_names(&body_json(response).await), ["pkg-1"]);
+
+        held.pop();
+        let mut attempts = 0;
+        let response = loop {
+            let response = server.get(uri).await;
+            if response.status() == StatusCode::OK {
+                break response;
+            }
+            attempts += 1;
+            assert!(attempts < 500, "the stream slot was never released");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(body_text(response).await.lines().count(), packages.len());
+    }
+}
@@ -1,6 +1,10 @@
+use crate::version::Version;
 use itertools::Itertools;
 use std::collections::{hash_map, HashMap};
 
+const VCS_SUFFIXES: &[&str] = &["-git", "-svn", "-hg", "-bzr", "-fossil", "-darcs"];
+const VCS_SOURCE_PREFIXES: &[&str] = &["git+", "git://", "svn+", "hg+", "bzr+", "fossil+"];
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ParsedSrcInfo {
     pub pkgbase: String,
@@ -23,20 +27,47 @@ fn merge_props(dst: &mut HashMap<String, Vec<String>>, src: &HashMap<String, Vec
     }
 }
 
+/// Pushes the last package of `base`, or `base` itself as a package named after it
+/// if it had no `pkgname` section, i.e. no packages from `base_start` on.
+fn finish_base(
+    result: &mut Vec<ParsedSrcInfo>,
+    base: Option<PkgBase>,
+    pkg: Option<ParsedSrcInfo>,
+    base_start: usize,
+) {
+    let Some(base) = base else {
+        return;
+    };
+    if let Some(mut pkg) = pkg {
+        merge_props(&mut pkg.properties, &base.properties);
+        result.push(pkg);
+    } else if result.len() == base_start {
+        result.push(ParsedSrcInfo {
+            pkgname: base.pkgbase.clone(),
+            pkgbase: base.pkgbase,
+            properties: base.properties,
+        });
+    }
+}
+
 impl ParsedSrcInfo {
     pub fn parse(srcinfo_text: &str) -> Vec<ParsedSrcInfo> {
         if srcinfo_text.trim().is_empty() {
             return vec![];
         }
 
+        // makepkg writes a `# Generated by makepkg` header, hand-written files may have
+        // more comments, which must not be read as keys if they contain `=`
         let lines = srcinfo_text
             .lines()
             .map(|l| l.trim())
-            .filter(|l| !l.is_empty());
+            .filter(|l| !l.is_empty() && !l.starts_with('#'));
 
         let mut result: Vec<ParsedSrcInfo> = Vec::new();
         let mut current_base: Option<PkgBase> = None;
         let mut current_pkg: Option<ParsedSrcInfo> = None;
+        // Where the packages of the current base start in `result`
+        let mut base_start = 0;
 
         for line in lines {
             if let Some(eq_pos) = line.find('=') {
@@ -46,12 +77,14 @@ impl ParsedSrcInfo {
 
                 match trimmed_key {
                     "pkgbase" => {
-                        if let Some(mut pkg) = current_pkg.take() {
-                            if let Some(base) = &current_base {
-                                merge_props(&mut pkg.properties, &base.properties);
-                            }
-                            result.push(pkg);
-                        }
+                        // A second base in one blob starts a separate group
+                        finish_base(
+                            &mut result,
+                            current_base.take(),
+                            current_pkg.take(),
+                            base_start,
+                        );
+                        base_start = result.len();
                         current_base = Some(PkgBase {
                             pkgbase: trimmed_value.to_string(),
                             properties: HashMap::new(),
@@ -88,22 +121,7 @@ impl ParsedSrcInfo {
             }
         }
 
-        if let Some(mut pkg) = current_pkg.take() {
-            if let Some(base) = &current_base {
-                merge_props(&mut pkg.properties, &base.properties);
-            }
-            result.push(pkg);
-        }
-
-        if result.is_empty() {
-            if let Some(base) = current_pkg.take() {
-                result.push(ParsedSrcInfo {
-                    pkgname: base.pkgbase.clone(),
-                    pkgbase: base.pkgbase,
-                    properties: base.properties,
-                });
-            }
-        }
+        finish_base(&mut result, current_base, current_pkg, base_start);
 
         result
     }
@@ -118,6 +136,36 @@ impl ParsedSrcInfo {
         self.properties.get(k).cloned().unwrap_or_default()
     }
 
+    /// Values of the non-standard `keywords` key, which may list several keywords
+    /// separated by whitespace on one line.
+    pub fn keywords(&self) -> Vec<String> {
+        self.properties
+            .get("keywords")
+            .into_iter()
+            .flatten()
+            .flat_map(|v| v.split_whitespace())
+            .map(|s| s.to_string())
+            .unique()
+            .collect()
+    }
+
+    /// Values of `k` by architecture: `None` for the plain key, `Some("x86_64")` for
+    /// `k_x86_64` and so on.
+    pub fn arch_props(&self, k: &str) -> HashMap<Option<String>, Vec<String>> {
+        let prefix = &format!("{}_", k);
+        self.properties
+            .iter()
+            .filter_map(|(key, values)| {
+                let arch = match key.strip_prefix(prefix) {
+                    Some(arch) => Some(arch.to_string()),
+                    None if key == k => None,
+                    None => return None,
+                };
+                Some((arch, values.clone()))
+            })
+            .collect()
+    }
+
     pub fn flatten_arch_prop(&self, k: &str) -> Vec<String> {
         // join all key named ${k} or starts with ${k}_
         // dedup and flatten
@@ -132,10 +180,60 @@ impl ParsedSrcInfo {
             .collect()
     }
 
+    pub fn supports_arch(&self, arch: &str) -> bool {
+        match self.properties.get("arch") {
+            Some(arches) if !arches.is_empty() => arches.iter().any(|a| a == arch || a == "any"),
+            _ => true,
+        }
+    }
+
+    /// Whether this is a VCS package, judging by its name or the scheme of its sources.
+    /// `pkgver()` itself is not part of .SRCINFO.
+    pub fn is_vcs(&self) -> bool {
+        VCS_SUFFIXES
+            .iter()
+            .any(|s| self.pkgname.ends_with(s) || self.pkgbase.ends_with(s))
+            || self.flatten_arch_prop("source").iter().any(|source| {
+                // Skip the optional `name::` prefix
+                let url = source
+                    .split_once("::")
+                    .map_or(source.as_str(), |(_, url)| url);
+                VCS_SOURCE_PREFIXES.iter().any(|p| url.starts_with(p))
+            })
+    }
+
+    /// Whether this is a VCS package whose `pkgver` matches one of `patterns` (`*` is a
+    /// wildcard), i.e. the stored version says nothing about the actual upstream HEAD.
+    pub fn has_placeholder_version(&self, patterns: &[String]) -> bool {
+        self.is_vcs()
+            && self
+                .version_prop("pkgver")
+                .is_none_or(|pkgver| patterns.iter().any(|p| glob_match(p, pkgver)))
+    }
+
+    /// Version components can never contain `#`, so anything after it is an inline comment.
+    /// This must not be applied to other fields (e.g. `source` URL fragments).
+    fn version_prop(&self, k: &str) -> Option<&str> {
+        self.first_prop(k)
+            .and_then(|v| v.split('#').next())
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+    }
+
+    /// The full version, or `None` if `pkgver` or `pkgrel` is missing or the
+    /// version is malformed, which [`Self::version`] would paper over.
+    pub fn try_version(&self) -> Option<String> {
+        self.version_prop("pkgver")?;
+        self.version_prop("pkgrel")?;
+        let version = self.version();
+        Version::parse(&version).is_ok().then_some(version)
+    }
+
+    /// The version with `pkgver` and `pkgrel` defaulting to `0.0.1` and `1`.
     pub fn version(&self) -> String {
-        let epoch = self.first_prop("epoch");
-        let pkgver = self.first_prop("pkgver").unwrap_or("0.0.1");
-        let pkgrel = self.first_prop("pkgrel").unwrap_or("1");
+        let epoch = self.version_prop("epoch");
+        let pkgver = self.version_prop("pkgver").unwrap_or("0.0.1");
+        let pkgrel = self.version_prop("pkgrel").unwrap_or("1");
         if let Some(epoch) = epoch {
             format!("{}:{}-{}", epoch, pkgver, pkgrel)
         } else {
@@ -143,3 +241,193 @@ impl ParsedSrcInfo {
         }
     }
 }
+
+/// Matches `text` against `pattern`, where `*` matches any run of characters.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let Some(mut rest) = text.strip_prefix(parts.next().unwrap_or_default()) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_one(srcinfo: &str) -> ParsedSrcInfo {
+        let mut packages = ParsedSrcInfo::parse(srcinfo);
+        assert_eq!(packages.len(), 1, "{:?}", packages);
+        packages.remove(0)
+    }
+
+    #[test]
+    fn version_strips_inline_comments() {
+        let pkg = parse_one(
+            "pkgbase = foo\n\
+             \tpkgver = 1.2.3 # bumped by hand\n\
+             \tpkgrel = 1  # note\n\
+             \tepoch = 2#x\n\
+             pkgname = foo\n",
+        );
+        assert_eq!(pkg.version(), "2:1.2.3-1");
+        assert_eq!(pkg.try_version().as_deref(), Some("2:1.2.3-1"));
+    }
+
+    #[test]
+    fn version_treats_comment_only_values_as_missing() {
+        let pkg = parse_one("pkgbase = foo\n\tpkgver = # unset\n\tpkgrel = 3\npkgname = foo\n");
+        assert_eq!(pkg.version(), "0.0.1-3");
+        assert_eq!(pkg.try_version(), None);
+    }
+
+    #[test]
+    fn glob_match_wildcards() {
+        assert!(glob_match("r0", "r0"));
+        assert!(!glob_match("r0", "r01"));
+        assert!(!glob_match("r0", "xr0"));
+        assert!(glob_match("*g0000000", "r0.g0000000"));
+        assert!(glob_match("*g0000000", "g0000000"));
+        assert!(!glob_match("*g0000000", "1.g00000001"));
+        assert!(glob_match("r0.*", "r0."));
+        assert!(glob_match("a*b*c", "a-b-b-c"));
+        assert!(!glob_match("a*b*c", "a-c-b"));
+        assert!(!glob_match("ab*ba", "aba"));
+        assert!(glob_match("*", ""));
+    }
+
+    #[test]
+    fn placeholder_versions_need_a_vcs_package() {
+        let patterns = ["r0".to_string()];
+        let srcinfo = |name: &str, pkgver: &str| {
+            parse_one(&format!(
+                "pkgbase = {name}\n\tpkgver = {pkgver}\n\tpkgrel = 1\npkgname = {name}\n"
+            ))
+        };
+        assert!(srcinfo("foo-git", "r0").has_placeholder_version(&patterns));
+        assert!(!srcinfo("foo-git", "r12.abc1234").has_placeholder_version(&patterns));
+        assert!(!srcinfo("foo", "r0").has_placeholder_version(&patterns));
+    }
+
+    #[test]
+    fn comments_are_kept_in_other_fields() {
+        let pkg = parse_one(
+            "pkgbase = foo\n\
+             \tpkgver = 1\n\
+             \tpkgrel = 1\n\
+             \tsource = git+https://example.com/foo.git#tag=v1\n\
+             pkgname = foo\n",
+        );
+        assert_eq!(
+            pkg.prop("source"),
+            ["git+https://example.com/foo.git#tag=v1"]
+        );
+    }
+
+    #[test]
+    fn keywords_split_on_whitespace() {
+        let pkg = parse_one(
+            "pkgbase = foo\n\tpkgver = 1\n\tkeywords = editor  text\n\tkeywords = vim editor\n\npkgname = foo\n",
+        );
+        assert_eq!(pkg.keywords(), ["editor", "text", "vim"]);
+        let pkg = parse_one("pkgbase = foo\n\tpkgver = 1\n\npkgname = foo\n");
+        assert!(pkg.keywords().is_empty());
+    }
+
+    #[test]
+    fn comment_lines_are_skipped() {
+        let pkg = parse_one(
+            "# Generated by makepkg 6.1.0\n\
+             # using fakeroot version 1.34\n\
+             pkgbase = foo\n\
+             \tpkgver = 1\n\
+             \t# depends = commented-out\n\
+             \tdepends = bar\n\
+             \n\
+             # pkgname = not-a-package\n\
+             pkgname = foo\n",
+        );
+        assert_eq!(pkg.pkgname, "foo");
+        assert_eq!(pkg.prop("depends"), ["bar"]);
+        assert!(pkg.properties.keys().all(|key| !key.starts_with('#')));
+    }
+
+    #[test]
+    fn blank_values_are_kept_as_empty() {
+        let pkg = parse_one(
+            "pkgbase = foo\n\
+             \tpkgver = 1\n\
+             \tpkgdesc =\n\
+             \turl =   \n\
+             \tdepends = bar\n\
+             \tdepends =\t\n\
+             pkgname = foo\n",
+        );
+        assert!(pkg.properties.contains_key("pkgdesc"));
+        assert_eq!(pkg.first_prop("pkgdesc"), None);
+        assert_eq!(pkg.first_prop("url"), None);
+        assert_eq!(pkg.prop("depends"), ["bar"]);
+    }
+
+    #[test]
+    fn blank_package_values_override_the_base() {
+        let packages = ParsedSrcInfo::parse(
+            "pkgbase = foo\n\
+             \tpkgver = 1\n\
+             \tdepends = bar\n\
+             pkgname = foo\n\
+             pkgname = foo-docs\n\
+             \tdepends =\n",
+        );
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages[0].prop("depends"), ["bar"]);
+        assert!(packages[1].prop("depends").is_empty());
+    }
+
+    #[test]
+    fn second_pkgbase_starts_a_separate_group() {
+        let packages = ParsedSrcInfo::parse(
+            "pkgbase = foo\n\
+             \tpkgver = 1\n\
+             \tdepends = bar\n\
+             pkgname = foo\n\
+             pkgname = foo-docs\n\
+             pkgbase = baz\n\
+             \tpkgver = 2\n\
+             pkgname = baz\n",
+        );
+        let names: Vec<_> = packages
+            .iter()
+            .map(|pkg| (pkg.pkgbase.as_str(), pkg.pkgname.as_str()))
+            .collect();
+        assert_eq!(names, [("foo", "foo"), ("foo", "foo-docs"), ("baz", "baz")]);
+        assert_eq!(packages[1].first_prop("pkgver"), Some("1"));
+        // Properties of the first base do not leak into the second
+        assert_eq!(packages[2].first_prop("pkgver"), Some("2"));
+        assert!(packages[2].prop("depends").is_empty());
+    }
+
+    #[test]
+    fn pkgbase_without_packages_is_its_own_package() {
+        let packages = ParsedSrcInfo::parse(
+            "pkgbase = foo\n\
+             \tpkgver = 1\n\
+             pkgbase = bar\n\
+             \tpkgver = 2\n\
+             pkgname = bar\n",
+        );
+        let names: Vec<_> = packages.iter().map(|pkg| pkg.pkgname.as_str()).collect();
+        assert_eq!(names, ["foo", "bar"]);
+        assert_eq!(packages[0].first_prop("pkgver"), Some("1"));
+    }
+}
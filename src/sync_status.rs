@@ -0,0 +1,60 @@
+//! Progress of a sync running in the same process as the server.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[derive(Clone, Default)]
+pub struct SyncStatus {
+    inner: Arc<SyncStatusInner>,
+}
+
+#[derive(Default)]
+struct SyncStatusInner {
+    running: AtomicBool,
+    processed: AtomicUsize,
+    total: AtomicUsize,
+    /// Syncs finished since the start, successful or not.
+    completed: AtomicU64,
+}
+
+/// Marks the sync as finished when dropped, including when it fails.
+pub struct SyncRunning<'a>(&'a SyncStatus);
+
+impl Drop for SyncRunning<'_> {
+    fn drop(&mut self) {
+        self.0.inner.running.store(false, Ordering::Relaxed);
+        self.0.inner.completed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl SyncStatus {
+    pub fn start(&self) -> SyncRunning<'_> {
+        self.inner.processed.store(0, Ordering::Relaxed);
+        self.inner.total.store(0, Ordering::Relaxed);
+        self.inner.running.store(true, Ordering::Relaxed);
+        SyncRunning(self)
+    }
+
+    pub fn set_total(&self, total: usize) {
+        self.inner.total.store(total, Ordering::Relaxed);
+    }
+
+    pub fn add_processed(&self, count: usize) {
+        self.inner.processed.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Changes with every finished sync, which may have modified packages.
+    pub fn completed_syncs(&self) -> u64 {
+        self.inner.completed.load(Ordering::Relaxed)
+    }
+
+    /// Processed and total branches of the running sync, `None` when idle.
+    pub fn progress(&self) -> Option<(usize, usize)> {
+        self.inner.running.load(Ordering::Relaxed).then(|| {
+            (
+                self.inner.processed.load(Ordering::Relaxed),
+                self.inner.total.load(Ordering::Relaxed),
+            )
+        })
+    }
+}
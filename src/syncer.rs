@@ -1,80 +1,329 @@
 use crate::{
     app_state::AppState,
-    aur_fetcher::AurFetcher,
-    database::DatabaseOps,
+    aur_fetcher::{AurFetcher, BranchList, BranchListSource, HttpTimeouts, UpstreamRepo},
+    commit_cache::CommitCache,
+    database::{DatabaseOps, PackageState, WriteStats},
     srcinfo_parse::ParsedSrcInfo,
-    types::{DatabasePackageDetails, DatabasePackageInfo},
+    sync_status::SyncStatus,
+    types::{DatabasePackageDetails, DatabasePackageInfo, PackageChange, Relation},
+};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::{stream, StreamExt};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::mpsc,
+    time::{sleep, sleep_until, Instant},
 };
-use anyhow::Result;
-use tokio::sync::mpsc;
 use tracing::{error, info, warn};
 
 const BATCH_SIZE: usize = 150;
 
+const PROBLEM_NO_PACKAGES: &str = "no_packages";
+const PROBLEM_FETCH_FAILED: &str = "fetch_failed";
+
+const CHANGE_ADDED: &str = "added";
+const CHANGE_REMOVED: &str = "removed";
+const CHANGE_VERSION: &str = "version";
+/// Same version, but different dependencies.
+const CHANGE_DEPENDS: &str = "depends";
+
+const META_BRANCH_LIST: &str = "branch_list";
+const META_BRANCH_LIST_ETAG: &str = "branch_list_etag";
+
 pub struct Syncer {
     db: DatabaseOps,
+    commit_cache: CommitCache,
+    status: SyncStatus,
     fetcher: AurFetcher,
+    options: SyncOptions,
+}
+
+#[derive(Debug, Default)]
+pub struct SyncOptions {
+    pub filter: SyncFilter,
+    /// Diff parsed packages against stored rows instead of rewriting whole branches.
+    /// Saves writes for small edits at the cost of extra reads.
+    pub diff_writes: bool,
+    /// Fetch commit dates along with `.SRCINFO` to populate `LastModified`.
+    pub commit_dates: bool,
+    /// How many times a failed batch is re-fetched before its branches are given up on.
+    pub batch_retries: u32,
+    /// `pkgver` patterns marking a VCS package's version as a placeholder.
+    pub version_placeholders: Vec<String>,
+    /// Machine-readable progress written to stdout, if any.
+    pub progress: Option<ProgressFormat>,
+    pub branch_list_source: BranchListSource,
+    /// Auxiliary rows (`sync_problems`, `branch_commit_history`) older than this many days are pruned at
+    /// the end of each sync. `0` keeps them forever.
+    pub prune_after_days: u32,
+    /// Keep every synced commit in `branch_commit_history`, not just the latest.
+    pub commit_history: bool,
+    /// Relation values longer than this many characters are skipped.
+    pub max_value_length: usize,
+    /// Stop fetching after the first batch that still fails after its retries.
+    /// Batches fetched before it are still committed.
+    pub fail_fast: bool,
+    /// Stop the sync once it has run this long. Batches written so far are kept and
+    /// the remaining branches are picked up by the next sync.
+    pub max_runtime: Option<Duration>,
+    /// Record added and removed packages and version or dependency changes in
+    /// `pkg_changes`, and report them as `package_changed` events.
+    pub track_package_changes: bool,
+    /// Log each batch's commits and GraphQL query before sending it.
+    pub dump_queries: bool,
+    /// GraphQL batches fetched at the same time.
+    pub concurrency: usize,
+    pub empty_srcinfo: EmptySrcinfoPolicy,
+    /// Attempts of each GitHub request failing with a 5xx status or a network error.
+    pub request_max_attempts: u32,
+    pub http_timeouts: HttpTimeouts,
+    pub upstream_repo: UpstreamRepo,
+    /// Where the AUR metadata dump is downloaded from at the start of each sync, to
+    /// fill `pkg_meta`. Not downloaded when `None`.
+    pub package_meta_url: Option<String>,
+    /// Syncs that may fail to fetch a branch at the same commit before it is skipped
+    /// until its commit changes. `0` retries forever.
+    pub failed_branch_max_retries: u32,
+}
+
+/// What a sync does with a branch whose .SRCINFO is empty or missing.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmptySrcinfoPolicy {
+    /// Store its commit without packages, it is fetched again only once it changes.
+    #[default]
+    Record,
+    /// Keep the previous commit and packages, so every sync fetches it again until a
+    /// .SRCINFO appears.
+    Retry,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProgressFormat {
+    /// One JSON `SyncEvent` per line
+    Json,
+}
+
+/// Progress reported to supervising processes with `--progress json`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SyncEvent<'a> {
+    Started {
+        total: usize,
+    },
+    BranchDone {
+        branch: &'a str,
+        processed: usize,
+        total: usize,
+    },
+    BranchFailed {
+        branch: &'a str,
+        error: &'a str,
+    },
+    PackageChanged(&'a PackageChange),
+    Finished {
+        processed: usize,
+        failed: usize,
+        packages: usize,
+    },
+}
+
+/// Restricts a sync to a subset of the mirror, e.g. to build specialized databases.
+#[derive(Debug, Default)]
+pub struct SyncFilter {
+    /// Only branches in this set are synced. `None` means all branches.
+    pub include_branches: Option<HashSet<String>>,
+    /// Only packages built for this architecture (or `any`) are indexed.
+    pub arch: Option<String>,
+}
+
+/// A branch whose `.SRCINFO` could not be fetched, even after retries.
+struct FailedBranch {
+    branch: String,
+    commit: String,
+    error: String,
 }
 
 struct SrcInfoTuple {
     branch: String,
     commit: String,
     srcinfo_text: String,
+    committed_at: Option<i64>,
 }
 
 impl Syncer {
-    pub fn new(app_state: AppState) -> Self {
-        let fetcher = AurFetcher::new(app_state.github_token);
+    pub fn new(app_state: AppState, options: SyncOptions) -> Self {
+        let fetcher = AurFetcher::new(app_state.github_token)
+            .with_commit_dates(options.commit_dates)
+            .with_branch_list_source(options.branch_list_source)
+            .with_dump_queries(options.dump_queries)
+            .with_max_attempts(options.request_max_attempts)
+            .with_timeouts(options.http_timeouts)
+            .with_upstream_repo(options.upstream_repo.clone());
         Self {
             db: app_state.db,
+            commit_cache: app_state.commit_cache,
+            status: app_state.sync_status,
             fetcher,
+            options,
         }
     }
 
     pub async fn sync(&self) -> Result<()> {
         info!("Starting sync operation...");
+        let _running = self.status.start();
+        let sync_started_at = Instant::now();
+        let deadline = self
+            .options
+            .max_runtime
+            .map(|budget| Instant::now() + budget);
 
         if self.fetcher.github_token().is_none() {
             warn!("⚠ No GitHub token configured. You may hit rate limits.");
         }
 
+        if let Some(url) = &self.options.package_meta_url {
+            // A separate source, so its failure must not hold back the packages
+            if let Err(e) = self.sync_package_meta(url).await {
+                error!("Failed to update package metadata: {:#}", e);
+            }
+        }
+
         info!("Fetching branch list from AUR Mirror...");
         // Fetch branch list
-        let branches = self.fetcher.fetch_branch_list().await?;
+        let mut branches = self.fetch_branch_list().await?;
+        if let Some(include_branches) = &self.options.filter.include_branches {
+            branches.retain(|branch, _| include_branches.contains(branch));
+        }
 
         info!(
             "Found {} branches, comparing to existing...",
             branches.len()
         );
-        let existing_commits = self.db.get_existing_commits().await?;
+        let existing_commits = if self.options.filter.include_branches.is_some() {
+            // Only look up the few branches that are synced
+            let names: Vec<String> = branches.keys().cloned().collect();
+            self.db.get_branch_commit_ids(&names).await?
+        } else {
+            self.db.get_existing_commits().await?
+        };
+        let vanished: Vec<(String, String)> = existing_commits
+            .iter()
+            .filter(|(branch, _)| !branches.contains_key(*branch))
+            .map(|(branch, commit)| (branch.clone(), commit.clone()))
+            .collect();
+        if branches.is_empty() && !vanished.is_empty() {
+            // More likely a broken branch list than the whole AUR being deleted
+            warn!("⚠ Empty branch list, not removing any branches");
+        } else if !vanished.is_empty() {
+            self.prune_vanished_branches(&vanished).await?;
+        }
+
+        // Failed branches keep their old commit, so they are fetched again below
+        // until they succeed or run out of retries
+        let failed_branches = self.db.get_failed_branches().await?;
+        if self.options.filter.include_branches.is_none() {
+            let gone: Vec<String> = failed_branches
+                .keys()
+                .filter(|branch| !branches.contains_key(*branch))
+                .cloned()
+                .collect();
+            if !gone.is_empty() {
+                self.db.forget_failed_branches(&gone).await?;
+            }
+        }
+        let max_retries = self.options.failed_branch_max_retries;
+        let mut retried = 0;
+        let mut given_up = 0;
         let to_process = branches
             .into_iter()
             .filter(|(branch, commit)| existing_commits.get(branch) != Some(commit))
+            .filter(|(branch, commit)| {
+                let Some((failed_commit, retries)) = failed_branches.get(branch) else {
+                    return true;
+                };
+                if max_retries > 0 && failed_commit == commit && *retries >= max_retries {
+                    given_up += 1;
+                    return false;
+                }
+                retried += 1;
+                true
+            })
             .collect::<Vec<_>>();
+        if retried > 0 {
+            info!("Retrying {} previously failed branches", retried);
+        }
+        if given_up > 0 {
+            warn!(
+                "⚠ Skipping {} branches that failed {} times at their current commit",
+                given_up, max_retries
+            );
+        }
 
-        info!("Need to process {} updated branches", to_process.len());
+        let total = to_process.len();
+        info!("Need to process {} updated branches", total);
+        self.status.set_total(total);
+        self.emit(SyncEvent::Started { total });
         if to_process.is_empty() {
             info!("All branches are up to date");
+            self.finalize(&[], true, sync_started_at.elapsed()).await?;
+            self.emit(SyncEvent::Finished {
+                processed: 0,
+                failed: 0,
+                packages: 0,
+            });
             return Ok(());
         }
 
+        let to_fetch = group_by_commit(to_process);
+        if to_fetch.len() < total {
+            info!(
+                "Fetching {} unique commits for {} branches",
+                to_fetch.len(),
+                total
+            );
+        }
+
         let (db_sender, mut db_receiver) = mpsc::channel::<SrcInfoTuple>(BATCH_SIZE * 2);
 
         let fetcher = self.fetcher.clone();
+        let batch_retries = self.options.batch_retries;
+        let fail_fast = self.options.fail_fast;
+        let dump_queries = self.options.dump_queries;
+        let concurrency = self.options.concurrency.max(1);
+        let batches = fetch_batches(to_fetch);
+        // Shared so the failures found so far survive aborting the task on timeout
+        let failed = Arc::new(Mutex::new(Vec::new()));
+        let fetch_failed = failed.clone();
         let fetch_task = tokio::spawn(async move {
-            for chunk in to_process.chunks(BATCH_SIZE) {
-                let commits = chunk.iter().map(|(_, commit)| commit.as_str());
-                match fetcher.fetch_srcinfo_batch(commits).await {
+            let mut fetched_branches = 0;
+            // Each batch is written in its own transaction, so they may complete in any order
+            let mut results = stream::iter(batches)
+                .map(|chunk| {
+                    let fetcher = fetcher.clone();
+                    async move {
+                        let result =
+                            fetch_batch(&fetcher, &chunk, batch_retries, dump_queries).await;
+                        (chunk, result)
+                    }
+                })
+                .buffer_unordered(concurrency);
+            while let Some((chunk, result)) = results.next().await {
+                fetched_branches += chunk
+                    .iter()
+                    .map(|(_, branches)| branches.len())
+                    .sum::<usize>();
+                match result {
                     Ok(srcinfo_data) => {
-                        for ((branch, commit), srcinfo_text) in chunk.iter().zip(srcinfo_data) {
-                            if let Err(e) = db_sender
-                                .send(SrcInfoTuple {
-                                    branch: branch.clone(),
-                                    commit: commit.clone(),
-                                    srcinfo_text,
-                                })
-                                .await
-                            {
+                        for tuple in fan_out(&chunk, srcinfo_data) {
+                            if let Err(e) = db_sender.send(tuple).await {
                                 error!("Failed to send srcinfo to database task: {}", e);
                                 break;
                             }
@@ -82,14 +331,42 @@ impl Syncer {
                     }
                     Err(e) => {
                         error!("Error fetching batch: {}", e);
+                        let error = e.to_string();
+                        fetch_failed.lock().unwrap().extend(chunk.iter().flat_map(
+                            |(commit, branches)| {
+                                branches.iter().map(|branch| FailedBranch {
+                                    branch: branch.clone(),
+                                    commit: commit.clone(),
+                                    error: error.clone(),
+                                })
+                            },
+                        ));
+                        if fail_fast {
+                            break;
+                        }
                     }
                 }
             }
+            // Batches still in flight are cancelled
+            drop(results);
+            let skipped = total - fetched_branches;
+            if skipped > 0 {
+                warn!(
+                    "Aborting sync after a failed batch (--fail-fast), {} branches were not fetched",
+                    skipped
+                );
+            }
             // Close the sender to signal we're done
             drop(db_sender);
         });
 
+        let started_at = Instant::now();
         let mut processed_packages = 0;
+        let mut processed_branches = 0;
+        let mut timed_out = false;
+        let mut write_stats = WriteStats::default();
+        let mut changed_packages = 0;
+        let mut changes_batch: Vec<PackageChange> = Vec::new();
         let mut srcinfo_batch: Vec<SrcInfoTuple> = Vec::with_capacity(BATCH_SIZE);
         let mut packages_batch: Vec<DatabasePackageDetails> =
             Vec::with_capacity((BATCH_SIZE + (BATCH_SIZE + 3)) >> 2);
@@ -97,77 +374,564 @@ impl Syncer {
             srcinfo_batch.clear();
             packages_batch.clear();
 
-            let count = db_receiver.recv_many(&mut srcinfo_batch, BATCH_SIZE).await;
+            // Only wait between transactions, so stopping never leaves one open
+            let count = tokio::select! {
+                count = db_receiver.recv_many(&mut srcinfo_batch, BATCH_SIZE) => count,
+                _ = sleep_until_deadline(deadline) => {
+                    timed_out = true;
+                    break;
+                }
+            };
             if count == 0 {
                 break; // Channel closed
             }
 
-            let mut tx = self.db.begin_transaction().await?;
-            for SrcInfoTuple {
+            self.write_batch(
+                &srcinfo_batch,
+                &mut packages_batch,
+                &mut changes_batch,
+                &mut write_stats,
+            )
+            .await?;
+            processed_packages += packages_batch.len();
+
+            self.status.add_processed(srcinfo_batch.len());
+            for change in changes_batch.drain(..) {
+                self.emit(SyncEvent::PackageChanged(&change));
+                changed_packages += 1;
+            }
+            for SrcInfoTuple { branch, .. } in srcinfo_batch.iter() {
+                processed_branches += 1;
+                self.emit(SyncEvent::BranchDone {
+                    branch,
+                    processed: processed_branches,
+                    total,
+                });
+            }
+
+            // Branches still queued are more batches like the ones so far
+            let elapsed = started_at.elapsed();
+            let remaining = total - processed_branches;
+            let eta = elapsed.mul_f64(remaining as f64 / processed_branches as f64);
+            info!(
+                "Processed {}/{} branches ({:.1}%), {} packages, ETA {}",
+                processed_branches,
+                total,
+                processed_branches as f64 * 100.0 / total as f64,
+                processed_packages,
+                format_duration(eta)
+            );
+        }
+
+        if timed_out {
+            fetch_task.abort();
+            // Wait for it to stop, so no failure is added after they are collected
+            let _ = fetch_task.await;
+            warn!(
+                "⚠ Sync exceeded its maximum runtime of {:?}, stopping after {} of {} branches",
+                self.options.max_runtime.unwrap_or_default(),
+                processed_branches,
+                total
+            );
+        } else {
+            fetch_task.await?;
+        }
+        let failed = std::mem::take(&mut *failed.lock().unwrap());
+        if self.options.track_package_changes {
+            info!("Recorded {} package changes", changed_packages);
+        }
+        self.finalize(
+            &failed,
+            failed.is_empty() && !timed_out,
+            sync_started_at.elapsed(),
+        )
+        .await?;
+        for FailedBranch { branch, error, .. } in &failed {
+            self.emit(SyncEvent::BranchFailed { branch, error });
+        }
+        self.emit(SyncEvent::Finished {
+            processed: processed_branches,
+            failed: failed.len(),
+            packages: processed_packages,
+        });
+
+        if self.options.diff_writes {
+            info!(
+                "Diff writes: {} rows written, {} unchanged relation rows skipped",
+                write_stats.written, write_stats.unchanged
+            );
+        }
+
+        if !failed.is_empty() {
+            error!("❌ {} branches failed to sync:", failed.len());
+            for FailedBranch {
                 branch,
                 commit,
-                srcinfo_text,
-            } in srcinfo_batch.iter()
+                error,
+            } in &failed
             {
-                self.db.clear_index_with_tx(&mut tx, branch).await?;
-                self.db
-                    .update_branch_commit_with_tx(&mut tx, branch, commit)
-                    .await?;
+                error!("  {} ({}): {}", branch, &commit[..8], error);
+            }
+            return Err(anyhow!(
+                "Sync incomplete: {} branches failed. Processed {} packages",
+                failed.len(),
+                processed_packages
+            ));
+        }
 
-                let branch_packages = srcinfo_to_db_models(branch, commit, srcinfo_text);
+        info!(
+            "✅ Sync completed successfully. Processed {} packages",
+            processed_packages
+        );
+        Ok(())
+    }
 
-                let before_len = packages_batch.len();
-                packages_batch.extend(branch_packages);
-                if before_len == packages_batch.len() {
-                    warn!(
-                        "⚠ No packages found for branch {} ({})",
-                        branch,
-                        &commit[..8]
-                    );
+    /// Deletes the packages of branches that disappeared from the mirror, i.e. packages
+    /// deleted from the AUR, in one transaction.
+    async fn prune_vanished_branches(&self, vanished: &[(String, String)]) -> Result<()> {
+        let mut tx = self.db.begin_transaction().await?;
+        let mut changes = Vec::new();
+        for (branch, commit) in vanished {
+            if self.options.track_package_changes {
+                let old_states = self.db.get_package_states_with_tx(&mut tx, branch).await?;
+                for change in package_changes(branch, commit, old_states, &[]) {
+                    self.db
+                        .record_package_change_with_tx(&mut tx, &change)
+                        .await?;
+                    changes.push(change);
                 }
             }
+            self.db.clear_index_with_tx(&mut tx, branch).await?;
+            self.db
+                .delete_branch_commit_with_tx(&mut tx, branch)
+                .await?;
+        }
+        tx.commit().await?;
+
+        for (branch, _) in vanished {
+            self.commit_cache.remove(branch);
+        }
+        for change in &changes {
+            self.emit(SyncEvent::PackageChanged(change));
+        }
+        info!("Removed {} branches deleted upstream", vanished.len());
+        Ok(())
+    }
+
+    /// Replaces `pkg_meta` with the current AUR metadata dump.
+    async fn sync_package_meta(&self, url: &str) -> Result<()> {
+        info!("Fetching package metadata from {}...", url);
+        let packages = self.fetcher.fetch_package_meta(url).await?;
+        self.db.replace_package_meta(&packages).await?;
+        info!("Stored metadata of {} packages", packages.len());
+        Ok(())
+    }
+
+    /// Records permanently failed branches and applies the retention policy. A
+    /// `completed` sync also becomes the last successful one, taking `duration`.
+    async fn finalize(
+        &self,
+        failed: &[FailedBranch],
+        completed: bool,
+        duration: Duration,
+    ) -> Result<()> {
+        let mut tx = self.db.begin_transaction().await?;
+        if completed {
+            self.db
+                .set_last_sync_with_tx(&mut tx, Utc::now().timestamp(), duration)
+                .await?;
+        }
+        for FailedBranch {
+            branch,
+            commit,
+            error,
+        } in failed
+        {
+            self.db
+                .record_problem_with_tx(&mut tx, branch, commit, PROBLEM_FETCH_FAILED, error)
+                .await?;
+            self.db
+                .record_failed_branch_with_tx(&mut tx, branch, commit, error)
+                .await?;
+        }
+        if let Some(before) = prune_cutoff(Utc::now(), self.options.prune_after_days) {
+            let pruned = self.db.prune_with_tx(&mut tx, before.timestamp()).await?;
+            if pruned > 0 {
+                info!(
+                    "Pruned {} problem and commit history rows older than {} days",
+                    pruned, self.options.prune_after_days
+                );
+            }
+        }
+        tx.commit().await?;
+        Ok(())
+    }
 
-            if !packages_batch.is_empty() {
+    /// Indexes a batch of fetched branches in one transaction, and only then updates the
+    /// commit cache.
+    async fn write_batch(
+        &self,
+        srcinfo_batch: &[SrcInfoTuple],
+        packages_batch: &mut Vec<DatabasePackageDetails>,
+        changes_batch: &mut Vec<PackageChange>,
+        write_stats: &mut WriteStats,
+    ) -> Result<()> {
+        let mut tx = self.db.begin_transaction().await?;
+        let mut retried = HashSet::new();
+        for SrcInfoTuple {
+            branch,
+            commit,
+            srcinfo_text,
+            committed_at,
+        } in srcinfo_batch.iter()
+        {
+            if srcinfo_text.trim().is_empty()
+                && self.options.empty_srcinfo == EmptySrcinfoPolicy::Retry
+            {
+                warn!(
+                    "⚠ Empty .SRCINFO for branch {} ({}), retrying next sync",
+                    branch,
+                    &commit[..8]
+                );
+                self.db
+                    .record_problem_with_tx(
+                        &mut tx,
+                        branch,
+                        commit,
+                        PROBLEM_NO_PACKAGES,
+                        "Empty .SRCINFO, will retry",
+                    )
+                    .await?;
+                retried.insert(branch.as_str());
+                continue;
+            }
+            let mut branch_packages: Vec<_> = srcinfo_to_db_models(
+                branch,
+                commit,
+                srcinfo_text,
+                *committed_at,
+                self.options.filter.arch.as_deref(),
+                &self.options.version_placeholders,
+            )
+            .collect();
+            for pkg in branch_packages.iter_mut() {
+                drop_overlong_values(pkg, self.options.max_value_length);
+            }
+            if branch_packages.is_empty() {
+                warn!(
+                    "⚠ No packages found for branch {} ({})",
+                    branch,
+                    &commit[..8]
+                );
                 self.db
-                    .update_index_with_tx(&mut tx, &packages_batch)
+                    .record_problem_with_tx(
+                        &mut tx,
+                        branch,
+                        commit,
+                        PROBLEM_NO_PACKAGES,
+                        "No packages found in .SRCINFO",
+                    )
                     .await?;
-                processed_packages += packages_batch.len();
             }
 
-            tx.commit().await?;
+            if self.options.track_package_changes {
+                let old_states = self.db.get_package_states_with_tx(&mut tx, branch).await?;
+                for change in package_changes(branch, commit, old_states, &branch_packages) {
+                    self.db
+                        .record_package_change_with_tx(&mut tx, &change)
+                        .await?;
+                    changes_batch.push(change);
+                }
+            }
 
-            info!("Processed {} packages", processed_packages);
+            if self.options.diff_writes {
+                *write_stats += self
+                    .db
+                    .diff_index_with_tx(&mut tx, branch, &branch_packages)
+                    .await?;
+            } else {
+                self.db.clear_index_with_tx(&mut tx, branch).await?;
+            }
+            self.db
+                .update_branch_commit_with_tx(&mut tx, branch, commit, *committed_at)
+                .await?;
+            if self.options.commit_history {
+                self.db
+                    .record_commit_history_with_tx(&mut tx, branch, commit)
+                    .await?;
+            }
+            packages_batch.extend(branch_packages);
         }
 
-        fetch_task.await?;
+        if !self.options.diff_writes && !packages_batch.is_empty() {
+            self.db
+                .update_index_with_tx(&mut tx, packages_batch)
+                .await?;
+        }
 
-        info!(
-            "✅ Sync completed successfully. Processed {} packages",
-            processed_packages
+        tx.commit().await?;
+        // Only now may readers in this process see the new commits
+        self.commit_cache.extend(
+            srcinfo_batch
+                .iter()
+                .filter(|tuple| !retried.contains(tuple.branch.as_str()))
+                .map(|tuple| (tuple.branch.clone(), tuple.commit.clone())),
         );
         Ok(())
     }
+
+    fn emit(&self, event: SyncEvent) {
+        match self.options.progress {
+            Some(ProgressFormat::Json) => match serde_json::to_string(&event) {
+                Ok(line) => println!("{}", line),
+                Err(e) => error!("Failed to serialize progress event: {}", e),
+            },
+            None => {}
+        }
+    }
+
+    /// Fetches the branch list, reusing the copy cached in `sync_meta` when upstream
+    /// reports it unchanged via its ETag.
+    async fn fetch_branch_list(&self) -> Result<HashMap<String, String>> {
+        let cached_etag = self.db.get_meta(META_BRANCH_LIST_ETAG).await?;
+        let cached_list = match cached_etag {
+            Some(_) => self.db.get_meta(META_BRANCH_LIST).await?,
+            None => None,
+        };
+
+        let etag = cached_list.as_ref().and(cached_etag.as_deref());
+        match self.fetcher.fetch_branch_list(etag).await? {
+            BranchList::NotModified => {
+                info!("Branch list is unchanged since the last sync, using cached copy");
+                let cached_list = cached_list.unwrap_or_default();
+                Ok(serde_json::from_str(&cached_list)?)
+            }
+            BranchList::Fetched { branches, etag } => {
+                if let Some(etag) = etag {
+                    self.db
+                        .set_meta(META_BRANCH_LIST, &serde_json::to_string(&branches)?)
+                        .await?;
+                    self.db.set_meta(META_BRANCH_LIST_ETAG, &etag).await?;
+                } else {
+                    // Without an ETag the cached copy can never be reused
+                    self.db.delete_meta(META_BRANCH_LIST).await?;
+                    self.db.delete_meta(META_BRANCH_LIST_ETAG).await?;
+                }
+                self.db.set_branch_list_count(branches.len()).await?;
+                Ok(branches)
+            }
+        }
+    }
+}
+
+/// Whole seconds as e.g. `1h02m03s`, `2m03s` or `3s`.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{}h{:02}m{:02}s", hours, minutes, secs)
+    } else if minutes > 0 {
+        format!("{}m{:02}s", minutes, secs)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// Sleeps until `deadline`, or forever if there is none.
+async fn sleep_until_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Guards the database against pathological or malicious values.
+fn drop_overlong_values(pkg: &mut DatabasePackageDetails, max_len: usize) {
+    let too_long = |value: &String| value.chars().count() > max_len;
+    for relation in Relation::ALL {
+        for value in pkg.relation(relation).iter().filter(|v| too_long(v)) {
+            warn!(
+                "⚠ Skipping {} value of {} ({}) with {} characters",
+                relation.name(),
+                pkg.info.pkg_name,
+                pkg.info.branch,
+                value.chars().count()
+            );
+        }
+        pkg.relation_mut(relation).retain(|v| !too_long(v));
+        if let Some(values) = pkg.arch_values.get_mut(&relation) {
+            values.retain(|(_, v)| !too_long(v));
+        }
+    }
+}
+
+/// Compares the stored state of a branch's packages with the newly parsed ones.
+fn package_changes(
+    branch: &str,
+    commit_id: &str,
+    mut old_states: HashMap<String, PackageState>,
+    packages: &[DatabasePackageDetails],
+) -> Vec<PackageChange> {
+    let changed_at = Utc::now().timestamp();
+    let change =
+        |pkg_name: &str, kind: &str, old_version: Option<String>, new_version: Option<&str>| {
+            PackageChange {
+                branch: branch.to_string(),
+                pkg_name: pkg_name.to_string(),
+                kind: kind.to_string(),
+                old_version,
+                new_version: new_version.map(str::to_string),
+                commit_id: commit_id.to_string(),
+                changed_at,
+            }
+        };
+
+    let mut changes = Vec::new();
+    for pkg in packages {
+        let new_version = &pkg.info.version;
+        let Some(old) = old_states.remove(&pkg.info.pkg_name) else {
+            changes.push(change(
+                &pkg.info.pkg_name,
+                CHANGE_ADDED,
+                None,
+                Some(new_version),
+            ));
+            continue;
+        };
+        let kind = if &old.version != new_version {
+            CHANGE_VERSION
+        } else {
+            let new_depends: HashSet<_> = Relation::DEPENDENCIES
+                .into_iter()
+                .flat_map(|relation| {
+                    pkg.relation(relation)
+                        .iter()
+                        .map(move |v| (relation, v.clone()))
+                })
+                .collect();
+            if new_depends == old.depends {
+                continue;
+            }
+            CHANGE_DEPENDS
+        };
+        changes.push(change(
+            &pkg.info.pkg_name,
+            kind,
+            Some(old.version),
+            Some(new_version),
+        ));
+    }
+    for (pkg_name, old) in old_states {
+        changes.push(change(&pkg_name, CHANGE_REMOVED, Some(old.version), None));
+    }
+    changes
+}
+
+/// Groups branches by the commit they point at. Branches sharing a commit share its
+/// .SRCINFO, so each commit is only fetched once.
+fn group_by_commit(branches: Vec<(String, String)>) -> Vec<(String, Vec<String>)> {
+    branches
+        .into_iter()
+        .map(|(branch, commit)| (commit, branch))
+        .into_group_map()
+        .into_iter()
+        .collect()
+}
+
+/// Splits the commits to fetch into batches of at most `BATCH_SIZE`.
+fn fetch_batches(to_fetch: Vec<(String, Vec<String>)>) -> Vec<Vec<(String, Vec<String>)>> {
+    to_fetch
+        .into_iter()
+        .chunks(BATCH_SIZE)
+        .into_iter()
+        .map(Iterator::collect)
+        .collect()
+}
+
+/// The fetched .SRCINFO of each commit in `chunk`, once for every branch at it.
+fn fan_out(
+    chunk: &[(String, Vec<String>)],
+    srcinfo_data: Vec<(String, Option<i64>)>,
+) -> impl Iterator<Item = SrcInfoTuple> + '_ {
+    chunk
+        .iter()
+        .zip(srcinfo_data)
+        .flat_map(|((commit, branches), (srcinfo_text, committed_at))| {
+            branches.iter().map(move |branch| SrcInfoTuple {
+                branch: branch.clone(),
+                commit: commit.clone(),
+                srcinfo_text: srcinfo_text.clone(),
+                committed_at,
+            })
+        })
+}
+
+/// Fetches one batch of commits, retrying failed requests `batch_retries` times.
+async fn fetch_batch(
+    fetcher: &AurFetcher,
+    chunk: &[(String, Vec<String>)],
+    batch_retries: u32,
+    dump_queries: bool,
+) -> Result<Vec<(String, Option<i64>)>> {
+    if dump_queries {
+        let commits = chunk
+            .iter()
+            .map(|(commit, branches)| format!("{} ({})", commit, branches.join(", ")))
+            .join(", ");
+        info!("Fetching batch of {} commits: {}", chunk.len(), commits);
+    }
+    let mut attempt = 0;
+    loop {
+        let commits = chunk.iter().map(|(commit, _)| commit.as_str());
+        match fetcher.fetch_srcinfo_batch(commits).await {
+            Err(e) if attempt < batch_retries => {
+                attempt += 1;
+                warn!(
+                    "Error fetching batch, retrying ({}/{}): {}",
+                    attempt, batch_retries, e
+                );
+                sleep(Duration::from_secs(2 * attempt as u64)).await;
+            }
+            result => return Ok(result?.collect()),
+        }
+    }
 }
 
-fn srcinfo_to_db_models(
+fn srcinfo_to_db_models<'a>(
     branch: &str,
     commit_id: &str,
     srcinfo: &str,
-) -> impl Iterator<Item = DatabasePackageDetails> {
+    committed_at: Option<i64>,
+    arch: Option<&str>,
+    version_placeholders: &'a [String],
+) -> impl Iterator<Item = DatabasePackageDetails> + 'a {
     let branch = branch.to_string();
     let commit_id = commit_id.to_string();
+    let arch = arch.map(|s| s.to_string());
     ParsedSrcInfo::parse(srcinfo)
         .into_iter()
+        .filter(move |pkg| arch.as_deref().is_none_or(|arch| pkg.supports_arch(arch)))
         .map(move |pkg| DatabasePackageDetails {
             info: DatabasePackageInfo {
                 branch: branch.clone(),
                 commit_id: commit_id.clone(),
                 pkg_name: pkg.pkgname.clone(),
                 pkg_desc: pkg.first_prop("pkgdesc").map(|s| s.to_string()),
-                version: pkg.version(),
+                version: pkg.try_version().unwrap_or_else(|| {
+                    let version = pkg.version();
+                    warn!(
+                        "⚠ Missing or malformed version of {} in branch {}, using {}",
+                        pkg.pkgname, branch, version
+                    );
+                    version
+                }),
                 url: pkg.first_prop("url").map(|s| s.to_string()),
+                last_modified: committed_at,
+                version_is_placeholder: pkg.has_placeholder_version(version_placeholders),
+                meta: None,
             },
             groups: pkg.prop("groups"),
+            keywords: pkg.keywords(),
             depends: pkg.flatten_arch_prop("depends"),
             make_depends: pkg.flatten_arch_prop("makedepends"),
             opt_depends: pkg.flatten_arch_prop("optdepends"),
@@ -175,5 +939,325 @@ fn srcinfo_to_db_models(
             provides: pkg.flatten_arch_prop("provides"),
             conflicts: pkg.flatten_arch_prop("conflicts"),
             replaces: pkg.flatten_arch_prop("replaces"),
+            arch_values: arch_values(&pkg),
+        })
+}
+
+/// The values of each arch-specific relation with the architecture of the key they
+/// came from, empty for the unsuffixed one.
+fn arch_values(pkg: &ParsedSrcInfo) -> HashMap<Relation, Vec<(String, String)>> {
+    Relation::ARCH_SPECIFIC
+        .into_iter()
+        .map(|relation| {
+            let values = pkg
+                .arch_props(relation.name())
+                .into_iter()
+                .flat_map(|(arch, values)| {
+                    let arch = arch.unwrap_or_default();
+                    values.into_iter().map(move |value| (arch.clone(), value))
+                })
+                .sorted()
+                .dedup()
+                .collect();
+            (relation, values)
         })
+        .collect()
+}
+
+/// Timestamp before which auxiliary rows are pruned, or `None` to keep them forever,
+/// either because pruning is disabled or the cutoff predates the representable range.
+fn prune_cutoff(now: DateTime<Utc>, prune_after_days: u32) -> Option<DateTime<Utc>> {
+    if prune_after_days == 0 {
+        return None;
+    }
+    now.checked_sub_signed(TimeDelta::days(prune_after_days.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tests::{insert_packages, test_db, test_package, TestDb};
+    use crate::types::{MatchMode, SearchType};
+
+    #[tokio::test]
+    async fn branches_sharing_a_commit_are_fetched_once() {
+        // Enough branches for several batches, three of them per commit
+        let commit = |i: usize| format!("{:040x}", i / 3);
+        let branches: Vec<_> = (0..BATCH_SIZE * 6)
+            .map(|i| (format!("pkg{i}"), commit(i)))
+            .collect();
+        let batches = fetch_batches(group_by_commit(branches.clone()));
+        assert!(batches.iter().all(|batch| batch.len() <= BATCH_SIZE));
+        let fetched_commits: Vec<_> = batches.iter().flatten().map(|(c, _)| c).collect();
+        assert_eq!(fetched_commits.len(), BATCH_SIZE * 2);
+        assert!(fetched_commits.iter().all_unique());
+        let mut fetched_branches: Vec<_> = batches
+            .iter()
+            .flatten()
+            .flat_map(|(c, bs)| bs.iter().map(move |b| (b.clone(), c.clone())))
+            .collect();
+        fetched_branches.sort();
+        let mut expected = branches;
+        expected.sort();
+        assert_eq!(fetched_branches, expected);
+
+        // One result per commit, as returned by a single batch fetch
+        let to_fetch = group_by_commit(vec![
+            ("foo".to_string(), OLD_COMMIT.to_string()),
+            ("foo-git".to_string(), OLD_COMMIT.to_string()),
+            ("bar".to_string(), NEW_COMMIT.to_string()),
+        ]);
+        let fetched: Vec<_> = to_fetch
+            .iter()
+            .map(|(commit, _)| {
+                let (name, committed_at) = if commit == OLD_COMMIT {
+                    ("foo", 1)
+                } else {
+                    ("bar", 2)
+                };
+                let srcinfo =
+                    format!("pkgbase = {name}\npkgver = 1\npkgrel = 1\npkgname = {name}\n");
+                (srcinfo, Some(committed_at))
+            })
+            .collect();
+        let mut tuples: Vec<_> = fan_out(&to_fetch, fetched).collect();
+        tuples.sort_by(|a, b| a.branch.cmp(&b.branch));
+        assert_eq!(
+            tuples
+                .iter()
+                .map(|tuple| (
+                    tuple.branch.as_str(),
+                    tuple.commit.as_str(),
+                    tuple.committed_at
+                ))
+                .collect::<Vec<_>>(),
+            [
+                ("bar", NEW_COMMIT, Some(2)),
+                ("foo", OLD_COMMIT, Some(1)),
+                ("foo-git", OLD_COMMIT, Some(1)),
+            ]
+        );
+
+        let db = test_db().await;
+        let syncer = test_syncer(&db, SyncOptions::default());
+        syncer
+            .write_batch(
+                &tuples,
+                &mut Vec::new(),
+                &mut Vec::new(),
+                &mut WriteStats::default(),
+            )
+            .await
+            .unwrap();
+        let mut indexed = db
+            .get_existing_commits()
+            .await
+            .unwrap()
+            .into_iter()
+            .collect_vec();
+        indexed.sort();
+        assert_eq!(
+            indexed,
+            [
+                ("bar", NEW_COMMIT),
+                ("foo", OLD_COMMIT),
+                ("foo-git", OLD_COMMIT)
+            ]
+            .map(|(branch, commit)| (branch.to_string(), commit.to_string()))
+        );
+        let details = db
+            .get_package_details(&["foo".to_string()], &[], false, None, None)
+            .await
+            .unwrap();
+        assert_eq!(details.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn keywords_round_trip() {
+        let srcinfo = "pkgbase = foo\n\tpkgver = 1\n\tpkgrel = 1\n\tkeywords = editor text\n\npkgname = foo\n";
+        let packages: Vec<_> =
+            srcinfo_to_db_models("foo", "c1", srcinfo, None, None, &[]).collect();
+        assert_eq!(packages[0].keywords, ["editor", "text"]);
+
+        let db = test_db().await;
+        insert_packages(&db, &packages).await;
+        let details = db
+            .get_package_details(
+                &["foo".to_string()],
+                &[Relation::Keywords],
+                false,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(details[0].keywords, ["editor", "text"]);
+        let found = db
+            .search_packages(SearchType::Keywords, "text", MatchMode::Exact, None, None)
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].pkg_name, "foo");
+    }
+
+    const OLD_COMMIT: &str = "1111111111111111111111111111111111111111";
+    const NEW_COMMIT: &str = "2222222222222222222222222222222222222222";
+
+    fn test_syncer(db: &TestDb, options: SyncOptions) -> Syncer {
+        let app_state = AppState {
+            db: db.db.clone(),
+            github_token: None,
+            commit_cache: CommitCache::default(),
+            sync_status: SyncStatus::default(),
+        };
+        Syncer::new(app_state, options)
+    }
+
+    /// A syncer with branch `foo` indexed at `OLD_COMMIT`, which is then synced to
+    /// `NEW_COMMIT` with the given .SRCINFO.
+    async fn sync_foo(policy: EmptySrcinfoPolicy, srcinfo_text: &str) -> (TestDb, Syncer) {
+        let db = test_db().await;
+        let mut pkg = test_package("foo", "foo");
+        pkg.info.commit_id = OLD_COMMIT.to_string();
+        insert_packages(&db, &[pkg]).await;
+        let options = SyncOptions {
+            empty_srcinfo: policy,
+            ..Default::default()
+        };
+        let syncer = test_syncer(&db, options);
+        let batch = [SrcInfoTuple {
+            branch: "foo".to_string(),
+            commit: NEW_COMMIT.to_string(),
+            srcinfo_text: srcinfo_text.to_string(),
+            committed_at: None,
+        }];
+        syncer
+            .write_batch(
+                &batch,
+                &mut Vec::new(),
+                &mut Vec::new(),
+                &mut WriteStats::default(),
+            )
+            .await
+            .unwrap();
+        (db, syncer)
+    }
+
+    async fn problem_count(db: &DatabaseOps) -> i64 {
+        db.table_counts().await.unwrap()["sync_problems"]
+    }
+
+    #[tokio::test]
+    async fn empty_srcinfo_is_recorded_by_default() {
+        for srcinfo in ["", "  \n\t\n"] {
+            let (db, syncer) = sync_foo(EmptySrcinfoPolicy::Record, srcinfo).await;
+            assert_eq!(
+                db.get_branch_commit_id("foo").await.unwrap().as_deref(),
+                Some(NEW_COMMIT)
+            );
+            assert_eq!(db.count_packages().await.unwrap(), 0);
+            assert_eq!(problem_count(&db).await, 1);
+            assert_eq!(syncer.commit_cache.get("foo").as_deref(), Some(NEW_COMMIT));
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_srcinfo_is_retried_with_the_retry_policy() {
+        for srcinfo in ["", "  \n\t\n"] {
+            let (db, syncer) = sync_foo(EmptySrcinfoPolicy::Retry, srcinfo).await;
+            assert_eq!(
+                db.get_branch_commit_id("foo").await.unwrap().as_deref(),
+                Some(OLD_COMMIT)
+            );
+            assert_eq!(db.count_packages().await.unwrap(), 1);
+            assert_eq!(problem_count(&db).await, 1);
+            assert_eq!(syncer.commit_cache.get("foo"), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_policy_indexes_non_empty_srcinfo() {
+        let srcinfo = "pkgbase = foo\n\tpkgver = 2\n\tpkgrel = 1\n\npkgname = foo\n";
+        let (db, _syncer) = sync_foo(EmptySrcinfoPolicy::Retry, srcinfo).await;
+        assert_eq!(
+            db.get_branch_commit_id("foo").await.unwrap().as_deref(),
+            Some(NEW_COMMIT)
+        );
+        assert_eq!(
+            db.get_branch_version("foo").await.unwrap().as_deref(),
+            Some("2-1")
+        );
+        assert_eq!(problem_count(&db).await, 0);
+    }
+
+    #[test]
+    fn prune_cutoff_subtracts_days() {
+        let now = Utc::now();
+        assert_eq!(prune_cutoff(now, 0), None);
+        assert_eq!(prune_cutoff(now, 30), Some(now - TimeDelta::days(30)));
+    }
+
+    #[test]
+    fn prune_cutoff_out_of_range_keeps_forever() {
+        assert_eq!(prune_cutoff(Utc::now(), u32::MAX), None);
+    }
+
+    /// Readers racing a sync must never see a cached commit the database does not have
+    /// yet, nor a package whose version belongs to another commit than its row's.
+    #[tokio::test]
+    async fn commit_cache_never_runs_ahead_of_the_database() {
+        const ROUNDS: u64 = 50;
+        let db = test_db().await;
+        let syncer = test_syncer(&db, SyncOptions::default());
+        let commit = |round: u64| format!("{:040}", round);
+
+        let reader = {
+            let (db, cache) = (db.db.clone(), syncer.commit_cache.clone());
+            tokio::spawn(async move {
+                loop {
+                    let cached = cache.get("foo");
+                    let stored = db.get_branch_commit_id("foo").await.unwrap();
+                    if let Some(cached) = &cached {
+                        let cached: u64 = cached.parse().unwrap();
+                        let stored: u64 = stored.unwrap().parse().unwrap();
+                        assert!(cached <= stored, "cache at {} before db", cached);
+                    }
+                    let details = db
+                        .get_package_details(&["foo".to_string()], &[], false, None, None)
+                        .await
+                        .unwrap();
+                    for pkg in &details {
+                        let round: u64 = pkg.info.commit_id.parse().unwrap();
+                        assert_eq!(pkg.info.version, format!("{}-1", round));
+                    }
+                    if cached == Some(commit(ROUNDS)) {
+                        break;
+                    }
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+
+        for round in 1..=ROUNDS {
+            let batch = [SrcInfoTuple {
+                branch: "foo".to_string(),
+                commit: commit(round),
+                srcinfo_text: format!(
+                    "pkgbase = foo\n\tpkgver = {round}\n\tpkgrel = 1\n\npkgname = foo\n"
+                ),
+                committed_at: None,
+            }];
+            syncer
+                .write_batch(
+                    &batch,
+                    &mut Vec::new(),
+                    &mut Vec::new(),
+                    &mut WriteStats::default(),
+                )
+                .await
+                .unwrap();
+            tokio::task::yield_now().await;
+        }
+        reader.await.unwrap();
+    }
 }
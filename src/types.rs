@@ -2,19 +2,22 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct GqlFetchSrcInfoResponse {
-    pub data: Option<GqlFetchSrcInfoData>,
+pub struct GqlResponse<T> {
+    pub data: Option<T>,
     pub errors: Option<Vec<GraphQLError>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GqlFetchSrcInfoData {
-    pub repository: HashMap<String, GqlFetchSrcInfoObject>,
+    pub repository: HashMap<String, Option<GqlFetchSrcInfoObject>>,
 }
 
+/// Either a `.SRCINFO` blob (`text`) or a commit (`committedDate`).
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GqlFetchSrcInfoObject {
-    pub text: String,
+    pub text: Option<String>,
+    #[serde(rename = "committedDate")]
+    pub committed_date: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -22,7 +25,44 @@ pub struct GraphQLError {
     pub message: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GqlBranchRefsData {
+    pub repository: GqlBranchRefsRepository,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GqlBranchRefsRepository {
+    pub refs: GqlRefConnection,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GqlRefConnection {
+    #[serde(rename = "pageInfo")]
+    pub page_info: GqlPageInfo,
+    pub nodes: Vec<GqlRef>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GqlPageInfo {
+    #[serde(rename = "hasNextPage")]
+    pub has_next_page: bool,
+    #[serde(rename = "endCursor")]
+    pub end_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GqlRef {
+    pub name: String,
+    pub target: GqlRefTarget,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GqlRefTarget {
+    pub oid: String,
+}
+
+/// RPC response envelope, shared by the server and clients parsing its output.
+#[derive(Debug, Serialize, Deserialize)]
 pub struct RpcResponse<T> {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<String>,
@@ -32,9 +72,12 @@ pub struct RpcResponse<T> {
     #[serde(rename = "type")]
     pub response_type: String,
     pub version: Option<u32>,
+    /// Number of results before pagination, only present on paginated responses.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct RpcPackageInfo {
     #[serde(rename = "ID")]
     pub id: u32,
@@ -64,9 +107,21 @@ pub struct RpcPackageInfo {
     pub last_modified: u64,
     #[serde(rename = "OutOfDate")]
     pub out_of_date: Option<String>,
+    /// Extension (`ext=depends_count`), not part of the aurweb schema.
+    #[serde(rename = "DependsCount", skip_serializing_if = "Option::is_none")]
+    pub depends_count: Option<u32>,
+    /// Extension (`ext=version_placeholder`), not part of the aurweb schema.
+    #[serde(
+        rename = "VersionIsPlaceholder",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub version_is_placeholder: Option<bool>,
+    /// Extension (`ext=aur_page`), not part of the aurweb schema.
+    #[serde(rename = "AURPage", skip_serializing_if = "Option::is_none")]
+    pub aur_page: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct RpcPackageDetails {
     #[serde(rename = "ID")]
     pub id: u32,
@@ -120,9 +175,18 @@ pub struct RpcPackageDetails {
     pub keywords: Vec<String>,
     #[serde(rename = "CoMaintainers")]
     pub co_maintainers: Vec<String>,
+    /// Extension (`ext=version_placeholder`), not part of the aurweb schema.
+    #[serde(
+        rename = "VersionIsPlaceholder",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub version_is_placeholder: Option<bool>,
+    /// Extension (`ext=aur_page`), not part of the aurweb schema.
+    #[serde(rename = "AURPage", skip_serializing_if = "Option::is_none")]
+    pub aur_page: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct DatabasePackageInfo {
     pub branch: String,
     pub commit_id: String,
@@ -130,9 +194,36 @@ pub struct DatabasePackageInfo {
     pub pkg_desc: Option<String>,
     pub version: String,
     pub url: Option<String>,
+    /// Unix timestamp of the branch's commit, if known.
+    pub last_modified: Option<i64>,
+    /// A VCS package whose `pkgver` was never bumped from its template value.
+    pub version_is_placeholder: bool,
+    /// From the AUR metadata dump, if it is fetched and lists the package.
+    pub meta: Option<PackageMeta>,
+}
+
+/// Package data .SRCINFO lacks, from the AUR's `packages-meta-ext-v1.json.gz` dump.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "PascalCase"))]
+pub struct PackageMeta {
+    /// `None` for orphaned packages.
+    pub maintainer: Option<String>,
+    pub num_votes: u32,
+    pub popularity: f64,
+    pub first_submitted: i64,
+    pub last_modified: i64,
 }
 
-#[derive(Debug, Clone)]
+/// A package of the AUR metadata dump, which has many more fields than read here.
+#[derive(Debug, Deserialize)]
+pub struct AurDumpPackage {
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(flatten)]
+    pub meta: PackageMeta,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct DatabasePackageDetails {
     pub info: DatabasePackageInfo,
     pub depends: Vec<String>,
@@ -143,6 +234,166 @@ pub struct DatabasePackageDetails {
     pub conflicts: Vec<String>,
     pub replaces: Vec<String>,
     pub groups: Vec<String>,
+    pub keywords: Vec<String>,
+    /// `(arch, value)` pairs of the arch-specific relations, with an empty `arch` for
+    /// the unsuffixed keys. Only set for freshly parsed packages, the relation arrays
+    /// above hold the values of all architectures.
+    #[serde(skip)]
+    pub arch_values: HashMap<Relation, Vec<(String, String)>>,
+}
+
+impl DatabasePackageDetails {
+    /// The `(arch, value)` rows to store for a relation.
+    pub fn relation_rows(&self, relation: Relation) -> Vec<(&str, &str)> {
+        match self.arch_values.get(&relation) {
+            Some(values) => values
+                .iter()
+                .map(|(arch, value)| (arch.as_str(), value.as_str()))
+                .collect(),
+            None => self
+                .relation(relation)
+                .iter()
+                .map(|value| ("", value.as_str()))
+                .collect(),
+        }
+    }
+
+    pub fn relation(&self, relation: Relation) -> &[String] {
+        match relation {
+            Relation::Depends => &self.depends,
+            Relation::MakeDepends => &self.make_depends,
+            Relation::OptDepends => &self.opt_depends,
+            Relation::CheckDepends => &self.check_depends,
+            Relation::Provides => &self.provides,
+            Relation::Conflicts => &self.conflicts,
+            Relation::Replaces => &self.replaces,
+            Relation::Groups => &self.groups,
+            Relation::Keywords => &self.keywords,
+        }
+    }
+
+    pub fn relation_mut(&mut self, relation: Relation) -> &mut Vec<String> {
+        match relation {
+            Relation::Depends => &mut self.depends,
+            Relation::MakeDepends => &mut self.make_depends,
+            Relation::OptDepends => &mut self.opt_depends,
+            Relation::CheckDepends => &mut self.check_depends,
+            Relation::Provides => &mut self.provides,
+            Relation::Conflicts => &mut self.conflicts,
+            Relation::Replaces => &mut self.replaces,
+            Relation::Groups => &mut self.groups,
+            Relation::Keywords => &mut self.keywords,
+        }
+    }
+}
+
+/// A change to one package made by a sync, as recorded in `pkg_changes`.
+#[derive(Debug, Clone, Serialize)]
+pub struct PackageChange {
+    pub branch: String,
+    pub pkg_name: String,
+    /// `added`, `removed`, `version` or `depends`.
+    pub kind: String,
+    pub old_version: Option<String>,
+    pub new_version: Option<String>,
+    pub commit_id: String,
+    /// Unix timestamp of the sync that made the change.
+    pub changed_at: i64,
+}
+
+/// Multi-valued package attributes, each stored in its own `pkg_*` table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Relation {
+    Depends,
+    MakeDepends,
+    OptDepends,
+    CheckDepends,
+    Provides,
+    Conflicts,
+    Replaces,
+    Groups,
+    Keywords,
+}
+
+impl Relation {
+    pub const ALL: [Relation; 9] = [
+        Self::Depends,
+        Self::MakeDepends,
+        Self::OptDepends,
+        Self::CheckDepends,
+        Self::Provides,
+        Self::Conflicts,
+        Self::Replaces,
+        Self::Groups,
+        Self::Keywords,
+    ];
+
+    /// The relations that are dependencies of some kind.
+    pub const DEPENDENCIES: [Relation; 4] = [
+        Self::Depends,
+        Self::MakeDepends,
+        Self::OptDepends,
+        Self::CheckDepends,
+    ];
+
+    /// The relations .SRCINFO can suffix with an architecture, e.g. `depends_x86_64`.
+    pub const ARCH_SPECIFIC: [Relation; 7] = [
+        Self::Depends,
+        Self::MakeDepends,
+        Self::OptDepends,
+        Self::CheckDepends,
+        Self::Provides,
+        Self::Conflicts,
+        Self::Replaces,
+    ];
+
+    /// The lowercase name of the matching RPC field, e.g. `makedepends`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Depends => "depends",
+            Self::MakeDepends => "makedepends",
+            Self::OptDepends => "optdepends",
+            Self::CheckDepends => "checkdepends",
+            Self::Provides => "provides",
+            Self::Conflicts => "conflicts",
+            Self::Replaces => "replaces",
+            Self::Groups => "groups",
+            Self::Keywords => "keywords",
+        }
+    }
+
+    /// Parses the lowercase name of the matching RPC field, e.g. `makedepends`.
+    pub fn from_str(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|r| r.name() == s)
+    }
+
+    pub fn table(self) -> &'static str {
+        match self {
+            Self::Depends => "pkg_depends",
+            Self::MakeDepends => "pkg_make_depends",
+            Self::OptDepends => "pkg_opt_depends",
+            Self::CheckDepends => "pkg_check_depends",
+            Self::Provides => "pkg_provides",
+            Self::Conflicts => "pkg_conflicts",
+            Self::Replaces => "pkg_replaces",
+            Self::Groups => "pkg_groups",
+            Self::Keywords => "pkg_keywords",
+        }
+    }
+
+    pub fn column(self) -> &'static str {
+        match self {
+            Self::Depends => "depend",
+            Self::MakeDepends => "make_depend",
+            Self::OptDepends => "opt_depend",
+            Self::CheckDepends => "check_depend",
+            Self::Provides => "provide",
+            Self::Conflicts => "conflict",
+            Self::Replaces => "replace",
+            Self::Groups => "group_name",
+            Self::Keywords => "keyword",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -153,17 +404,132 @@ pub enum SearchType {
     MakeDepends,
     OptDepends,
     CheckDepends,
+    Provides,
+    Conflicts,
+    Replaces,
+    Groups,
+    Keywords,
+    /// Ranked full-text search of names and descriptions, an extension.
+    FullText,
+    /// Matches maintainers from the AUR metadata dump, nothing if it is not fetched.
+    Maintainer,
+    /// Accepted for compatibility, but the mirror has no submitter data.
+    Submitter,
 }
 
 impl SearchType {
+    pub const ALL: [SearchType; 14] = [
+        Self::Name,
+        Self::NameDesc,
+        Self::Depends,
+        Self::MakeDepends,
+        Self::OptDepends,
+        Self::CheckDepends,
+        Self::Provides,
+        Self::Conflicts,
+        Self::Replaces,
+        Self::Groups,
+        Self::Keywords,
+        Self::FullText,
+        Self::Maintainer,
+        Self::Submitter,
+    ];
+
+    /// The `by` value selecting this type.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Name => "name",
+            Self::NameDesc => "name-desc",
+            Self::Depends => "depends",
+            Self::MakeDepends => "makedepends",
+            Self::OptDepends => "optdepends",
+            Self::CheckDepends => "checkdepends",
+            Self::Provides => "provides",
+            Self::Conflicts => "conflicts",
+            Self::Replaces => "replaces",
+            Self::Groups => "groups",
+            Self::Keywords => "keywords",
+            Self::FullText => "fulltext",
+            Self::Maintainer => "maintainer",
+            Self::Submitter => "submitter",
+        }
+    }
+
+    /// The relation table searched by this type, if it is a relation search.
+    pub fn relation(self) -> Option<Relation> {
+        match self {
+            Self::Name | Self::NameDesc | Self::FullText | Self::Maintainer | Self::Submitter => {
+                None
+            }
+            Self::Depends => Some(Relation::Depends),
+            Self::MakeDepends => Some(Relation::MakeDepends),
+            Self::OptDepends => Some(Relation::OptDepends),
+            Self::CheckDepends => Some(Relation::CheckDepends),
+            Self::Provides => Some(Relation::Provides),
+            Self::Conflicts => Some(Relation::Conflicts),
+            Self::Replaces => Some(Relation::Replaces),
+            Self::Groups => Some(Relation::Groups),
+            Self::Keywords => Some(Relation::Keywords),
+        }
+    }
+
+    pub fn from_str(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|t| t.name() == s)
+    }
+}
+
+/// A slice of the results, from the `offset` and `limit` parameters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Page {
+    pub offset: usize,
+    pub limit: usize,
+}
+
+/// Order of search results, from the `sort` parameter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortOrder {
+    /// Most popular first, as the AUR web interface lists packages.
+    Popularity,
+    /// Most voted first.
+    Votes,
+    Name,
+}
+
+impl SortOrder {
     pub fn from_str(s: &str) -> Option<Self> {
         match s {
+            "popularity" => Some(Self::Popularity),
+            "votes" => Some(Self::Votes),
             "name" => Some(Self::Name),
-            "name-desc" => Some(Self::NameDesc),
-            "depends" => Some(Self::Depends),
-            "makedepends" => Some(Self::MakeDepends),
-            "optdepends" => Some(Self::OptDepends),
-            "checkdepends" => Some(Self::CheckDepends),
+            _ => None,
+        }
+    }
+
+    /// `ORDER BY` terms for `PKG_INFO_SELECT`. Packages missing from the AUR metadata
+    /// dump sort last, and ties are broken by name so the order is deterministic.
+    pub fn order_by(self) -> &'static str {
+        match self {
+            Self::Popularity => "m.popularity DESC, m.num_votes DESC, p.pkg_name, p.branch",
+            Self::Votes => "m.num_votes DESC, m.popularity DESC, p.pkg_name, p.branch",
+            Self::Name => "p.pkg_name, p.branch",
+        }
+    }
+}
+
+/// How relation searches compare the keyword against stored values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchMode {
+    /// Exact match, as aurweb does. Uses the reverse lookup indexes.
+    Exact,
+    /// `LIKE '%keyword%'` match. Cannot use the indexes, so it scans the whole table.
+    Substring,
+}
+
+impl MatchMode {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "exact" => Some(Self::Exact),
+            "substring" => Some(Self::Substring),
             _ => None,
         }
     }
@@ -0,0 +1,276 @@
+//! Package versions (`[epoch:]pkgver[-pkgrel]`) and pacman's version comparison.
+
+use anyhow::{anyhow, Result};
+use std::cmp::Ordering;
+
+/// A version split into its parts, validated with makepkg's rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Version<'a> {
+    pub epoch: Option<&'a str>,
+    pub pkgver: &'a str,
+    pub pkgrel: Option<&'a str>,
+}
+
+impl<'a> Version<'a> {
+    pub fn parse(s: &'a str) -> Result<Self> {
+        if s.is_empty() {
+            return Err(anyhow!("Empty version"));
+        }
+        if !s.bytes().all(|b| b.is_ascii_graphic()) {
+            return Err(anyhow!("Invalid character in version '{}'", s));
+        }
+        let (epoch, rest) = match s.split_once(':') {
+            Some((epoch, rest)) => (Some(epoch), rest),
+            None => (None, s),
+        };
+        if epoch.is_some_and(|epoch| epoch.is_empty() || !is_digits(epoch)) {
+            return Err(anyhow!("Invalid epoch in version '{}'", s));
+        }
+        let (pkgver, pkgrel) = match rest.rsplit_once('-') {
+            Some((pkgver, pkgrel)) => (pkgver, Some(pkgrel)),
+            None => (rest, None),
+        };
+        if pkgver.is_empty() || pkgver.contains([':', '-', '/']) {
+            return Err(anyhow!("Invalid pkgver in version '{}'", s));
+        }
+        if pkgrel.is_some_and(|pkgrel| !is_valid_pkgrel(pkgrel)) {
+            return Err(anyhow!("Invalid pkgrel in version '{}'", s));
+        }
+        Ok(Self {
+            epoch,
+            pkgver,
+            pkgrel,
+        })
+    }
+}
+
+fn is_digits(s: &str) -> bool {
+    s.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// `1` or `1.1`, as required by makepkg.
+fn is_valid_pkgrel(pkgrel: &str) -> bool {
+    let (major, minor) = match pkgrel.split_once('.') {
+        Some((major, minor)) => (major, Some(minor)),
+        None => (pkgrel, None),
+    };
+    !major.is_empty()
+        && is_digits(major)
+        && minor.is_none_or(|minor| !minor.is_empty() && is_digits(minor))
+}
+
+/// Compares two full versions like `vercmp`/`alpm_pkg_vercmp`: epoch first, then
+/// pkgver, then pkgrel if both have one. Works on any strings, without validation.
+pub fn vercmp(a: &str, b: &str) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+    let (epoch_a, ver_a, rel_a) = split_evr(a);
+    let (epoch_b, ver_b, rel_b) = split_evr(b);
+    rpmvercmp(epoch_a, epoch_b)
+        .then_with(|| rpmvercmp(ver_a, ver_b))
+        .then_with(|| match (rel_a, rel_b) {
+            (Some(rel_a), Some(rel_b)) => rpmvercmp(rel_a, rel_b),
+            _ => Ordering::Equal,
+        })
+}
+
+/// Same as libalpm's `parseEVR`: a missing epoch is `0`, the release follows the
+/// last `-`.
+fn split_evr(evr: &str) -> (&str, &str, Option<&str>) {
+    let digits = evr.bytes().take_while(u8::is_ascii_digit).count();
+    let (epoch, rest) = match evr[digits..].strip_prefix(':') {
+        Some(rest) if digits > 0 => (&evr[..digits], rest),
+        Some(rest) => ("0", rest),
+        None => ("0", evr),
+    };
+    match rest.rsplit_once('-') {
+        Some((version, release)) => (epoch, version, Some(release)),
+        None => (epoch, rest, None),
+    }
+}
+
+/// Segment-wise comparison of libalpm's `rpmvercmp`: runs of digits compare
+/// numerically, runs of letters lexically, and a numeric segment is newer than an
+/// alphabetic one.
+fn rpmvercmp(a: &str, b: &str) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (mut one, mut two) = (0, 0);
+
+    while one < a.len() && two < b.len() {
+        let separator_start = (one, two);
+        while one < a.len() && !a[one].is_ascii_alphanumeric() {
+            one += 1;
+        }
+        while two < b.len() && !b[two].is_ascii_alphanumeric() {
+            two += 1;
+        }
+        if one == a.len() || two == b.len() {
+            break;
+        }
+        // Longer separators are newer
+        let separator_len = (one - separator_start.0, two - separator_start.1);
+        if separator_len.0 != separator_len.1 {
+            return separator_len.0.cmp(&separator_len.1);
+        }
+
+        let is_num = a[one].is_ascii_digit();
+        let in_segment: fn(&u8) -> bool = if is_num {
+            u8::is_ascii_digit
+        } else {
+            u8::is_ascii_alphabetic
+        };
+        let end_one = one + a[one..].iter().take_while(|b| in_segment(b)).count();
+        let end_two = two + b[two..].iter().take_while(|b| in_segment(b)).count();
+        let (mut seg_one, mut seg_two) = (&a[one..end_one], &b[two..end_two]);
+
+        // Different types, one numeric and the other alphabetic
+        if seg_two.is_empty() {
+            return if is_num {
+                Ordering::Greater
+            } else {
+                Ordering::Less
+            };
+        }
+        if is_num {
+            let trim_zeros =
+                |seg: &[u8]| -> usize { seg.iter().take_while(|&&b| b == b'0').count() };
+            seg_one = &seg_one[trim_zeros(seg_one)..];
+            seg_two = &seg_two[trim_zeros(seg_two)..];
+            match seg_one.len().cmp(&seg_two.len()) {
+                Ordering::Equal => {}
+                ordering => return ordering,
+            }
+        }
+        match seg_one.cmp(seg_two) {
+            Ordering::Equal => {}
+            ordering => return ordering,
+        }
+        one = end_one;
+        two = end_two;
+    }
+
+    let (rest_one, rest_two) = (&a[one..], &b[two..]);
+    if rest_one.is_empty() && rest_two.is_empty() {
+        // Only the separators differed
+        return Ordering::Equal;
+    }
+    // A remaining alphabetic segment never beats an empty one, e.g. `1.0a` < `1.0`
+    let two_is_alpha = rest_two.first().is_some_and(u8::is_ascii_alphabetic);
+    let one_is_alpha = rest_one.first().is_some_and(u8::is_ascii_alphabetic);
+    if (rest_one.is_empty() && !two_is_alpha) || one_is_alpha {
+        Ordering::Less
+    } else {
+        Ordering::Greater
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cases of pacman's `test/util/vercmptest.sh`, as `(a, b, vercmp(a, b))`.
+    const VERCMP_CASES: &[(&str, &str, i8)] = &[
+        // Similar length, no pkgrel
+        ("1.5.0", "1.5.0", 0),
+        ("1.5.1", "1.5.0", 1),
+        // Mixed length
+        ("1.5.1", "1.5", 1),
+        // With pkgrel
+        ("1.5.0-1", "1.5.0-1", 0),
+        ("1.5.0-1", "1.5.0-2", -1),
+        ("1.5.0-1", "1.5.1-1", -1),
+        ("1.5.0-2", "1.5.1-1", -1),
+        ("1.5-1", "1.5.1-1", -1),
+        ("1.5-2", "1.5.1-1", -1),
+        ("1.5-2", "1.5.1-2", -1),
+        // pkgrel on one side only is ignored
+        ("1.5", "1.5-1", 0),
+        ("1.5-1", "1.5", 0),
+        ("1.1-1", "1.1", 0),
+        ("1.0-1", "1.1", -1),
+        ("1.1-1", "1.0", 1),
+        // Alphanumeric versions
+        ("1.5b-1", "1.5-1", -1),
+        ("1.5b", "1.5", -1),
+        ("1.5b-1", "1.5", -1),
+        ("1.5b", "1.5.1", -1),
+        // From the vercmp man page
+        ("1.0a", "1.0alpha", -1),
+        ("1.0alpha", "1.0b", -1),
+        ("1.0b", "1.0beta", -1),
+        ("1.0beta", "1.0rc", -1),
+        ("1.0rc", "1.0", -1),
+        // Alphabetic segments after dots
+        ("1.5.a", "1.5", 1),
+        ("1.5.b", "1.5.a", 1),
+        ("1.5.1", "1.5.b", 1),
+        ("1.5.b-1", "1.5.b", 0),
+        ("1.5-1", "1.5.b", -1),
+        // Same content, differing separators
+        ("2.0", "2_0", 0),
+        ("2.0_a", "2_0.a", 0),
+        ("2.0a", "2.0.a", -1),
+        ("2___a", "2_a", 1),
+        // Epochs
+        ("0:1.0", "0:1.0", 0),
+        ("0:1.0", "0:1.1", -1),
+        ("1:1.0", "0:1.0", 1),
+        ("1:1.0", "0:1.1", 1),
+        ("1:1.0", "2:1.1", -1),
+        ("1:1.0", "0:1.0-1", 1),
+        ("1:1.0-1", "0:1.1-1", 1),
+        ("0:1.0", "1.0", 0),
+        ("0:1.0", "1.1", -1),
+        ("0:1.1", "1.0", 1),
+        ("1:1.0", "1.0", 1),
+        ("1:1.0", "1.1", 1),
+        ("1:1.1", "1.1", 1),
+        // Leading zeros of numeric segments
+        ("1.01", "1.1", 0),
+        ("1.001", "1.0001", 0),
+        ("1.010", "1.1", 1),
+        ("1:1.0-01", "1:1.0-1", 0),
+    ];
+
+    #[test]
+    fn vercmp_matches_pacman() {
+        for &(a, b, expected) in VERCMP_CASES {
+            let expected = expected.cmp(&0);
+            assert_eq!(vercmp(a, b), expected, "vercmp({}, {})", a, b);
+            assert_eq!(vercmp(b, a), expected.reverse(), "vercmp({}, {})", b, a);
+        }
+    }
+
+    #[test]
+    fn parse_splits_parts() {
+        assert_eq!(
+            Version::parse("2:1.0.r3.gabc-1.1").unwrap(),
+            Version {
+                epoch: Some("2"),
+                pkgver: "1.0.r3.gabc",
+                pkgrel: Some("1.1"),
+            }
+        );
+        assert_eq!(
+            Version::parse("1.0").unwrap(),
+            Version {
+                epoch: None,
+                pkgver: "1.0",
+                pkgrel: None,
+            }
+        );
+    }
+
+    #[test]
+    fn parse_rejects_invalid_versions() {
+        for version in [
+            "", "1.0 beta", ":1.0", "a:1.0", "1.0-", "1.0-1.", "1.0-a", "-1", "1/0",
+        ] {
+            assert!(Version::parse(version).is_err(), "{}", version);
+        }
+    }
+}